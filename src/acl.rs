@@ -0,0 +1,135 @@
+// Access control for a Verifier, kept in its chain rather than in its file.
+// The Acl in force is stored as its own block, referenced by every VerifiedData from the
+// chain's root on, and each change is a Signed Update<AclCommand> that an Admin submitted.
+// The root's Acl makes the Verifier's allowed keys the Admins.
+
+use rmp_serde::{to_vec_named as serialize, from_slice as deserialize};
+use rpds::HashTrieMap;
+use futures::Future;
+
+use std::io;
+use std::sync::Arc;
+
+use block::{BlockHash, BlockStore, hung_up};
+use signed::{PublicKey, AllowedKeys, Context};
+use update::Command;
+
+// Admin implies Writer
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Role{
+    Writer, // may submit updates to the value
+    Admin,  // may also change the Acl
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct Acl(pub HashTrieMap<PublicKey, Role>);
+
+impl Acl{
+    // the Acl a chain's root records
+    pub fn from_allowed(allowed: &AllowedKeys) -> Acl{
+        Acl(allowed.iter().fold(HashTrieMap::new(), |acl, key| acl.insert(key.clone(), Role::Admin)))
+    }
+
+    pub fn allows(&self, key: &PublicKey, role: Role) -> bool{
+        self.0.get(key).map_or(false, |r| *r >= role)
+    }
+
+    pub fn admins(&self) -> usize{
+        self.0.values().filter(|r| **r == Role::Admin).count()
+    }
+
+    // blocking
+    pub fn load(store: &BlockStore, hash: &BlockHash) -> io::Result<Acl>{
+        let data = store.get(hash.clone()).wait()
+            .map_err(hung_up)??;
+        deserialize(&data[..])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                                        format!("{:?} is not an Acl: {:?}", hash, e)))
+    }
+
+    // blocking
+    pub fn store(&self, store: &BlockStore) -> io::Result<BlockHash>{
+        let data = serialize(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        store.set(Arc::new(data)).wait()
+            .map_err(hung_up)?
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag="Cmd", content="Data")]
+pub enum AclCommand{
+    Grant(PublicKey),   // make key a Writer, leaving an Admin as it is
+    Revoke(PublicKey),  // take away whatever role key has
+    Promote(PublicKey), // make key an Admin
+}
+
+impl Context for AclCommand{
+    fn context() -> String{
+        "AclCommand".into()
+    }
+}
+
+impl Command<Acl> for AclCommand{
+    fn process(self, old: Acl) -> Result<Acl, ()>{
+        match self{
+            AclCommand::Grant(key) => {
+                if old.0.contains_key(&key){
+                    Ok(old)
+                }
+                else{
+                    Ok(Acl(old.0.insert(key, Role::Writer)))
+                }
+            },
+            AclCommand::Revoke(key) => {
+                let acl = Acl(old.0.remove(&key));
+                // nobody could ever change it again
+                if acl.admins() == 0{
+                    return Err(());
+                }
+                Ok(acl)
+            },
+            AclCommand::Promote(key) => {
+                Ok(Acl(old.0.insert(key, Role::Admin)))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use signed::KeyPair;
+
+    fn acl(roles: &[(&KeyPair, Role)]) -> Acl{
+        Acl(roles.iter().fold(HashTrieMap::new(), |acl, &(key, role)| acl.insert(key.public.clone(), role)))
+    }
+
+    #[test]
+    fn revoke_of_the_last_admin_is_refused(){
+        let (admin, other, writer) = (KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+        let one = acl(&[(&admin, Role::Admin), (&writer, Role::Writer)]);
+        assert!(AclCommand::Revoke(admin.public.clone()).process(one.clone()).is_err());
+        let revoked = AclCommand::Revoke(writer.public.clone()).process(one).unwrap();
+        assert!(!revoked.allows(&writer.public, Role::Writer));
+
+        let two = acl(&[(&admin, Role::Admin), (&other, Role::Admin)]);
+        let revoked = AclCommand::Revoke(admin.public.clone()).process(two).unwrap();
+        assert_eq!(revoked.admins(), 1);
+        assert!(!revoked.allows(&admin.public, Role::Writer));
+    }
+
+    #[test]
+    fn grant_leaves_an_admin_as_admin(){
+        let (admin, writer) = (KeyPair::generate(), KeyPair::generate());
+        let granted = AclCommand::Grant(admin.public.clone())
+            .process(acl(&[(&admin, Role::Admin)]))
+            .unwrap();
+        assert!(granted.allows(&admin.public, Role::Admin));
+
+        let granted = AclCommand::Grant(writer.public.clone()).process(granted).unwrap();
+        assert!(granted.allows(&writer.public, Role::Writer));
+        assert!(!granted.allows(&writer.public, Role::Admin));
+        assert_eq!(granted.admins(), 1);
+    }
+}
@@ -0,0 +1,299 @@
+// Portable archives of block subgraphs, for moving tile libraries and worlds between
+// servers without copying the whole block store.
+// Format:
+//   ARCHIVE_MAGIC
+//   u32 (big endian) length of the header, then the msgpack ArchiveHeader
+//   header.blocks times: u8 length of the BlockHash, the BlockHash (as_bytes),
+//                        u32 (big endian) length, the data
+// Every block is rehashed on import, and every Signed block has its signatures checked, so a
+// damaged or tampered archive is refused. All of it is checked before any of it is stored,
+// so a refused archive leaves nothing behind.
+
+use rmp_serde::{to_vec_named as serialize, from_slice as deserialize};
+use futures::Future;
+use clap::ArgMatches;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader, BufWriter};
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+use std::convert::TryFrom;
+
+use block::{BlockHash, BlockStore, BlockStoreConfig, spawn_thread as spawn_block_thread, hung_up};
+use signed::{Signed, Passphrase, check_batch, BATCH_THREADS};
+use verify::VerifierMap;
+use map::TILE_LIBRARY_DIR;
+use gc;
+
+const ARCHIVE_MAGIC: &'static [u8] = b"HTG-ARCHIVE-1\0";
+// nothing legitimate comes close, so anything bigger is a corrupt length
+const MAX_ARCHIVE_HEADER: u32 = 1<<24; // 16M
+// blocks have their signatures checked this many at a time
+const IMPORT_BATCH: usize = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveRoot{
+    pub name: Option<String>, // the Verifier it was the latest of, if exported by name
+    pub hash: BlockHash
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchiveHeader{
+    roots:  Vec<ArchiveRoot>,
+    blocks: u64
+}
+
+#[derive(Debug, Default)]
+pub struct ExportReport{
+    pub roots:   Vec<ArchiveRoot>,
+    pub blocks:  u64,
+    pub bytes:   u64,
+    pub missing: Vec<BlockHash>, // reachable but not in the store, so not exported
+}
+
+impl fmt::Display for ExportReport{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "exported {} blocks ({} bytes) under {} roots",
+               self.blocks, self.bytes, self.roots.len())?;
+        for hash in self.missing.iter(){
+            write!(f, "\nmissing {:?}", hash)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport{
+    pub roots:  Vec<ArchiveRoot>,
+    pub blocks: u64,
+    pub new:    u64, // blocks that weren't already stored
+}
+
+impl fmt::Display for ImportReport{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "imported {} blocks, {} new", self.blocks, self.new)?;
+        for root in self.roots.iter(){
+            match root.name{
+                Some(ref name) => write!(f, "\nroot {:?} (latest of {})", root.hash, name)?,
+                None           => write!(f, "\nroot {:?}", root.hash)?
+            }
+        }
+        Ok(())
+    }
+}
+
+
+fn write_u32<W: Write>(wtr: &mut W, n: u32) -> io::Result<()>{
+    wtr.write_all(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8])
+}
+
+fn read_u32<R: Read>(rdr: &mut R) -> io::Result<u32>{
+    let mut b = [0u8; 4];
+    rdr.read_exact(&mut b)?;
+    Ok((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32)
+}
+
+pub fn export<W: Write>(store: &BlockStore, roots: Vec<ArchiveRoot>, wtr: &mut W)
+    -> io::Result<ExportReport>
+{
+    let mut report = ExportReport::default();
+
+    let hashes = gc::reachable(store, roots.iter().map(|r| r.hash.clone()).collect());
+    let have = store.has_many(hashes.clone()).wait().map_err(hung_up)??;
+    let (hashes, missing): (Vec<_>, Vec<_>) = hashes.into_iter()
+        .zip(have)
+        .partition(|&(_, have)| have);
+    report.missing = missing.into_iter().map(|(hash, _)| hash).collect();
+
+    let header = ArchiveHeader{
+        roots:  roots.clone(),
+        blocks: hashes.len() as u64
+    };
+    let header = serialize(&header)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    wtr.write_all(ARCHIVE_MAGIC)?;
+    write_u32(wtr, header.len() as u32)?;
+    wtr.write_all(&header[..])?;
+
+    for (hash, _) in hashes{
+        let data = store.get(hash.clone()).wait().map_err(hung_up)??;
+        wtr.write_all(&[hash.as_bytes().len() as u8])?;
+        wtr.write_all(hash.as_bytes())?;
+        write_u32(wtr, data.len() as u32)?;
+        wtr.write_all(&data[..])?;
+        report.blocks += 1;
+        report.bytes  += data.len() as u64;
+    }
+
+    report.roots = roots;
+    Ok(report)
+}
+
+// checks the signatures of any blocks that are Signed (which says nothing about whether the
+// keys are any use, that's for whoever takes the roots)
+fn check_batch_signatures(batch: Vec<(BlockHash, Vec<u8>)>) -> io::Result<()>{
+    let mut signed: Vec<(&BlockHash, Signed)> = batch.iter()
+        .filter_map(|&(ref hash, ref data)| deserialize(&data[..]).ok().map(|s| (hash, s)))
+        .collect();
+    let good = check_batch(signed.iter_mut().map(|&mut (_, ref mut s)| s).collect(), BATCH_THREADS);
+    for (&(hash, _), good) in signed.iter().zip(good){
+        if !good{
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("{:?} has a bad signature", hash)));
+        }
+    }
+    Ok(())
+}
+
+// the next block of the archive, having checked that it is what it claims to be
+fn read_block<R: Read>(rdr: &mut R) -> io::Result<(BlockHash, Vec<u8>)>{
+    let mut hash_len = [0u8];
+    rdr.read_exact(&mut hash_len)?;
+    let mut hash = vec![0u8; hash_len[0] as usize];
+    rdr.read_exact(&mut hash[..])?;
+    let hash = BlockHash::try_from(&hash[..])?;
+
+    let len = read_u32(rdr)?;
+    let mut data = Vec::new();
+    rdr.by_ref().take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len as usize{
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  format!("archive ends partway through {:?}", hash)));
+    }
+
+    // don't take the archive's word for it
+    if !hash.is_hash_of(&data[..]){
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("block claiming to be {:?} doesn't match its hash", hash)));
+    }
+    Ok((hash, data))
+}
+
+// reads the archive twice, checking everything in it and then storing it
+pub fn import<R: Read + Seek>(store: &BlockStore, rdr: &mut R) -> io::Result<ImportReport>{
+    let invalid = |s: String| io::Error::new(io::ErrorKind::InvalidData, s);
+
+    let mut magic = vec![0u8; ARCHIVE_MAGIC.len()];
+    rdr.read_exact(&mut magic[..])?;
+    if &magic[..] != ARCHIVE_MAGIC{
+        return Err(invalid("not an archive".into()));
+    }
+
+    let header_len = read_u32(rdr)?;
+    if header_len > MAX_ARCHIVE_HEADER{
+        return Err(invalid(format!("header claims to be {} bytes", header_len)));
+    }
+    let mut header = vec![0u8; header_len as usize];
+    rdr.read_exact(&mut header[..])?;
+    let header: ArchiveHeader = deserialize(&header[..])
+        .map_err(|e| invalid(format!("header doesn't decode: {:?}", e)))?;
+    let blocks_start = rdr.seek(SeekFrom::Current(0))?;
+
+    let mut archived = HashSet::new();
+    let mut batch = Vec::with_capacity(IMPORT_BATCH);
+    for _ in 0..header.blocks{
+        let (hash, data) = read_block(rdr)?;
+        archived.insert(hash.clone());
+        batch.push((hash, data));
+        if batch.len() == IMPORT_BATCH{
+            check_batch_signatures(mem::replace(&mut batch, Vec::with_capacity(IMPORT_BATCH)))?;
+        }
+    }
+    check_batch_signatures(batch)?;
+
+    // a root that wasn't exported (or already here) is no use to anyone
+    let elsewhere: Vec<&ArchiveRoot> = header.roots.iter()
+        .filter(|root| !archived.contains(&root.hash))
+        .collect();
+    let have = store.has_many(elsewhere.iter().map(|root| root.hash.clone()).collect())
+        .wait().map_err(hung_up)??;
+    for (root, have) in elsewhere.into_iter().zip(have){
+        if !have{
+            return Err(invalid(format!("root {:?} isn't in the archive", root.hash)));
+        }
+    }
+
+    let mut report = ImportReport::default();
+    rdr.seek(SeekFrom::Start(blocks_start))?;
+    for _ in 0..header.blocks{
+        // checked again, incase the archive changed since
+        let (hash, data) = read_block(rdr)?;
+        if !archived.contains(&hash){
+            return Err(invalid(format!("{:?} wasn't in the archive when it was checked", hash)));
+        }
+        let (_, new) = store.insert_with(hash.algorithm(), Arc::new(data)).wait().map_err(hung_up)??;
+        report.blocks += 1;
+        if new{
+            report.new += 1;
+        }
+    }
+
+    report.roots = header.roots;
+    Ok(report)
+}
+
+// roots given by tile library Verifier name and by hash
+fn roots_from_args(args: &ArgMatches) -> io::Result<Vec<ArchiveRoot>>{
+    let mut roots = Vec::new();
+    if let Some(names) = args.values_of("verifier"){
+        let latest = VerifierMap::peek_dir(::secret_dir(args).join(TILE_LIBRARY_DIR), Passphrase::Ask)?.roots();
+        for name in names{
+            match latest.iter().find(|&&(ref n, _)| n == name){
+                Some(&(_, ref hash)) => roots.push(ArchiveRoot{
+                    name: Some(name.to_string()),
+                    hash: hash.clone()
+                }),
+                None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                                  format!("no tile library named {} (or it has no latest)", name)))
+            }
+        }
+    }
+    if let Some(hashes) = args.values_of("hash"){
+        for hash in hashes{
+            roots.push(ArchiveRoot{
+                name: None,
+                hash: hash.parse()?
+            });
+        }
+    }
+    if roots.is_empty(){
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "nothing to export, give a --verifier or --hash"));
+    }
+    Ok(roots)
+}
+
+// must not be run alongside a server using the same blocks
+pub fn export_main(args: &ArgMatches){
+    let path = Path::new(args.value_of("archive").unwrap());
+    let block_store = spawn_block_thread(BlockStoreConfig::from_args(args));
+
+    let result = roots_from_args(args)
+        .and_then(|roots|
+                  ::write_then_rename(path, |wtr|{
+                      let mut wtr = BufWriter::new(wtr);
+                      let report = export(&block_store, roots, &mut wtr)?;
+                      wtr.flush()?;
+                      Ok(report)
+                  }));
+    match result{
+        Ok(report) => println!("{}", report),
+        Err(e) => println!("Export failed: {:?}", e)
+    }
+}
+
+// must not be run alongside a server using the same blocks
+pub fn import_main(args: &ArgMatches){
+    let path = Path::new(args.value_of("archive").unwrap());
+    let block_store = spawn_block_thread(BlockStoreConfig::from_args(args));
+
+    let result = fs::File::open(path)
+        .and_then(|file| import(&block_store, &mut BufReader::new(file)));
+    match result{
+        Ok(report) => println!("{}", report),
+        Err(e) => println!("Import failed: {:?}", e)
+    }
+}
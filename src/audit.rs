@@ -0,0 +1,376 @@
+// Proves that a Verifier's latest is what its history adds up to. The chain is walked back
+// to its root (or a trusted checkpoint's state) and checked as verify_history does, then
+// replayed forward from there:
+// every command is processed again and the result compared with the value stored in the
+// state it made, and key rotations and ACL changes must carry the value over unchanged.
+// Every Checkpoint a state refers to must follow the one before it and agree with the
+// height and counts of the state it is of.
+
+use serde::{Serialize, Deserialize};
+use clap::ArgMatches;
+
+use std::fmt::{self, Debug};
+use std::collections::HashMap;
+
+use block::{BlockHash, BlockStore, BlockStoreConfig, spawn_thread as spawn_block_thread};
+use update::{Update, Command, NamedHash, NamedHashCommand};
+use verify::{VerifierError, VerifierMap, History, load_checked_history};
+use acl::{Acl, AclCommand};
+use signed::{PublicKey, Passphrase};
+use map::TILE_LIBRARY_DIR;
+use checkpoint::{self, ChainStats, Checkpoint};
+
+#[derive(Debug, PartialEq)]
+pub enum Divergence{
+    Rejected,   // the command fails when replayed
+    Value,      // the command replayed makes a different value than the one stored
+    Carried,    // a key rotation or ACL change didn't keep the value as it was
+    Acl,        // the ACL change replayed makes a different Acl than the one stored, or
+                // a state that isn't an ACL change has another Acl than the one before it
+    Checkpoint, // a new checkpoint doesn't match the chain up to its state
+}
+
+#[derive(Debug)]
+pub struct AuditReport{
+    pub states:    usize,
+    pub root:      BlockHash, // the first state replayed, the root unless started from a checkpoint
+    // the first state that doesn't follow from the one before it, if any
+    pub divergent: Option<(BlockHash, Divergence)>,
+}
+
+impl fmt::Display for AuditReport{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{} states from {:?}", self.states, self.root)?;
+        match self.divergent{
+            Some((ref hash, ref divergence)) => write!(f, ", diverges at {:?} ({:?})", hash, divergence),
+            None => write!(f, ", every one replayed")
+        }
+    }
+}
+
+// blocking. key and from_height are as for verify_history, whose errors are returned as
+// they are. Divergence is reported rather than returned.
+pub fn audit<T, C>(store: &BlockStore, latest: BlockHash, key: &PublicKey, from_height: Option<u64>)
+    -> Result<AuditReport, VerifierError>
+    where T: Serialize + Debug + PartialEq,
+          C: Command<T>,
+    for <'de> T: Deserialize<'de>,
+    for <'de> C: Deserialize<'de>
+{
+    let (history, trusted) = load_checked_history::<T, C>(store, latest, key, from_height)?;
+    replay::<T, C>(store, history, trusted)
+}
+
+// blocking. Replays a history that check_history has passed, from the root or from the
+// state of the trusted Checkpoint it was loaded down to
+pub fn replay<T, C>(store: &BlockStore, history: History<T>, trusted: Option<Checkpoint>)
+    -> Result<AuditReport, VerifierError>
+    where T: Serialize + Debug + PartialEq,
+          C: Command<T>,
+    for <'de> C: Deserialize<'de>
+{
+    let states = history.len();
+    let mut forward = history.into_iter().rev();
+    let (root, first_signed, first) = forward.next().ok_or(VerifierError::LastErr)?;
+    let mut report = AuditReport{ states, root: root.clone(), divergent: None };
+
+    // the height, counts and signer of every state so far, for checking checkpoints against
+    let (base, mut stats, mut checkpoints) = match trusted{
+        Some(checkpoint) => (checkpoint.height, checkpoint.stats, checkpoint.number),
+        None => (0, ChainStats::default(), 0)
+    };
+    let mut replayed = HashMap::new();
+    replayed.insert(root, (base, stats.clone(), first_signed.user().clone()));
+    let mut last_checkpoint = first.checkpoint.clone(); // None if it's the root
+
+    let mut value = first.value;
+    let mut acl = first.acl;
+    for (height, (hash, signed, state)) in forward.enumerate(){
+        let divergence = if let Some((_, update)) = state.acl_changed()?{
+            if state.value != value{
+                Some(Divergence::Carried)
+            }
+            else if !replay_acl(store, &acl, update, &state.acl)?{
+                Some(Divergence::Acl)
+            }
+            else{
+                None
+            }
+        }
+        else if state.rotation.is_some(){
+            if state.value != value { Some(Divergence::Carried) } else { None }
+        }
+        else if let Some(ref update) = state.update{
+            // check_history verified it
+            let update: Update<C> = update.peek()?;
+            match update.command.process(value){
+                Ok(replayed) => if replayed != state.value { Some(Divergence::Value) } else { None },
+                Err(()) => Some(Divergence::Rejected)
+            }
+        }
+        else{
+            None // only the root has no update, and the walk back stops there
+        };
+
+        let divergence = divergence.or_else(||{
+            if state.acl_update.is_none() && state.acl != acl { Some(Divergence::Acl) } else { None }
+        });
+        let divergence = divergence.or_else(||{
+            if state.checkpoint == last_checkpoint{
+                return None;
+            }
+            let follows = match state.checkpoint{
+                Some(ref new) => checkpoint_follows(store, &replayed, new, &last_checkpoint, checkpoints),
+                None => false // a state can't forget the checkpoint
+            };
+            checkpoints += 1;
+            if follows { None } else { Some(Divergence::Checkpoint) }
+        });
+
+        if let Some(divergence) = divergence{
+            report.divergent = Some((hash, divergence));
+            break;
+        }
+        stats.count(&state);
+        replayed.insert(hash, (base + height as u64 + 1, stats.clone(), signed.user().clone()));
+        last_checkpoint = state.checkpoint;
+        value = state.value;
+        acl = state.acl;
+    }
+    Ok(report)
+}
+
+// whether new is the next Checkpoint after previous, with number checkpoints before it,
+// and its state is one already replayed, signed by the same key and at the same height
+// with the same counts
+fn checkpoint_follows(store: &BlockStore, replayed: &HashMap<BlockHash, (u64, ChainStats, PublicKey)>,
+                      new: &BlockHash, previous: &Option<BlockHash>, number: u64)
+    -> bool
+{
+    let (signer, new) = match checkpoint::load(store, new){
+        Ok(loaded) => loaded,
+        Err(_) => return false
+    };
+    match replayed.get(&new.state){
+        Some(&(height, ref stats, ref state_signer)) =>
+            new.height == height && new.stats == *stats && signer == *state_signer &&
+            new.number == number && new.skip.first() == previous.as_ref(),
+        None => false
+    }
+}
+
+// whether update turns the Acl old into new. Every state records its Acl from the root on,
+// so there's nothing to change without one.
+fn replay_acl(store: &BlockStore, old: &Option<BlockHash>, update: Update<AclCommand>,
+              new: &Option<BlockHash>)
+    -> Result<bool, VerifierError>
+{
+    let load = |hash: &BlockHash| Acl::load(store, hash).map_err(|_| VerifierError::LastErr);
+    let old = match *old{
+        Some(ref old) => load(old)?,
+        None => return Ok(false)
+    };
+    let new = match *new{
+        Some(ref new) => load(new)?,
+        None => return Ok(false)
+    };
+    Ok(update.command.process(old).map(|acl| acl == new).unwrap_or(false))
+}
+
+// must not be run alongside a server using the same blocks
+pub fn main(args: &ArgMatches){
+    let from_height = match args.value_of("from-height").map(str::parse::<u64>).map_or(Ok(None), |r| r.map(Some)){
+        Ok(from_height) => from_height,
+        Err(e) => {
+            println!("Bad --from-height: {}", e);
+            return;
+        }
+    };
+    let block_store = spawn_block_thread(BlockStoreConfig::from_args(args));
+    let libraries = match VerifierMap::peek_dir(::secret_dir(args).join(TILE_LIBRARY_DIR), Passphrase::Ask){
+        Ok(libraries) => libraries,
+        Err(e) => {
+            println!("Failed to load tile libraries: {:?}", e);
+            return;
+        }
+    };
+
+    let keys = libraries.keys();
+    let names: Vec<String> = match args.values_of("verifier"){
+        Some(names) => names.map(String::from).collect(),
+        None => keys.iter().map(|&(ref name, _)| name.clone()).collect()
+    };
+    for name in names{
+        let key = match keys.iter().find(|&&(ref n, _)| *n == name){
+            Some(&(_, ref key)) => key,
+            None => {
+                println!("{}: no such tile library", name);
+                continue;
+            }
+        };
+        let latest = match libraries.latest(&name){
+            Some(latest) => latest,
+            None => {
+                println!("{}: no latest, nothing to audit", name);
+                continue;
+            }
+        };
+        match audit::<NamedHash, NamedHashCommand>(&block_store, latest, key, from_height){
+            Ok(report) => println!("{}: {}", name, report),
+            Err(e) => println!("{}: history doesn't verify, {:?}", name, e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use futures::{Future, IntoFuture};
+    use rmp_serde::to_vec_named as serialize;
+    use rpds::HashTrieSet;
+
+    use std::sync::Arc;
+
+    use block::spawn_memory_thread;
+    use ltime::SerializableTime;
+    use replay::new_nonce;
+    use signed::{Signed, KeyPair, Context};
+    use update::{TestObject, TestCommand};
+    use verify::{Verifier, VerifiedData, peek_state};
+
+    fn sign<C: Serialize + Context>(command: C, last: &BlockHash, keypair: &KeyPair) -> Signed{
+        let timestamp = SerializableTime::from_system_now().unwrap();
+        Signed::sign(Update{ timestamp, command, last: last.clone(), nonce: Some(new_nonce()) }, keypair).unwrap()
+    }
+
+    fn verifier<T: Serialize + Debug>(store: &BlockStore, admin: &KeyPair, root: T) -> Verifier{
+        let verifier = Verifier::new(None, Some(HashTrieSet::new().insert(admin.public.clone())), None);
+        verifier.force(store, root).unwrap();
+        verifier
+    }
+
+    fn latest(verifier: &Verifier) -> BlockHash{
+        verifier.latest.borrow().clone().unwrap()
+    }
+
+    fn add(store: &BlockStore, verifier: &Verifier, n: u64, keypair: &KeyPair) -> BlockHash{
+        let signed = sign(TestCommand::Add(n), &latest(verifier), keypair);
+        verifier.verify::<TestObject, TestCommand>(store, signed).into_future().wait().unwrap()
+    }
+
+    // a state on top of last signed by the Verifier's key, as verify would make it for
+    // command except that it stores value
+    fn forge(store: &BlockStore, verifier: &Verifier, last: &BlockHash, command: TestCommand, value: TestObject,
+             keypair: &KeyPair)
+        -> BlockHash
+    {
+        let (_, parent) = peek_state::<TestObject>(store, last).unwrap();
+        let verified = VerifiedData{
+            value,
+            update: Some(sign(command, last, keypair)),
+            rotation: None,
+            acl: parent.acl,
+            acl_update: None,
+            cosigners: Vec::new(),
+            rebase: None,
+            checkpoint: parent.checkpoint
+        };
+        let data = serialize(&Signed::sign(verified, &verifier.keypair).unwrap()).unwrap();
+        store.set(Arc::new(data)).wait().unwrap().unwrap()
+    }
+
+    #[test]
+    fn clean_chain_replays(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let verifier = verifier(&store, &admin, TestObject::default());
+        let root = latest(&verifier);
+        for n in 1..4{
+            add(&store, &verifier, n, &admin);
+        }
+        let report = audit::<TestObject, TestCommand>(&store, latest(&verifier), &verifier.keypair.public, None).unwrap();
+        assert_eq!(report.states, 4);
+        assert_eq!(report.root, root);
+        assert_eq!(report.divergent, None);
+    }
+
+    #[test]
+    fn altered_value_diverges_where_it_was_altered(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let verifier = verifier(&store, &admin, TestObject::default());
+        let root = latest(&verifier);
+        let first = add(&store, &verifier, 1, &admin);
+
+        // stores what Add(5) would have made, and the next state carries on from it
+        let wrong = TestCommand::Add(5).process(TestObject::default()).unwrap();
+        let altered = forge(&store, &verifier, &root, TestCommand::Add(1), wrong, &admin);
+        let next = forge(&store, &verifier, &altered, TestCommand::Add(2),
+                         TestCommand::Add(2).process(wrong).unwrap(), &admin);
+
+        let report = audit::<TestObject, TestCommand>(&store, next, &verifier.keypair.public, None).unwrap();
+        assert_eq!(report.states, 3);
+        assert_eq!(report.divergent, Some((altered, Divergence::Value)));
+        // the state the Verifier made itself is fine
+        let report = audit::<TestObject, TestCommand>(&store, first, &verifier.keypair.public, None).unwrap();
+        assert_eq!(report.divergent, None);
+    }
+
+    #[test]
+    fn rebased_update_replays(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let mut verifier = verifier(&store, &admin, NamedHash::default());
+        verifier.rebase = true;
+        let root = latest(&verifier);
+        let set = |name: &str|{
+            let signed = sign(NamedHashCommand::Set(name.into(), BlockHash::of(name.as_bytes())), &root, &admin);
+            verifier.verify::<NamedHash, NamedHashCommand>(&store, signed).into_future().wait().unwrap()
+        };
+        set("a");
+        let rebased = set("b");
+        assert!(peek_state::<NamedHash>(&store, &rebased).unwrap().1.rebase.is_some());
+
+        let report = audit::<NamedHash, NamedHashCommand>(&store, rebased, &verifier.keypair.public, None).unwrap();
+        assert_eq!(report.states, 3);
+        assert_eq!(report.divergent, None);
+    }
+
+    #[test]
+    fn acl_changes_replay(){
+        let store = spawn_memory_thread();
+        let (admin, writer) = (KeyPair::generate(), KeyPair::generate());
+        let verifier = verifier(&store, &admin, TestObject::default());
+        let change_acl = |command: AclCommand|{
+            verifier.change_acl::<TestObject>(&store, sign(command, &latest(&verifier), &admin)).unwrap()
+        };
+        change_acl(AclCommand::Grant(writer.public.clone()));
+        add(&store, &verifier, 1, &writer);
+        change_acl(AclCommand::Promote(writer.public.clone()));
+        add(&store, &verifier, 2, &writer);
+        change_acl(AclCommand::Revoke(admin.public.clone()));
+
+        let report = audit::<TestObject, TestCommand>(&store, latest(&verifier), &verifier.keypair.public, None).unwrap();
+        assert_eq!(report.states, 6);
+        assert_eq!(report.divergent, None);
+    }
+
+    #[test]
+    fn replays_from_a_trusted_checkpoint(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let mut verifier = verifier(&store, &admin, TestObject::default());
+        verifier.checkpoint_every = Some(2);
+        for n in 1..9{
+            add(&store, &verifier, n, &admin);
+        }
+        let last = latest(&verifier);
+        let (_, checkpoint) = checkpoint::trusted_from::<TestObject>(&store, &last, 3).unwrap().unwrap();
+
+        // the checkpoints above it must still follow on from it
+        let report = audit::<TestObject, TestCommand>(&store, last, &verifier.keypair.public, Some(3)).unwrap();
+        assert_eq!(report.root, checkpoint.state);
+        assert_eq!(report.states, 5);
+        assert_eq!(report.divergent, None);
+    }
+}
@@ -0,0 +1,208 @@
+// Storage behind a BlockStore.
+// The BlockStore thread does the hashing and caching, a Backend only has to
+// map BlockHashes to data and back.
+
+use sled;
+use clap::ArgMatches;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use block::BlockHash;
+
+// each backend has its own default, so switching --store never opens one's files as the other's
+pub const DEFAULT_SLED_DIR: &'static str = "public/blocks/";
+pub const DEFAULT_DIR_DIR:  &'static str = "public/block_files/";
+
+pub trait Backend{
+    fn get(&mut self, hash: &BlockHash) -> io::Result<Option<Vec<u8>>>;
+    // data is only ever set under its own hash, so setting an existing hash can be a no-op
+    fn set(&mut self, hash: &BlockHash, data: &[u8]) -> io::Result<()>;
+    // returns whether there was anything to remove
+    fn remove(&mut self, hash: &BlockHash) -> io::Result<bool>;
+    // every hash currently stored
+    fn hashes(&mut self) -> io::Result<Vec<BlockHash>>;
+
+    fn contains(&mut self, hash: &BlockHash) -> io::Result<bool>{
+        self.get(hash).map(|d| d.is_some())
+    }
+}
+
+// where blocks are kept, chosen by --store and --blocks
+#[derive(Debug, Clone)]
+pub enum BackendConfig{
+    Sled(PathBuf),
+    Dir(PathBuf),
+    Memory
+}
+
+impl BackendConfig{
+    pub fn from_args(args: &ArgMatches) -> BackendConfig{
+        let path = |default: &'static str| PathBuf::from(args.value_of("blocks").unwrap_or(default));
+        match args.value_of("store").unwrap_or("sled"){
+            "sled"   => BackendConfig::Sled(path(DEFAULT_SLED_DIR)),
+            "dir"    => BackendConfig::Dir(path(DEFAULT_DIR_DIR)),
+            "memory" => BackendConfig::Memory,
+            other    => panic!("Unknown block store {}", other) // clap checks possible_values
+        }
+    }
+
+    pub fn open(&self) -> io::Result<Box<Backend>>{
+        let backend: Box<Backend> = match *self{
+            BackendConfig::Sled(ref path) => Box::new(SledBackend::open(path)?),
+            BackendConfig::Dir(ref path)  => Box::new(DirBackend::open(path)?),
+            BackendConfig::Memory         => Box::new(MemoryBackend::default())
+        };
+        Ok(backend)
+    }
+}
+
+pub struct SledBackend(sled::Tree);
+
+impl SledBackend{
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SledBackend>{
+        let config = sled::ConfigBuilder::new()
+            .path(path.as_ref().to_path_buf())
+            .build();
+        sled::Tree::start(config)
+            .map(SledBackend)
+            .map_err(sled_to_io)
+    }
+}
+
+impl Backend for SledBackend{
+    fn get(&mut self, hash: &BlockHash) -> io::Result<Option<Vec<u8>>>{
+        self.0.get(hash.as_bytes())
+            .map_err(sled_to_io)
+    }
+    fn set(&mut self, hash: &BlockHash, data: &[u8]) -> io::Result<()>{
+        // why on earth does sled need to own a vec ಠ_ಠ
+        self.0.set(hash.as_bytes().to_vec(), data.to_vec())
+            .map_err(sled_to_io)
+    }
+    fn remove(&mut self, hash: &BlockHash) -> io::Result<bool>{
+        self.0.del(hash.as_bytes())
+            .map(|old| old.is_some())
+            .map_err(sled_to_io)
+    }
+    fn hashes(&mut self) -> io::Result<Vec<BlockHash>>{
+        let mut hashes = Vec::new();
+        for entry in self.0.iter(){
+            let (key, _) = entry.map_err(sled_to_io)?;
+            match BlockHash::try_from(&key[..]){
+                Ok(hash) => hashes.push(hash),
+                Err(e) => warn!("Skipping key {:?} that isn't a BlockHash: {}", key, e)
+            }
+        }
+        Ok(hashes)
+    }
+}
+
+fn sled_to_io(e: sled::Error<()>) -> io::Error{
+    use sled::Error::*;
+    match e{
+        Io(ie) => ie,
+        CasFailed(_) =>
+            io::Error::new(io::ErrorKind::Interrupted, e),
+        Unsupported(_) =>
+            io::Error::new(io::ErrorKind::InvalidInput, e),
+        ReportableBug(s) =>
+            io::Error::new(io::ErrorKind::Other, s),
+        Corruption{at} =>
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("Corruption at {}", at))
+    }
+}
+
+// one file per block, named by the base64 of its hash
+// Warning: assumes it is the only program modifying the folder it is responsible for.
+pub struct DirBackend{
+    dir: PathBuf
+}
+
+impl DirBackend{
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<DirBackend>{
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(DirBackend{
+            dir: ::absolute_pathbuf(dir)
+        })
+    }
+    fn path(&self, hash: &BlockHash) -> PathBuf{
+        self.dir.join(hash.to_base64())
+    }
+}
+
+impl Backend for DirBackend{
+    fn get(&mut self, hash: &BlockHash) -> io::Result<Option<Vec<u8>>>{
+        let mut file = match fs::File::open(self.path(hash)){
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+    fn set(&mut self, hash: &BlockHash, data: &[u8]) -> io::Result<()>{
+        let path = self.path(hash);
+        if path.exists(){
+            return Ok(());
+        }
+        ::write_then_rename(path, |wtr| wtr.write_all(data))
+    }
+    fn remove(&mut self, hash: &BlockHash) -> io::Result<bool>{
+        match fs::remove_file(self.path(hash)){
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+    fn hashes(&mut self) -> io::Result<Vec<BlockHash>>{
+        let mut hashes = Vec::new();
+        for rentry in fs::read_dir(&self.dir)?{
+            let entry = rentry?;
+            if !entry.file_type()?.is_file(){
+                continue;
+            }
+            // skip anything that isn't a block, i.e. a leftover from write_then_rename
+            let name = match entry.file_name().into_string(){
+                Ok(name) => name,
+                Err(_) => continue
+            };
+            match name.parse::<BlockHash>(){
+                Ok(hash) => hashes.push(hash),
+                Err(_) => trace!("Skipping non-block file {:?} in {:?}", name, self.dir)
+            }
+        }
+        Ok(hashes)
+    }
+    fn contains(&mut self, hash: &BlockHash) -> io::Result<bool>{
+        Ok(self.path(hash).is_file())
+    }
+}
+
+// nothing survives the process, for tests and throwaway instances
+#[derive(Default)]
+pub struct MemoryBackend(HashMap<BlockHash, Vec<u8>>);
+
+impl Backend for MemoryBackend{
+    fn get(&mut self, hash: &BlockHash) -> io::Result<Option<Vec<u8>>>{
+        Ok(self.0.get(hash).cloned())
+    }
+    fn set(&mut self, hash: &BlockHash, data: &[u8]) -> io::Result<()>{
+        self.0.entry(hash.clone()).or_insert_with(|| data.to_vec());
+        Ok(())
+    }
+    fn remove(&mut self, hash: &BlockHash) -> io::Result<bool>{
+        Ok(self.0.remove(hash).is_some())
+    }
+    fn hashes(&mut self) -> io::Result<Vec<BlockHash>>{
+        Ok(self.0.keys().cloned().collect())
+    }
+    fn contains(&mut self, hash: &BlockHash) -> io::Result<bool>{
+        Ok(self.0.contains_key(hash))
+    }
+}
@@ -1,5 +1,6 @@
-// Content-addressed binary block storage in separate files
-// Warning: assumes it is the only program modifying the folder it is responsible for.
+// Content-addressed binary block storage
+// The actual storage is a Backend (see backend.rs), this thread hashes and caches.
+// Warning: assumes it is the only program modifying the storage it is responsible for.
 // Audit/improve this.
 
 use futures::{sync::mpsc::{UnboundedReceiver, UnboundedSender,
@@ -8,64 +9,348 @@ use futures::{sync::mpsc::{UnboundedReceiver, UnboundedSender,
                               channel as oneshot},
               Stream};
 use sha2::{Sha256, Digest};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
 use base64;
 use lru_cache::LruCache;
-use sled;
 
 use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use std::thread;
 use std::io;
-use std::path::{Path, PathBuf};
 use std::fmt::{self, Debug};
+use std::error::Error;
+use std::str::FromStr;
+use std::convert::TryFrom;
 
-const SHA256_BYTES: usize = 256 / 8;
+use backend::{Backend, BackendConfig};
+use clap::ArgMatches;
 
+pub const SHA256_BYTES:  usize = 256 / 8;
+pub const BLAKE2B_BYTES: usize = 256 / 8;
+
+// Which hash a BlockHash is of. The codes are multihash's.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm{
+    Sha256,
+    Blake2b256
+}
+
+impl HashAlgorithm{
+    pub fn code(self) -> u64{
+        match self{
+            HashAlgorithm::Sha256     => 0x12,
+            HashAlgorithm::Blake2b256 => 0xb220
+        }
+    }
+    pub fn from_code(code: u64) -> Option<HashAlgorithm>{
+        match code{
+            0x12   => Some(HashAlgorithm::Sha256),
+            0xb220 => Some(HashAlgorithm::Blake2b256),
+            _      => None
+        }
+    }
+    pub fn digest_len(self) -> usize{
+        match self{
+            HashAlgorithm::Sha256     => SHA256_BYTES,
+            HashAlgorithm::Blake2b256 => BLAKE2B_BYTES
+        }
+    }
+    pub fn from_name(name: &str) -> Option<HashAlgorithm>{
+        match name{
+            "sha256"  => Some(HashAlgorithm::Sha256),
+            "blake2b" => Some(HashAlgorithm::Blake2b256),
+            _         => None
+        }
+    }
+    pub fn digest(self, data: &[u8]) -> Vec<u8>{
+        match self{
+            HashAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            HashAlgorithm::Blake2b256 => {
+                use sodiumoxide::crypto::generichash;
+                // only fails for invalid lengths, and BLAKE2B_BYTES is valid
+                let mut state = generichash::State::new(BLAKE2B_BYTES, None).unwrap();
+                state.update(data).unwrap();
+                state.finalize().unwrap().as_ref().to_vec()
+            }
+        }
+    }
+}
+
+impl Default for HashAlgorithm{
+    fn default() -> Self{
+        HashAlgorithm::Sha256
+    }
+}
+
+fn push_varint(out: &mut Vec<u8>, mut n: u64){
+    while n >= 0x80{
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+// (value, bytes used)
+fn read_varint(data: &[u8]) -> Option<(u64, usize)>{
+    let mut n = 0u64;
+    for (i, &b) in data.iter().enumerate().take(9){
+        n |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0{
+            return Some((n, i + 1));
+        }
+    }
+    None
+}
+
+// A multihash-style self-describing hash.
+// bytes is the compact form used for storage keys, URLs and serialization: a SHA-256
+// BlockHash is just the digest, as it was before there was a choice of algorithm, so
+// existing stores and links stay valid. Every other algorithm is a full multihash
+// (varint code, varint length, digest). The two can't be confused since a multihash
+// is never exactly SHA256_BYTES long.
 // XXX intern these?
 // YYY no don't, not yet, we don't create enough to make the interning table worth it. (Apr 27)
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct BlockHash(pub Arc<[u8; SHA256_BYTES]>);
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BlockHash{
+    algorithm: HashAlgorithm,
+    bytes:     Arc<[u8]>
+}
 
-impl<'a> From<&'a str> for BlockHash{
-    fn from(s: &'a str) -> BlockHash{
-        use base64::URL_SAFE_NO_PAD;
-        let mut bytes: [u8; SHA256_BYTES];
-        base64::decode_config_slice(&s, URL_SAFE_NO_PAD, &mut bytes)
-            .unwrap();
-        BlockHash(Arc::new(bytes))
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockHashParseError{
+    Base64(base64::DecodeError),
+    Malformed, // not a multihash (or bare SHA-256 digest) at all
+    UnknownAlgorithm(u64),
+    WrongLength{ algorithm: HashAlgorithm, len: usize }
+}
+
+impl fmt::Display for BlockHashParseError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        use self::BlockHashParseError::*;
+        match *self{
+            Base64(ref e) => write!(f, "BlockHash isn't valid base64: {}", e),
+            Malformed => write!(f, "BlockHash is malformed"),
+            UnknownAlgorithm(code) => write!(f, "BlockHash has unknown algorithm {:#x}", code),
+            WrongLength{ algorithm, len } =>
+                write!(f, "BlockHash digest is {} bytes, but {:?} is {}",
+                       len, algorithm, algorithm.digest_len())
+        }
     }
 }
 
-impl<'a> From<&'a [u8]> for BlockHash{
-    fn from(s: &'a [u8]) -> BlockHash{
-        let mut a: [u8; SHA256_BYTES];
-        a.clone_from_slice(s);
-        BlockHash(Arc::new(a))
+impl Error for BlockHashParseError{
+    fn description(&self) -> &str{
+        "invalid BlockHash"
+    }
+}
+
+// for the places that already deal in io::Errors, i.e. reading files of hashes
+impl From<BlockHashParseError> for io::Error{
+    fn from(e: BlockHashParseError) -> io::Error{
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+// for a BlockStore response whose sender was dropped, as in .wait().map_err(hung_up)
+pub fn hung_up<E>(_: E) -> io::Error{
+    io::Error::new(io::ErrorKind::Other, "BlockStore hung up its responder")
+}
+
+// the URL-safe base64 form, as used in /block/ URLs
+impl FromStr for BlockHash{
+    type Err = BlockHashParseError;
+    fn from_str(s: &str) -> Result<BlockHash, BlockHashParseError>{
+        BlockHash::from_base64(s)
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for BlockHash{
+    type Error = BlockHashParseError;
+    fn try_from(s: &'a [u8]) -> Result<BlockHash, BlockHashParseError>{
+        BlockHash::from_bytes(s)
+    }
+}
+
+impl fmt::Display for BlockHash{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{}", self.to_base64())
     }
 }
 
 impl BlockHash{
+    // the compact form, see above
     pub fn as_bytes<'a>(&'a self) -> &'a [u8]{
-        &self.0[..]
+        &self.bytes[..]
+    }
+    pub fn algorithm(&self) -> HashAlgorithm{
+        self.algorithm
+    }
+    pub fn digest(&self) -> &[u8]{
+        match self.algorithm{
+            HashAlgorithm::Sha256 => &self.bytes[..],
+            // skip the code and length
+            _ => &self.bytes[self.bytes.len() - self.algorithm.digest_len()..]
+        }
+    }
+    // always tagged, even for SHA-256
+    pub fn multihash(&self) -> Vec<u8>{
+        let mut out = Vec::new();
+        push_varint(&mut out, self.algorithm.code());
+        push_varint(&mut out, self.algorithm.digest_len() as u64);
+        out.extend_from_slice(self.digest());
+        out
+    }
+
+    pub fn from_digest(algorithm: HashAlgorithm, digest: &[u8]) -> Result<BlockHash, BlockHashParseError>{
+        if digest.len() != algorithm.digest_len(){
+            return Err(BlockHashParseError::WrongLength{ algorithm, len: digest.len() });
+        }
+        let bytes = match algorithm{
+            HashAlgorithm::Sha256 => digest.to_vec(),
+            _ => {
+                let mut bytes = Vec::new();
+                push_varint(&mut bytes, algorithm.code());
+                push_varint(&mut bytes, digest.len() as u64);
+                bytes.extend_from_slice(digest);
+                bytes
+            }
+        };
+        Ok(BlockHash{
+            algorithm,
+            bytes: Arc::from(bytes)
+        })
+    }
+    // accepts the compact form or any full multihash of a known algorithm
+    pub fn from_bytes(bytes: &[u8]) -> Result<BlockHash, BlockHashParseError>{
+        if bytes.len() == SHA256_BYTES{
+            return BlockHash::from_digest(HashAlgorithm::Sha256, bytes);
+        }
+        let (code, code_len) = read_varint(bytes)
+            .ok_or(BlockHashParseError::Malformed)?;
+        let (len, len_len) = read_varint(&bytes[code_len..])
+            .ok_or(BlockHashParseError::Malformed)?;
+        let algorithm = HashAlgorithm::from_code(code)
+            .ok_or(BlockHashParseError::UnknownAlgorithm(code))?;
+        let digest = &bytes[code_len + len_len..];
+        if digest.len() as u64 != len{
+            return Err(BlockHashParseError::Malformed);
+        }
+        BlockHash::from_digest(algorithm, digest)
+    }
+    pub fn from_base64(s: &str) -> Result<BlockHash, BlockHashParseError>{
+        use base64::URL_SAFE_NO_PAD;
+        let bytes = base64::decode_config(s, URL_SAFE_NO_PAD)
+            .map_err(BlockHashParseError::Base64)?;
+        BlockHash::from_bytes(&bytes[..])
+    }
+    pub fn to_base64(&self) -> String{
+        use base64::URL_SAFE_NO_PAD;
+        base64::encode_config(self.as_bytes(), URL_SAFE_NO_PAD)
+    }
+
+    // the SHA-256 hash data is stored under
+    pub fn of(data: &[u8]) -> BlockHash{
+        BlockHash::with(HashAlgorithm::Sha256, data)
+    }
+    pub fn with(algorithm: HashAlgorithm, data: &[u8]) -> BlockHash{
+        BlockHash::from_digest(algorithm, &algorithm.digest(data)[..]).unwrap()
+    }
+    // whether this is the hash of data, by whichever algorithm this is
+    pub fn is_hash_of(&self, data: &[u8]) -> bool{
+        &self.algorithm.digest(data)[..] == self.digest()
+    }
+}
+
+// Serialized as the compact bytes in a newtype, which for SHA-256 is exactly how
+// BlockHash(Arc<[u8; 32]>) used to be derived, so old blocks still decode.
+#[derive(Serialize, Deserialize)]
+#[serde(rename="BlockHash")]
+struct BlockHashRepr(Vec<u8>);
+
+impl Serialize for BlockHash{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>{
+        BlockHashRepr(self.bytes.to_vec()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockHash{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BlockHash, D::Error>{
+        use serde::de::Error;
+        let repr = BlockHashRepr::deserialize(deserializer)?;
+        BlockHash::from_bytes(&repr.0[..])
+            .map_err(D::Error::custom)
     }
 }
 
 pub type         BlockData = Arc<Vec<u8>>;
 pub type  BlockGetResponse = OneshotReceiver<io::Result<BlockData>>;
 pub type  BlockSetResponse = OneshotReceiver<io::Result<BlockHash>>;
+// like BlockSetResponse, but the bool is true if the block was not already stored
+pub type BlockInsertResponse = OneshotReceiver<io::Result<(BlockHash, bool)>>;
 
 type BlockGetResponder = OneshotSender<io::Result<BlockData>>;
 type BlockSetResponder = OneshotSender<io::Result<BlockHash>>;
+type BlockInsertResponder = OneshotSender<io::Result<(BlockHash, bool)>>;
+
+pub type     BlockHasResponse = OneshotReceiver<io::Result<bool>>;
+// one bool per hash asked about, in the same order
+pub type BlockHasManyResponse = OneshotReceiver<io::Result<Vec<bool>>>;
+// one result per hash asked for, in the same order, so one missing block doesn't fail the rest
+pub type BlockGetManyResponse = OneshotReceiver<Vec<io::Result<BlockData>>>;
+pub type BlockSetManyResponse = OneshotReceiver<io::Result<Vec<BlockHash>>>;
+// true if there was anything to remove
+pub type  BlockRemoveResponse = OneshotReceiver<io::Result<bool>>;
+
+type     BlockHasResponder = OneshotSender<io::Result<bool>>;
+type BlockHasManyResponder = OneshotSender<io::Result<Vec<bool>>>;
+type BlockGetManyResponder = OneshotSender<Vec<io::Result<BlockData>>>;
+type BlockSetManyResponder = OneshotSender<io::Result<Vec<BlockHash>>>;
+type  BlockRemoveResponder = OneshotSender<io::Result<bool>>;
+
+// deletes every block not in keep, unless it was set within the grace period
+#[derive(Debug)]
+pub struct SweepRequest{
+    pub keep:    HashSet<BlockHash>,
+    pub grace:   Duration,
+    pub dry_run: bool // only report what would be deleted
+}
+
+#[derive(Debug, Default)]
+pub struct SweepReport{
+    pub examined: usize,
+    pub swept:    Vec<BlockHash>, // deleted, or would have been if dry_run
+    pub spared:   Vec<BlockHash>, // unreachable but still within the grace period
+}
+
+pub type BlockSweepResponse = OneshotReceiver<io::Result<SweepReport>>;
+type BlockSweepResponder = OneshotSender<io::Result<SweepReport>>;
+pub type BlockHashesResponse = OneshotReceiver<io::Result<Vec<BlockHash>>>;
+type BlockHashesResponder = OneshotSender<io::Result<Vec<BlockHash>>>;
 
 #[derive(Debug)]
 enum BlockRequest{
     Get(BlockHash, BlockGetResponder),
-    Set(BlockData, BlockSetResponder)
+    Set(BlockData, BlockSetResponder),
+    Insert(BlockData, HashAlgorithm, BlockInsertResponder),
+    Has(BlockHash, BlockHasResponder),
+    HasMany(Vec<BlockHash>, BlockHasManyResponder),
+    GetMany(Vec<BlockHash>, BlockGetManyResponder),
+    SetMany(Vec<BlockData>, BlockSetManyResponder),
+    Remove(BlockHash, BlockRemoveResponder),
+    Sweep(SweepRequest, BlockSweepResponder),
+    Hashes(BlockHashesResponder),
+    Stats(BlockStatsResponder)
 }
 
 #[derive(Clone)]
-pub struct BlockStore(UnboundedSender<BlockRequest>);
+pub struct BlockStore(UnboundedSender<BlockRequest>, HashAlgorithm);
 
 impl BlockStore{
+    // what new blocks are hashed with
+    pub fn algorithm(&self) -> HashAlgorithm{
+        self.1
+    }
     pub fn get(&self, hash: BlockHash) -> BlockGetResponse{
         let (responder, response) = oneshot();
         self.0.unbounded_send(BlockRequest::Get(hash, responder)).unwrap();
@@ -79,64 +364,308 @@ impl BlockStore{
         }
         response
     }
+    pub fn insert(&self, data: BlockData) -> BlockInsertResponse{
+        self.insert_with(self.algorithm(), data)
+    }
+    // for blocks that must keep the hash they came with, i.e. from another server
+    pub fn insert_with(&self, algorithm: HashAlgorithm, data: BlockData) -> BlockInsertResponse{
+        let (responder, response) = oneshot();
+        match self.0.unbounded_send(BlockRequest::Insert(data, algorithm, responder)){
+            Ok(_) => (),
+            Err(e) => debug!("Failed to send Insert to BlockStore, {:?}", e)
+        }
+        response
+    }
+    // doesn't load the block (unless the backend has to, to find out)
+    pub fn has(&self, hash: BlockHash) -> BlockHasResponse{
+        let (responder, response) = oneshot();
+        match self.0.unbounded_send(BlockRequest::Has(hash, responder)){
+            Ok(_) => (),
+            Err(e) => debug!("Failed to send Has to BlockStore, {:?}", e)
+        }
+        response
+    }
+    pub fn has_many(&self, hashes: Vec<BlockHash>) -> BlockHasManyResponse{
+        let (responder, response) = oneshot();
+        match self.0.unbounded_send(BlockRequest::HasMany(hashes, responder)){
+            Ok(_) => (),
+            Err(e) => debug!("Failed to send HasMany to BlockStore, {:?}", e)
+        }
+        response
+    }
+    pub fn get_many(&self, hashes: Vec<BlockHash>) -> BlockGetManyResponse{
+        let (responder, response) = oneshot();
+        match self.0.unbounded_send(BlockRequest::GetMany(hashes, responder)){
+            Ok(_) => (),
+            Err(e) => debug!("Failed to send GetMany to BlockStore, {:?}", e)
+        }
+        response
+    }
+    pub fn set_many(&self, data: Vec<BlockData>) -> BlockSetManyResponse{
+        let (responder, response) = oneshot();
+        match self.0.unbounded_send(BlockRequest::SetMany(data, responder)){
+            Ok(_) => (),
+            Err(e) => debug!("Failed to send SetMany to BlockStore, {:?}", e)
+        }
+        response
+    }
+    // Warning: nothing checks whether anything still refers to the block, see gc.rs
+    pub fn remove(&self, hash: BlockHash) -> BlockRemoveResponse{
+        let (responder, response) = oneshot();
+        match self.0.unbounded_send(BlockRequest::Remove(hash, responder)){
+            Ok(_) => (),
+            Err(e) => debug!("Failed to send Remove to BlockStore, {:?}", e)
+        }
+        response
+    }
+    pub fn sweep(&self, request: SweepRequest) -> BlockSweepResponse{
+        let (responder, response) = oneshot();
+        match self.0.unbounded_send(BlockRequest::Sweep(request, responder)){
+            Ok(_) => (),
+            Err(e) => debug!("Failed to send Sweep to BlockStore, {:?}", e)
+        }
+        response
+    }
+    // every hash in the store, not just what is cached
+    pub fn hashes(&self) -> BlockHashesResponse{
+        let (responder, response) = oneshot();
+        match self.0.unbounded_send(BlockRequest::Hashes(responder)){
+            Ok(_) => (),
+            Err(e) => debug!("Failed to send Hashes to BlockStore, {:?}", e)
+        }
+        response
+    }
+    pub fn stats(&self) -> BlockStatsResponse{
+        let (responder, response) = oneshot();
+        match self.0.unbounded_send(BlockRequest::Stats(responder)){
+            Ok(_) => (),
+            Err(e) => debug!("Failed to send Stats to BlockStore, {:?}", e)
+        }
+        response
+    }
 }
 
+#[derive(Debug, Clone)]
+pub struct BlockStoreConfig{
+    pub backend:      BackendConfig,
+    pub verify_reads: bool, // rehash everything read from the backend
+    pub cache_bytes:  usize,
+    pub algorithm:    HashAlgorithm // for new blocks, existing ones are readable regardless
+}
+
+impl BlockStoreConfig{
+    pub fn from_args(args: &ArgMatches) -> BlockStoreConfig{
+        BlockStoreConfig{
+            backend:      BackendConfig::from_args(args),
+            verify_reads: args.is_present("verify-reads"),
+            cache_bytes:  args.value_of("cache-bytes")
+                .map(|s| s.parse().expect("--cache-bytes must be a number of bytes"))
+                .unwrap_or(DEFAULT_CACHE_BYTES),
+            algorithm:    args.value_of("hash-algorithm")
+                .map(|s| HashAlgorithm::from_name(s).expect("--hash-algorithm must be sha256 or blake2b"))
+                .unwrap_or_default()
+        }
+    }
+}
+
+// counters since the BlockStore was spawned
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BlockStoreStats{
+    pub hits:           u64, // gets served from the cache
+    pub misses:         u64, // gets that had to go to the backend
+    pub backend_reads:  u64, // including for Has
+    pub bytes_served:   u64,
+    pub sets:           u64,
+    pub set_dedup_hits: u64, // sets of a block that was already stored
+    pub removed:        u64,
+    pub cached_blocks:  usize,
+    pub cached_bytes:   usize,
+    pub cache_budget:   usize
+}
+
+pub type BlockStatsResponse = OneshotReceiver<BlockStoreStats>;
+type BlockStatsResponder = OneshotSender<BlockStoreStats>;
+
 impl Debug for BlockStore{
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
         write!(f, "BlockStore")
     }
 }
 
-// Number of chunks to cache in LRU.
-// A chunk is expected to be not larger than 64K.
-// So i.e. 256 chunks would be approx 16MB + hash space + pointers.
-// sled probably does caching of its own, but this should keep the block in
+// Total bytes of block data to keep in the LRU cache, unless --cache-bytes says otherwise.
+// The backend may do caching of its own, but this should keep the block in
 // an Arc and thus serve as in-memory deduplication.
-const BLOCK_STORE_LRU_CAPACITY: usize = 256;
+pub const DEFAULT_CACHE_BYTES: usize = 16<<20; // 16M
+
+// An LruCache bounded by the size of the blocks in it rather than how many there are,
+// so a few huge blocks can't push everything else out (they just aren't cached).
+struct BlockCache{
+    lru:    LruCache<BlockHash, BlockData>,
+    bytes:  usize,
+    budget: usize
+}
+
+impl BlockCache{
+    fn new(budget: usize) -> BlockCache{
+        BlockCache{
+            // evicted by size below instead
+            lru: LruCache::new(usize::max_value()),
+            bytes: 0,
+            budget
+        }
+    }
+    fn get(&mut self, hash: &BlockHash) -> Option<BlockData>{
+        self.lru.get_mut(hash).map(|data| data.clone())
+    }
+    fn contains_key(&mut self, hash: &BlockHash) -> bool{
+        self.lru.contains_key(hash)
+    }
+    fn insert(&mut self, hash: BlockHash, data: BlockData){
+        let len = data.len();
+        if len > self.budget{
+            return;
+        }
+        self.remove(&hash);
+        while self.bytes + len > self.budget{
+            match self.lru.remove_lru(){
+                Some((_, evicted)) => self.bytes -= evicted.len(),
+                None => break
+            }
+        }
+        self.bytes += len;
+        self.lru.insert(hash, data);
+    }
+    fn remove(&mut self, hash: &BlockHash){
+        if let Some(data) = self.lru.remove(hash){
+            self.bytes -= data.len();
+        }
+    }
+}
+
 struct BlockStoreThread{
-    store: sled::Tree,
-    cache: LruCache<BlockHash, BlockData>
+    store: Box<Backend>,
+    verify_reads: bool,
+    algorithm: HashAlgorithm,
+    cache: BlockCache,
+    stats: BlockStoreStats,
+    // when each block was last set, so a sweep can't collect an upload that
+    // just hasn't been referenced yet
+    recent: HashMap<BlockHash, Instant>
 }
 
 impl BlockStoreThread{
     fn get(&mut self, hash: BlockHash) -> io::Result<BlockData>{
-        // cache is tried before this function is called
-        
-        use sled::Error::*;
-        let data = self.store.get(hash.as_bytes())
-            .map_err(|e| match e{
-                Io(ie) => ie,
-                CasFailed(_) =>
-                    io::Error::new(io::ErrorKind::Interrupted, e),
-                Unsupported(_) =>
-                    io::Error::new(io::ErrorKind::InvalidInput, e),
-                ReportableBug(s) =>
-                    io::Error::new(io::ErrorKind::Other, s),
-                Corruption{at} =>
-                    io::Error::new(io::ErrorKind::InvalidData,
-                                   format!("Corruption at {}", at))
-            })
+        if let Some(data) = self.cache.get(&hash){
+            self.stats.hits += 1;
+            self.stats.bytes_served += data.len() as u64;
+            return Ok(data);
+        }
+
+        self.stats.misses += 1;
+        self.stats.backend_reads += 1;
+        let data = self.store.get(&hash)
             .and_then(|r|
                       r.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
                                                      "BlockHash not found")))?;
+        if self.verify_reads && !hash.is_hash_of(&data[..]){
+            error!("Block {:?} doesn't match its hash, the store is corrupt", hash);
+            self.cache.remove(&hash); // shouldn't be there, but make sure
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Block doesn't match its hash"));
+        }
         let block_data = Arc::new(data);
+        self.stats.bytes_served += block_data.len() as u64;
         self.cache.insert(hash, block_data.clone());
         Ok(block_data)
     }
 
     fn set(&mut self, data: BlockData) -> io::Result<BlockHash>{
-        // hash data
-        let hash_digest = Sha256::digest(data.as_slice());
-        let hash = BlockHash::from(&hash_digest[..]);
+        let algorithm = self.algorithm;
+        self.insert(data, algorithm).map(|(hash, _)| hash)
+    }
 
-        // why on earth does sled need to own a vec ಠ_ಠ
-        let key = hash.as_bytes().to_vec();
-        let value = (*data).clone();
-        self.store.set(key, value);
+    fn insert(&mut self, data: BlockData, algorithm: HashAlgorithm) -> io::Result<(BlockHash, bool)>{
+        let hash = BlockHash::with(algorithm, data.as_slice());
+        self.stats.sets += 1;
+        // set again, even if it was already there, so it's spared for the grace period again
+        self.recent.insert(hash.clone(), Instant::now());
+
+        // anything in the cache is already in the store
+        if self.cache.contains_key(&hash){
+            self.stats.set_dedup_hits += 1;
+            return Ok((hash, false));
+        }
+
+        self.stats.backend_reads += 1;
+        let existed = self.store.contains(&hash)?;
+        if existed{
+            self.stats.set_dedup_hits += 1;
+        }
+        else{
+            self.store.set(&hash, data.as_slice())?;
+        }
 
         self.cache.insert(hash.clone(), data);
 
-        Ok(hash)
+        Ok((hash, !existed))
+    }
+
+    fn has(&mut self, hash: &BlockHash) -> io::Result<bool>{
+        if self.cache.contains_key(hash){
+            return Ok(true);
+        }
+        self.stats.backend_reads += 1;
+        self.store.contains(hash)
+    }
+
+    fn remove(&mut self, hash: &BlockHash) -> io::Result<bool>{
+        self.cache.remove(hash);
+        self.recent.remove(hash);
+        let removed = self.store.remove(hash)?;
+        if removed{
+            self.stats.removed += 1;
+        }
+        Ok(removed)
+    }
+
+    fn stats(&self) -> BlockStoreStats{
+        let mut stats = self.stats.clone();
+        stats.cached_blocks = self.cache.lru.len();
+        stats.cached_bytes  = self.cache.bytes;
+        stats.cache_budget  = self.cache.budget;
+        stats
+    }
+
+    fn sweep(&mut self, request: SweepRequest) -> io::Result<SweepReport>{
+        let mut report = SweepReport::default();
+
+        // anything older than the grace period no longer needs remembering
+        let grace = request.grace;
+        self.recent.retain(|_, set_at| set_at.elapsed() < grace);
+
+        for hash in self.store.hashes()?{
+            report.examined += 1;
+            if request.keep.contains(&hash){
+                continue;
+            }
+            if self.recent.contains_key(&hash){
+                report.spared.push(hash);
+            }
+            else{
+                report.swept.push(hash);
+            }
+        }
+
+        if !request.dry_run{
+            for hash in report.swept.iter(){
+                if self.store.remove(hash)?{
+                    self.stats.removed += 1;
+                }
+                self.cache.remove(hash);
+            }
+        }
+
+        Ok(report)
     }
 
     fn run(mut self, receiver: UnboundedReceiver<BlockRequest>){
@@ -146,60 +675,128 @@ impl BlockStoreThread{
             trace!("got {:?}", update);
             match update{
                 Get(hash, responder) => {
-                    if let Some(data) = self.cache.get_mut(&hash){
-                        responder.send(Ok(data.clone())).unwrap();
-                    }
-                    else{
-                        responder.send(self.get(hash)).unwrap();
-                    }
+                    responder.send(self.get(hash)).unwrap();
                 },
                 Set(data, responder) => {
                     responder.send(self.set(data)).unwrap();
+                },
+                Insert(data, algorithm, responder) => {
+                    responder.send(self.insert(data, algorithm)).unwrap();
+                },
+                Has(hash, responder) => {
+                    responder.send(self.has(&hash)).unwrap();
+                },
+                HasMany(hashes, responder) => {
+                    let result = hashes.iter()
+                        .map(|hash| self.has(hash))
+                        .collect();
+                    responder.send(result).unwrap();
+                },
+                GetMany(hashes, responder) => {
+                    let result = hashes.into_iter()
+                        .map(|hash| self.get(hash))
+                        .collect();
+                    responder.send(result).unwrap();
+                },
+                SetMany(data, responder) => {
+                    let result = data.into_iter()
+                        .map(|data| self.set(data))
+                        .collect();
+                    responder.send(result).unwrap();
+                },
+                Remove(hash, responder) => {
+                    responder.send(self.remove(&hash)).unwrap();
+                },
+                Sweep(request, responder) => {
+                    responder.send(self.sweep(request)).unwrap();
+                },
+                Hashes(responder) => {
+                    responder.send(self.store.hashes()).unwrap();
+                },
+                Stats(responder) => {
+                    responder.send(self.stats()).unwrap();
                 }
             }
         }).wait().last();
         debug!("BlockStore thread exiting");
     }
 }
-                
-pub fn spawn_thread<P: AsRef<Path>>(path: P) -> BlockStore{
+
+pub fn spawn_thread(config: BlockStoreConfig) -> BlockStore{
     let (sender, receiver) = unbounded_channel();
-    let path: PathBuf = path.as_ref().to_path_buf(); // need to own to move into new thread
 
     let _thread = thread::Builder::new()
         .name("BlockStore".into())
         .spawn(move ||{
-            let store =
-                sled::Tree::start(sled::ConfigBuilder::new().path(path).build())
-                .unwrap_or_else(|e| panic!("failed to open sled {:?}", path.as_path()));
+            let store = config.backend.open()
+                .unwrap_or_else(|e| panic!("failed to open block store {:?}: {:?}", config.backend, e));
             BlockStoreThread{
                 store,
-                cache: LruCache::new(BLOCK_STORE_LRU_CAPACITY)
+                verify_reads: config.verify_reads,
+                algorithm: config.algorithm,
+                cache: BlockCache::new(config.cache_bytes),
+                stats: BlockStoreStats::default(),
+                recent: HashMap::new()
             }.run(receiver)
         });
 
-    BlockStore(sender)
+    BlockStore(sender, config.algorithm)
+}
+
+// a BlockStore that forgets everything when dropped, for tests
+#[cfg(test)]
+pub fn spawn_memory_thread() -> BlockStore{
+    spawn_thread(BlockStoreConfig{
+        backend:      BackendConfig::Memory,
+        verify_reads: true,
+        cache_bytes:  DEFAULT_CACHE_BYTES,
+        algorithm:    HashAlgorithm::default()
+    })
 }
 
 pub mod base64_blockhash{
     use serde::{Deserialize, Serializer, Deserializer};
-    use base64::{self, URL_SAFE_NO_PAD};
-    use super::{SHA256_BYTES, BlockHash};
+    use super::BlockHash;
     pub fn serialize<S>(t: &BlockHash, serializer: S) -> Result<S::Ok, S::Error>
     where S: Serializer
     {
-        let s = base64::encode_config(t.0.as_ref(), URL_SAFE_NO_PAD);
-        serializer.serialize_str(&s)
+        serializer.serialize_str(&t.to_base64())
     }
     pub fn deserialize<'de, D>(deserializer: D) -> Result<BlockHash, D::Error>
         where D: Deserializer<'de>
     {
         use serde::de::Error;
         let s = String::deserialize(deserializer)?;
-        let mut bytes: [u8; SHA256_BYTES];
-        base64::decode_config_slice(&s, URL_SAFE_NO_PAD, &mut bytes)
-            .map_err(|e| Error::custom(e.to_string()))?;
-        Ok(BlockHash::from(&bytes[..]))
+        s.parse().map_err(Error::custom)
     }
 }
 
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn memory_store_keeps_blocks_by_hash(){
+        let store = spawn_memory_thread();
+        let data = Arc::new(b"some block".to_vec());
+        let hash = store.set(data.clone()).wait().unwrap().unwrap();
+        assert_eq!(hash, BlockHash::of(&data[..]));
+        assert_eq!(store.get(hash.clone()).wait().unwrap().unwrap(), data);
+
+        // only new the first time
+        assert_eq!(store.insert(data.clone()).wait().unwrap().unwrap(), (hash.clone(), false));
+        let other = Arc::new(b"another block".to_vec());
+        let (other_hash, new) = store.insert(other).wait().unwrap().unwrap();
+        assert!(new);
+
+        let missing = BlockHash::of(b"never stored");
+        assert_eq!(store.has_many(vec![hash.clone(), missing.clone(), other_hash.clone()]).wait().unwrap().unwrap(),
+                   vec![true, false, true]);
+        assert!(store.get(missing).wait().unwrap().is_err());
+
+        assert!(store.remove(other_hash.clone()).wait().unwrap().unwrap());
+        assert!(!store.remove(other_hash).wait().unwrap().unwrap());
+        assert_eq!(store.hashes().wait().unwrap().unwrap(), vec![hash]);
+    }
+}
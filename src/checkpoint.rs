@@ -0,0 +1,252 @@
+// Checkpoints bound how far a chain has to be walked one state at a time. Every so many
+// states a Verifier signs a Checkpoint of the state it has just made, saying how far that
+// state is from the root and counting what came before it, and each state refers to the
+// newest Checkpoint there was when it was made. A Checkpoint links to the ones 1, 2, 4, 8...
+// checkpoints before it, so the state at any height is a logarithmic number of jumps and a
+// walk shorter than the interval between checkpoints away.
+
+use rmp_serde::{to_vec_named as serialize, from_slice as deserialize};
+use serde::{Serialize, Deserialize};
+use rpds::HashTrieSet;
+use futures::Future;
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use block::{BlockHash, BlockStore};
+use signed::{Signed, KeyPair, PublicKey, Context};
+use update::Command;
+use verify::{VerifiedData, VerifierError, peek_state};
+
+// states between checkpoints, unless a Verifier says otherwise
+pub const DEFAULT_EVERY: u64 = 256;
+
+// what the states of a chain were, from its root up to some state
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChainStats{
+    pub updates:     u64,
+    pub rebased:     u64, // of the updates
+    pub acl_changes: u64,
+    pub rotations:   u64,
+}
+
+impl ChainStats{
+    pub fn count<T: Debug + Serialize>(&mut self, state: &VerifiedData<T>){
+        if state.rotation.is_some(){
+            self.rotations += 1;
+        }
+        else if state.acl_update.is_some(){
+            self.acl_changes += 1;
+        }
+        else if state.update.is_some(){
+            self.updates += 1;
+            if state.rebase.is_some(){
+                self.rebased += 1;
+            }
+        }
+    }
+
+    pub fn plus(&self, other: &ChainStats) -> ChainStats{
+        ChainStats{
+            updates:     self.updates + other.updates,
+            rebased:     self.rebased + other.rebased,
+            acl_changes: self.acl_changes + other.acl_changes,
+            rotations:   self.rotations + other.rotations,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint{
+    pub state:  BlockHash,      // the VerifiedData this is a checkpoint of
+    pub height: u64,            // states before state, back to the root
+    pub number: u64,            // checkpoints before this one
+    pub skip:   Vec<BlockHash>, // skip[k] is the checkpoint 2^k before this one, as far as there are
+    pub stats:  ChainStats,     // up to and including state
+}
+
+impl Context for Checkpoint{
+    fn context() -> String{
+        "Checkpoint".into()
+    }
+}
+
+// blocking. A Checkpoint and the key that signed it, which is also the key that signed its
+// state. Whether that key is to be trusted is for the caller to say.
+pub fn load(store: &BlockStore, hash: &BlockHash) -> Result<(PublicKey, Checkpoint), VerifierError>{
+    let block = store.get(hash.clone()).wait()
+        .map_err(|_| VerifierError::LastErr)? // Oneshot::Cancelled
+        .map_err(|_| VerifierError::LastErr)?;
+    let signed: Signed = deserialize(&block[..])
+        .map_err(|_| VerifierError::DecodeFailed)?;
+    let signer = signed.user().clone();
+    let checkpoint = signed.verify(&HashTrieSet::new().insert(signer.clone()))?;
+    Ok((signer, checkpoint))
+}
+
+// blocking. Signs and stores a Checkpoint of state, walking back from it to the state of the
+// newest Checkpoint it refers to (or to the root if there is none)
+pub fn emit<T: Serialize + Debug, C: Command<T>>(store: &BlockStore, state: &BlockHash, keypair: &KeyPair)
+    -> Result<BlockHash, VerifierError>
+    where for <'de> T: Deserialize<'de>,
+          for <'de> C: Deserialize<'de>
+{
+    let (_, newest) = peek_state::<T>(store, state)?;
+    let previous = match newest.checkpoint{
+        Some(ref hash) => Some((hash.clone(), load(store, hash)?.1)),
+        None => None
+    };
+
+    let since = walk::<T, C>(store, state.clone(), previous.as_ref().map(|&(_, ref previous)| &previous.state))?;
+    let mut stats = ChainStats::default();
+    for &(_, ref verified) in since.iter(){
+        stats.count(verified);
+    }
+    let walked = since.len() as u64;
+
+    let checkpoint = match previous{
+        Some((hash, previous)) => {
+            // the checkpoint 2^k back is the one 2^(k-1) back from the one 2^(k-1) back
+            let mut skip = vec![hash];
+            let mut at = previous.clone();
+            while let Some(further) = at.skip.get(skip.len() - 1).cloned(){
+                at = load(store, &further)?.1;
+                skip.push(further);
+            }
+            Checkpoint{
+                state:  state.clone(),
+                height: previous.height + walked,
+                number: previous.number + 1,
+                skip,
+                stats:  previous.stats.plus(&stats)
+            }
+        },
+        None => Checkpoint{
+            state:  state.clone(),
+            height: walked - 1, // the root was walked too
+            number: 0,
+            skip:   Vec::new(),
+            stats
+        }
+    };
+
+    let signed = Signed::sign(checkpoint, keypair)
+        .map_err(|_| VerifierError::StoreErr)?;
+    let data = serialize(&signed)
+        .map_err(|_| VerifierError::StoreErr)?;
+    store.set(Arc::new(data)).wait()
+        .map_err(|_| VerifierError::StoreErr)? // Oneshot::Cancelled
+        .map_err(|_| VerifierError::StoreErr)
+}
+
+// blocking. Of the checkpoints at or before from, the oldest whose state is at least height
+// from the root, or None if from's own state is lower than that
+pub fn seek(store: &BlockStore, from: &BlockHash, height: u64)
+    -> Result<Option<(BlockHash, Checkpoint)>, VerifierError>
+{
+    let mut at = (from.clone(), load(store, from)?.1);
+    if at.1.height < height{
+        return Ok(None);
+    }
+    'jump: loop{
+        // as far back as it can go without going too far
+        for further in at.1.skip.clone().into_iter().rev(){
+            // one that isn't stored, as below where a replica was pulled from, is too far
+            let checkpoint = match load(store, &further){
+                Ok((_, checkpoint)) => checkpoint,
+                Err(VerifierError::LastErr) => continue,
+                Err(e) => return Err(e)
+            };
+            if checkpoint.height >= height{
+                at = (further, checkpoint);
+                continue 'jump;
+            }
+        }
+        return Ok(Some(at));
+    }
+}
+
+// blocking. The checkpoint to start a walk back from latest at, trusting whoever signed it
+// for the chain below: the oldest at least height from the root, or the newest if none is
+// that high. None if latest refers to no checkpoint.
+pub fn trusted_from<T: Serialize + Debug>(store: &BlockStore, latest: &BlockHash, height: u64)
+    -> Result<Option<(PublicKey, Checkpoint)>, VerifierError>
+    where for <'de> T: Deserialize<'de>
+{
+    let (_, newest) = peek_state::<T>(store, latest)?;
+    let newest = match newest.checkpoint{
+        Some(hash) => hash,
+        None => return Ok(None)
+    };
+    let hash = match seek(store, &newest, height)?{
+        Some((hash, _)) => hash,
+        None => newest
+    };
+    load(store, &hash).map(Some)
+}
+
+// blocking. The state height states from the root of the chain behind latest, or None if
+// the chain isn't that long. Nothing is verified, see verify_history.
+pub fn state_at<T: Serialize + Debug, C: Command<T>>(store: &BlockStore, latest: BlockHash, height: u64)
+    -> Result<Option<(BlockHash, VerifiedData<T>)>, VerifierError>
+    where for <'de> T: Deserialize<'de>,
+          for <'de> C: Deserialize<'de>
+{
+    let (_, newest) = peek_state::<T>(store, &latest)?;
+    let (from, above) = match newest.checkpoint{
+        Some(ref checkpoint) => match seek(store, checkpoint, height)?{
+            Some((_, checkpoint)) => (checkpoint.state, checkpoint.height - height),
+            // it's above the newest checkpoint, so walk down to that
+            None => {
+                let newest = load(store, checkpoint)?.1;
+                let mut since = walk::<T, C>(store, latest, Some(&newest.state))?;
+                let above_newest = (height - newest.height) as usize;
+                if above_newest > since.len(){
+                    return Ok(None);
+                }
+                let at = since.len() - above_newest;
+                return Ok(Some(since.swap_remove(at)));
+            }
+        },
+        // every state is below latest, and only the root knows its height
+        None => {
+            let mut history = walk::<T, C>(store, latest, None)?;
+            if height as usize >= history.len(){
+                return Ok(None);
+            }
+            let at = history.len() - 1 - height as usize;
+            return Ok(Some(history.swap_remove(at)));
+        }
+    };
+
+    let mut hash = from;
+    for _ in 0..above{
+        hash = peek_state::<T>(store, &hash)?.1.previous_state::<C>()?
+            .ok_or(VerifierError::LastErr)?;
+    }
+    let (_, verified) = peek_state::<T>(store, &hash)?;
+    Ok(Some((hash, verified)))
+}
+
+// the states from from back to (but not including) until, newest first. Without until the
+// walk goes all the way to the root.
+fn walk<T: Serialize + Debug, C: Command<T>>(store: &BlockStore, from: BlockHash, until: Option<&BlockHash>)
+    -> Result<Vec<(BlockHash, VerifiedData<T>)>, VerifierError>
+    where for <'de> T: Deserialize<'de>,
+          for <'de> C: Deserialize<'de>
+{
+    let mut states = Vec::new();
+    let mut next = Some(from);
+    while let Some(hash) = next.take(){
+        if Some(&hash) == until{
+            return Ok(states);
+        }
+        let (_, verified) = peek_state::<T>(store, &hash)?;
+        next = verified.previous_state::<C>()?;
+        states.push((hash, verified));
+    }
+    match until{
+        Some(_) => Err(VerifierError::LastErr), // until isn't behind from
+        None => Ok(states)
+    }
+}
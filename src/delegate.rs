@@ -0,0 +1,51 @@
+// Issues Delegations, i.e. for a game client's own keypair to update tile libraries with.
+// The certificate is printed as base64 msgpack, for the client to attach (along with any
+// it was itself issued, outermost first) to the Signed updates it makes.
+
+use rmp_serde::{to_vec_named as serialize};
+use base64::{self, URL_SAFE_NO_PAD};
+use clap::ArgMatches;
+
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use signed::{KeyPair, PublicKey, Delegation, Scope, Passphrase};
+use ltime::SerializableTime;
+use run::ROOTKEY_FILE;
+
+pub const DEFAULT_EXPIRES_SECONDS: u64 = 30 * 24 * 60 * 60; // 30 days
+
+fn issue(args: &ArgMatches) -> io::Result<String>{
+    let invalid = |s: String| io::Error::new(io::ErrorKind::InvalidInput, s);
+
+    let issuer = match args.value_of("keypair"){
+        Some(path) => KeyPair::from_file(path, Passphrase::Ask)?,
+        None => KeyPair::from_file(::secret_dir(args).join(ROOTKEY_FILE), Passphrase::Ask)?
+    };
+    let key = args.value_of("key").unwrap();
+    let key = base64::decode_config(key, URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|bytes| PublicKey::from_slice(&bytes[..]))
+        .ok_or_else(|| invalid(format!("{} is not a base64 public key", key)))?;
+    let scope = Scope(args.values_of("scope").unwrap().map(String::from).collect());
+    let expires = match args.value_of("expires"){
+        Some(s) => s.parse().map_err(|_| invalid("--expires must be a number of seconds".into()))?,
+        None => DEFAULT_EXPIRES_SECONDS
+    };
+    let expires = SerializableTime::from_system(SystemTime::now() + Duration::from_secs(expires))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    let cert = Delegation{ key, scope, expires }
+        .issue(&issuer)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+    let cert = serialize(&cert)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(base64::encode_config(&cert[..], URL_SAFE_NO_PAD))
+}
+
+pub fn main(args: &ArgMatches){
+    match issue(args){
+        Ok(cert) => println!("{}", cert),
+        Err(e) => println!("Failed to issue delegation: {:?}", e)
+    }
+}
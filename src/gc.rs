@@ -0,0 +1,382 @@
+// Mark-and-sweep garbage collection for the BlockStore.
+// Marks everything reachable from a set of roots (normally every Verifier's latest):
+// each VerifiedData block, the chain behind it (through Update.last or the Rebase parent,
+// KeyRotation.last and ACL changes), every BlockHash that the state values and commands
+// refer to, and the Acl blocks. The BlockStore then sweeps the rest, sparing pinned blocks
+// and anything set within the grace period.
+
+use rmp_serde::{from_slice as deserialize};
+use rpds::HashTrieSet;
+use serde::{Serialize, Deserialize};
+use futures::Future;
+use clap::ArgMatches;
+
+use std::collections::HashSet;
+use std::fmt::{self, Debug};
+use std::fs;
+use std::io::{self, BufRead};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use block::{BlockHash, BlockStore, SweepRequest, SweepReport, BlockStoreConfig, spawn_thread as spawn_block_thread, hung_up};
+use signed::{Signed, Passphrase};
+use update::{Update, Command, NamedHash, NamedHashCommand};
+use verify::{VerifiedData, VerifierMap};
+use map::TILE_LIBRARY_DIR;
+use object::Manifest;
+use checkpoint;
+
+// blocks set more recently than this are never collected
+pub const DEFAULT_GRACE_SECONDS: u64 = 600;
+// one base64 BlockHash per line, for blocks that must survive without being referenced.
+// Under the secret dir.
+pub const PINS_FILE: &'static str = "gc_pins";
+
+pub trait References{
+    // every BlockHash this refers to that must be kept alive along with it
+    fn references(&self) -> Vec<BlockHash>;
+}
+
+#[derive(Debug, Default)]
+pub struct Marked{
+    pub reachable: HashSet<BlockHash>,
+    pub missing:   Vec<BlockHash>, // chain blocks that aren't in the store
+    pub invalid:   Vec<BlockHash>, // chain blocks that couldn't be read, decoded or verified
+    pub limit:     Option<usize>,  // marking stops once more than this many blocks are reachable
+    pub stop:      HashSet<BlockHash>, // chain states whose own blocks are marked, but not the chain behind them
+}
+
+impl Marked{
+    pub fn within(limit: usize) -> Marked{
+        Marked{ limit: Some(limit), ..Marked::default() }
+    }
+    pub fn over_limit(&self) -> bool{
+        self.limit.map_or(false, |limit| self.reachable.len() > limit)
+    }
+}
+
+// walks every chain of VerifiedData<T> (updated by C) back from roots
+pub fn mark<T, C>(store: &BlockStore, roots: Vec<BlockHash>) -> Marked
+    where T: Serialize + Debug + References,
+          C: Command<T> + References,
+    for <'de> T: Deserialize<'de>,
+    for <'de> C: Deserialize<'de>
+{
+    mark_into::<T, C>(store, roots, Marked::default())
+}
+
+// as mark, adding to what's already marked and stopping if that goes over its limit
+pub fn mark_into<T, C>(store: &BlockStore, roots: Vec<BlockHash>, mut marked: Marked) -> Marked
+    where T: Serialize + Debug + References,
+          C: Command<T> + References,
+    for <'de> T: Deserialize<'de>,
+    for <'de> C: Deserialize<'de>
+{
+    let mut chain = roots;
+    while let Some(hash) = chain.pop(){
+        if marked.over_limit(){
+            break;
+        }
+        if !marked.reachable.insert(hash.clone()){
+            continue; // already walked from here
+        }
+        let walk_on = !marked.stop.contains(&hash);
+
+        let block = match store.get(hash.clone()).wait(){
+            Ok(Ok(block)) => block,
+            Ok(Err(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                marked.missing.push(hash);
+                continue;
+            },
+            r => {
+                error!("Failed to load {:?} while marking: {:?}", hash, r);
+                marked.invalid.push(hash);
+                continue;
+            }
+        };
+        let signed: Signed = match deserialize(&block[..]){
+            Ok(s) => s,
+            Err(e) => {
+                error!("{:?} failed to decode to Signed while marking: {:?}", hash, e);
+                marked.invalid.push(hash);
+                continue;
+            }
+        };
+
+        // only reachability matters here, not who signed it
+        let allow_signer = HashTrieSet::new().insert(signed.user().clone());
+        let verified = match signed.verify::<VerifiedData<T>>(&allow_signer){
+            Ok(v) => v,
+            Err(e) => {
+                error!("{:?} is not a valid VerifiedData: {:?}", hash, e);
+                marked.invalid.push(hash);
+                continue;
+            }
+        };
+        mark_leaves(store, &mut marked, verified.value.references());
+
+        // the chain carries on under the old key
+        match verified.rotated_from(signed.user()){
+            Ok(Some((last, _))) => if walk_on { chain.push(last) },
+            Ok(None) => (),
+            Err(e) => {
+                error!("{:?} contains an invalid key rotation: {:?}", hash, e);
+                marked.invalid.push(hash.clone());
+            }
+        }
+        if let Some(ref acl) = verified.acl{
+            mark_leaves(store, &mut marked, vec![acl.clone()]);
+        }
+        // older checkpoints are referred to by older states
+        if let Some(ref checkpoint) = verified.checkpoint{
+            mark_leaves(store, &mut marked, vec![checkpoint.clone()]);
+        }
+        match verified.acl_changed(){
+            Ok(Some((_, update))) => if walk_on { chain.push(update.last) },
+            Ok(None) => (),
+            Err(e) => {
+                error!("{:?} contains an invalid ACL change: {:?}", hash, e);
+                marked.invalid.push(hash.clone());
+            }
+        }
+
+        if let Some(ref update) = verified.update{
+            let allow_updater = HashTrieSet::new().insert(update.user().clone());
+            match update.verify::<Update<C>>(&allow_updater){
+                Ok(update) => {
+                    mark_leaves(store, &mut marked, update.command.references());
+                    // a rebased update's own last is further back along the same chain
+                    if walk_on{
+                        chain.push(verified.update_parent(update.last));
+                    }
+                },
+                Err(e) => {
+                    error!("{:?} contains an invalid update: {:?}", hash, e);
+                    marked.invalid.push(hash);
+                }
+            }
+        }
+    }
+    marked
+}
+
+// marks blocks referred to by values and commands, along with the chunks under any
+// that are object manifests. Leaves aren't chain blocks, so a missing one isn't noted.
+pub fn mark_leaves(store: &BlockStore, marked: &mut Marked, leaves: Vec<BlockHash>){
+    // with the level a manifest above says they are (0 for data), None for object roots
+    let mut leaves: Vec<(BlockHash, Option<u8>)> = leaves.into_iter().map(|hash| (hash, None)).collect();
+    while let Some((hash, level)) = leaves.pop(){
+        if marked.over_limit(){
+            return;
+        }
+        if !marked.reachable.insert(hash.clone()) || level == Some(0){
+            continue;
+        }
+        if let Ok(Ok(block)) = store.get(hash.clone()).wait(){
+            let manifest = match level{
+                Some(level) => Manifest::decode_at(&block[..], level).or_else(||{
+                    warn!("{:?} is listed as a manifest of level {} but isn't one", hash, level);
+                    None
+                }),
+                None => Manifest::decode(&block[..])
+            };
+            if let Some(manifest) = manifest{
+                let level = manifest.level - 1;
+                leaves.extend(manifest.chunks.into_iter().map(|hash| (hash, Some(level))));
+            }
+        }
+    }
+}
+
+// every block reachable from tile library roots, including the chunks of any objects.
+// A root that isn't a VerifiedData (i.e. an uploaded file) counts as plain data.
+pub fn reachable(store: &BlockStore, roots: Vec<BlockHash>) -> Vec<BlockHash>{
+    reachable_from(store, roots, Marked::default())
+        .expect("marking without a limit can't go over it")
+}
+
+// as reachable, but gives up with None once more than limit blocks are reachable. With
+// from_height a chain is only walked down to the state of the checkpoint trusted_from finds
+// for it, which is as far as verify_history from the same height goes.
+pub fn reachable_within(store: &BlockStore, roots: Vec<BlockHash>, limit: usize, from_height: Option<u64>)
+    -> Option<Vec<BlockHash>>
+{
+    let mut marked = Marked::within(limit);
+    if let Some(height) = from_height{
+        for root in roots.iter(){
+            // a root without checkpoints is walked all the way
+            if let Ok(Some((_, checkpoint))) = checkpoint::trusted_from::<NamedHash>(store, root, height){
+                marked.stop.insert(checkpoint.state);
+            }
+        }
+    }
+    reachable_from(store, roots, marked)
+}
+
+fn reachable_from(store: &BlockStore, roots: Vec<BlockHash>, marked: Marked) -> Option<Vec<BlockHash>>{
+    let mut marked = mark_into::<NamedHash, NamedHashCommand>(store, roots, marked);
+    let stopped = marked.over_limit();
+    let plain = mem::replace(&mut marked.invalid, Vec::new());
+    for hash in plain.iter(){
+        marked.reachable.remove(hash);
+    }
+    mark_leaves(store, &mut marked, plain);
+    if stopped || marked.over_limit(){
+        None
+    }
+    else{
+        Some(marked.reachable.into_iter().collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GcOptions{
+    pub dry_run: bool,
+    pub grace:   Duration,
+    pub pins:    Vec<BlockHash>
+}
+
+impl Default for GcOptions{
+    fn default() -> Self{
+        GcOptions{
+            dry_run: false,
+            grace:   Duration::from_secs(DEFAULT_GRACE_SECONDS),
+            pins:    Vec::new()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GcReport{
+    pub dry_run: bool,
+    pub marked:  Marked,
+    pub sweep:   SweepReport
+}
+
+impl fmt::Display for GcReport{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{}examined {} blocks, {} reachable, {} {}, {} spared by grace period",
+               if self.dry_run { "(dry run) " } else { "" },
+               self.sweep.examined,
+               self.marked.reachable.len(),
+               self.sweep.swept.len(),
+               if self.dry_run { "would be collected" } else { "collected" },
+               self.sweep.spared.len())?;
+        if !self.marked.missing.is_empty(){
+            write!(f, "\nmissing chain blocks: {:?}", self.marked.missing)?;
+        }
+        if !self.marked.invalid.is_empty(){
+            write!(f, "\ninvalid chain blocks: {:?}", self.marked.invalid)?;
+        }
+        Ok(())
+    }
+}
+
+// blocking
+pub fn collect<T, C>(store: &BlockStore, roots: Vec<BlockHash>, options: &GcOptions)
+    -> io::Result<GcReport>
+    where T: Serialize + Debug + References,
+          C: Command<T> + References,
+    for <'de> T: Deserialize<'de>,
+    for <'de> C: Deserialize<'de>
+{
+    let mut marked = mark::<T, C>(store, roots);
+    marked.reachable.extend(options.pins.iter().cloned());
+
+    // whatever is behind an unreadable chain block is unknown, so deleting anything
+    // could break a chain that only looks unreachable
+    if !marked.invalid.is_empty() && !options.dry_run{
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("refusing to sweep, invalid chain blocks {:?}",
+                                          marked.invalid)));
+    }
+
+    let sweep = store.sweep(SweepRequest{
+            keep:    marked.reachable.clone(),
+            grace:   options.grace,
+            dry_run: options.dry_run
+        })
+        .wait()
+        .map_err(hung_up)??;
+
+    Ok(GcReport{
+        dry_run: options.dry_run,
+        marked,
+        sweep
+    })
+}
+
+// a missing pins file just means nothing is pinned
+pub fn load_pins<P: AsRef<Path>>(path: P) -> io::Result<Vec<BlockHash>>{
+    let file = match fs::File::open(path.as_ref()){
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+    let mut pins = Vec::new();
+    for line in io::BufReader::new(file).lines(){
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#'){
+            continue;
+        }
+        pins.push(line.parse()?);
+    }
+    Ok(pins)
+}
+
+pub fn tile_library_roots(secret_dir: &Path) -> io::Result<Vec<BlockHash>>{
+    Ok(VerifierMap::peek_dir(secret_dir.join(TILE_LIBRARY_DIR), Passphrase::Ask)?
+       .roots()
+       .into_iter()
+       .map(|(_, hash)| hash)
+       .collect())
+}
+
+// roots and pins are reloaded every time, the map thread owns the real Verifiers
+fn collect_tile_libraries(store: &BlockStore, secret_dir: &Path, options: &GcOptions) -> io::Result<GcReport>{
+    let roots = tile_library_roots(secret_dir)?;
+    let mut options = options.clone();
+    options.pins.extend(load_pins(secret_dir.join(PINS_FILE))?);
+    collect::<NamedHash, NamedHashCommand>(store, roots, &options)
+}
+
+// collects periodically alongside a running server
+pub fn spawn_thread(store: BlockStore, secret_dir: PathBuf, interval: Duration, options: GcOptions){
+    let _thread = thread::Builder::new()
+        .name("GC".into())
+        .spawn(move ||{
+            loop{
+                thread::sleep(interval);
+                match collect_tile_libraries(&store, &secret_dir, &options){
+                    Ok(report) => info!("GC: {}", report),
+                    Err(e) => error!("GC failed: {:?}", e)
+                }
+            }
+        }).unwrap();
+}
+
+// offline collection. Must not be run alongside a server using the same blocks!
+pub fn main(args: &ArgMatches){
+    let options = GcOptions{
+        dry_run: args.is_present("dry-run"),
+        grace: args.value_of("grace")
+            .map(|s| Duration::from_secs(s.parse().expect("--grace must be a number of seconds")))
+            .unwrap_or(Duration::from_secs(DEFAULT_GRACE_SECONDS)),
+        pins: Vec::new()
+    };
+    let block_store = spawn_block_thread(BlockStoreConfig::from_args(args));
+    match collect_tile_libraries(&block_store, &::secret_dir(args), &options){
+        Ok(report) => {
+            println!("{}", report);
+            if options.dry_run{
+                for hash in report.sweep.swept.iter(){
+                    println!("would collect {:?}", hash);
+                }
+            }
+        },
+        Err(e) => println!("GC failed: {:?}", e)
+    }
+}
@@ -1,5 +1,6 @@
 use hyper::server::{Http, Request, Response, Service, NewService};
 use hyper::{Error as HyperError,
+            StatusCode,
             header::{LastModified, Location, ContentLength, ContentType},
             //mime,
             //Body,
@@ -16,15 +17,17 @@ use futures::{sync::mpsc::{UnboundedReceiver, UnboundedSender,
 use tokio_core::{self, reactor::Handle};
 
 use map::MapThreadHandle;
-use block::{BlockStore, BlockData, BlockHash};
+use block::{BlockStore, BlockData, BlockHash, BlockStoreStats};
+use object::{Manifest, ObjectReader, ObjectWriter};
 
 use std::thread;
 use std::thread::{JoinHandle};
 use std::io;
 use std::fs;
-use std::sync::{Arc, atomic::AtomicUsize};
+use std::sync::{Arc, mpsc, atomic::AtomicUsize};
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
+use std::net::SocketAddr;
 
 type ResponseFuture = Box<Future<Item=Response, Error=HyperError>>;
 
@@ -33,6 +36,31 @@ const SEND_WHOLE_FILE_THRESHOLD: usize = 1<<16; // 64K
 // number of chunks that fit in a File->HTTP chunk channel
 const CHUNK_CHANNEL_BOUND: usize = 4;
 const CHUNK_CHANNEL_SIZE:  usize = 1<<16; // 64K
+// uploads larger than these are refused unless configured otherwise
+pub const DEFAULT_MAX_BLOCK_SIZE:  usize = 1<<20; // 1M
+pub const DEFAULT_MAX_OBJECT_SIZE: usize = 1<<28; // 256M
+pub const DEFAULT_LISTEN: &'static str = "127.0.0.1:3000";
+// POST /block/reachable may ask about this many roots, and gets refused if more than
+// this many blocks are reachable from them
+pub const MAX_REACHABLE_ROOTS:  usize = 256;
+pub const MAX_REACHABLE_BLOCKS: usize = 1<<20;
+
+#[derive(Debug, Copy, Clone)]
+pub struct UploadLimits{
+    pub max_block_size:  usize, // PUT /block/
+    pub max_object_size: usize, // PUT /object/, stored chunked
+    pub serve_reachable: bool,  // POST /block/reachable, only for servers that are replicated from
+}
+
+impl Default for UploadLimits{
+    fn default() -> Self{
+        UploadLimits{
+            max_block_size:  DEFAULT_MAX_BLOCK_SIZE,
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+            serve_reachable: false
+        }
+    }
+}
 
 pub struct RoundRobin{
     counter: AtomicUsize,
@@ -125,7 +153,6 @@ r#"<tr class="{0}"><td><a href="{1}{4}">{1}</a></td><td class="{2}"></td><td>{3}
 fn http_get<P: AsRef<Path>>(handle: &Handle, path: P, path_str: String) -> io::Result<Response>{
     use std::io::Read;
     use std::ffi::OsStr; 
-    use hyper::StatusCode;
 
     let path = path.as_ref();
 
@@ -220,7 +247,18 @@ fn http_get<P: AsRef<Path>>(handle: &Handle, path: P, path_str: String) -> io::R
 enum FileThreadRequestKind{
     File,
     Block,
-    Upload
+    Object,
+    Upload,
+    UploadObject,
+    Have,
+    Reachable,
+    Status
+}
+
+// served as JSON on GET /status
+#[derive(Debug, Serialize)]
+struct StatusPage{
+    blocks: BlockStoreStats
 }
 
 type FileThreadRequest   = (FileThreadRequestKind, Request, String, FileThreadResponder);
@@ -228,32 +266,192 @@ type FileThreadSender    = UnboundedSender<FileThreadRequest>;
 type FileThreadReceiver  = UnboundedReceiver<FileThreadRequest>;
 type FileThreadResponder = OneshotSender<Response>;
 type FileThreadResponse  = OneshotReceiver<Response>;
+type ReachableRequest    = (Vec<BlockHash>, Option<u64>, FileThreadResponder); // roots, from-height
+type ReachableSender     = mpsc::Sender<ReachableRequest>;
 
-fn ise(responder: FileThreadResponder, s: String){
-    use hyper::StatusCode;
-    let error_page = format!("<h1>Internal Server Error</h1><h2>{}</h2><hr/><tt>Generated by {}</tt>",
-                             s, thread::current().name().unwrap());
+fn error_response(responder: FileThreadResponder, status: StatusCode, s: String){
+    let error_page = format!("<h1>{}</h1><h2>{}</h2><hr/><tt>Generated by {}</tt>",
+                             status, s, thread::current().name().unwrap());
     responder.send(
         Response::new()
             .with_header(ContentLength(error_page.len() as u64))
             .with_header(ContentType::html())
-            .with_status(StatusCode::InternalServerError)
+            .with_status(status)
             .with_body(error_page)).unwrap();
 }
 
+fn ise(responder: FileThreadResponder, s: String){
+    error_response(responder, StatusCode::InternalServerError, s)
+}
+
+#[derive(Debug)]
+enum UploadError{
+    Body(HyperError),
+    TooLarge,
+    Manifest, // a block that would pass for an object manifest
+    Store(io::Error),
+    Cancelled // BlockStore hung up its OneshotSender
+}
+
+// location is where it can be fetched from, /block/ or /object/
+fn upload_stored(responder: FileThreadResponder, location: &str, hash: BlockHash, new: bool){
+    let hash_b64 = hash.to_base64();
+    let status = if new { StatusCode::Created } else { StatusCode::Ok };
+    responder.send(
+        Response::new()
+            .with_header(ContentLength(hash_b64.len() as u64))
+            .with_header(ContentType::text())
+            .with_header(Location::new(format!("{}{}", location, hash_b64)))
+            .with_status(status)
+            .with_body(hash_b64)).unwrap();
+}
+
+fn upload_failed(responder: FileThreadResponder, e: UploadError, limit: usize){
+    match e{
+        UploadError::TooLarge =>
+            error_response(responder, StatusCode::PayloadTooLarge,
+                           format!("Uploads here may be at most {} bytes", limit)),
+        UploadError::Manifest =>
+            error_response(responder, StatusCode::BadRequest,
+                           "Blocks may not start like an object manifest, upload objects to /object/".into()),
+        UploadError::Body(e) =>
+            error_response(responder, StatusCode::BadRequest,
+                           format!("Failed to read request body: {}", e)),
+        e =>
+            ise(responder, format!("{:?}", e))
+    }
+}
+
+// don't bother reading the body if the client already told us it's too big
+fn check_content_length(request: &Request, limit: usize) -> Result<(), UploadError>{
+    match request.headers().get::<ContentLength>(){
+        Some(&ContentLength(len)) if len > limit as u64 => Err(UploadError::TooLarge),
+        _ => Ok(())
+    }
+}
+
+// base64 BlockHashes, one per line
+pub fn parse_hash_list(data: &[u8]) -> Result<Vec<BlockHash>, String>{
+    use std::str;
+
+    let s = str::from_utf8(data)
+        .map_err(|_| "Expected one base64 BlockHash per line".to_string())?;
+    s.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.parse().map_err(|e| format!("{}: {:?}", e, line)))
+        .collect()
+}
+
+pub fn format_hash_list<'a, I: Iterator<Item=&'a BlockHash>>(hashes: I) -> String{
+    let mut list = String::new();
+    for hash in hashes{
+        list.push_str(&hash.to_base64());
+        list.push('\n');
+    }
+    list
+}
+
+// reads a request body of parse_hash_list format.
+// Errors once the request has been responded to.
+fn read_hash_list(request: Request, max_size: usize, responder: FileThreadResponder)
+    -> Box<Future<Item=(Vec<BlockHash>, FileThreadResponder), Error=()>>
+{
+    use futures::future;
+
+    if let Err(e) = check_content_length(&request, max_size){
+        upload_failed(responder, e, max_size);
+        return Box::new(future::err(()));
+    }
+
+    Box::new(request.body()
+        .map_err(UploadError::Body)
+        .fold(Vec::new(), move |mut data, chunk| -> Result<Vec<u8>, UploadError>{
+            if data.len() + chunk.len() > max_size{
+                return Err(UploadError::TooLarge);
+            }
+            data.extend_from_slice(&chunk);
+            Ok(data)
+        })
+        .then(move |r|{
+            let data = match r{
+                Ok(data) => data,
+                Err(e) => {
+                    upload_failed(responder, e, max_size);
+                    return Err(());
+                }
+            };
+            match parse_hash_list(&data[..]){
+                Ok(hashes) => Ok((hashes, responder)),
+                Err(e) => {
+                    error_response(responder, StatusCode::BadRequest, e);
+                    Err(())
+                }
+            }
+        }))
+}
+
+fn send_hash_list<'a, I: Iterator<Item=&'a BlockHash>>(responder: FileThreadResponder, hashes: I){
+    let body = format_hash_list(hashes);
+    responder.send(
+        Response::new()
+            .with_header(ContentLength(body.len() as u64))
+            .with_header(ContentType::text())
+            .with_status(StatusCode::Ok)
+            .with_body(body)).unwrap();
+}
+
+// Walks chains for POST /block/reachable. A walk reads block after block from the
+// BlockStore, so it gets a thread of its own instead of holding up a file thread's Core,
+// and walks queue up behind each other instead of running at once.
+struct ReachableThread;
+impl ReachableThread{
+    fn spawn(block_store: BlockStore) -> ReachableSender{
+        use gc;
+
+        let (sender, receiver) = mpsc::channel::<ReachableRequest>();
+        let _thread = thread::Builder::new()
+            .name("Reachable".into())
+            .spawn(move ||{
+                for (roots, from_height, responder) in receiver{
+                    match gc::reachable_within(&block_store, roots, MAX_REACHABLE_BLOCKS, from_height){
+                        Some(reachable) => send_hash_list(responder, reachable.iter()),
+                        None => error_response(responder, StatusCode::BadRequest,
+                                               format!("More than {} blocks are reachable from these roots, ask about fewer at once",
+                                                       MAX_REACHABLE_BLOCKS))
+                    }
+                }
+            });
+
+        sender
+    }
+}
+
+// the from-height of a POST /block/reachable query, the only thing it may ask
+fn from_height(query: Option<&str>) -> Result<Option<u64>, String>{
+    let mut height = None;
+    for pair in query.unwrap_or("").split('&').filter(|pair| !pair.is_empty()){
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()){
+            (Some("from-height"), Some(value)) =>
+                height = Some(value.parse().map_err(|_| format!("from-height must be a number of states: {}", value))?),
+            _ => return Err(format!("Unknown query: {}", pair))
+        }
+    }
+    Ok(height)
+}
 
 struct FileThread;
 impl FileThread{
-    fn spawn(base_path: Arc<PathBuf>, block_store: BlockStore, n: usize) -> FileThreadSender{
+    fn spawn(base_path: Arc<PathBuf>, block_store: BlockStore, limits: UploadLimits, reachable: Option<ReachableSender>, n: usize) -> FileThreadSender{
         let (sender, receiver) = unbounded_channel();
         let _thread = thread::Builder::new()
             .name(format!("File IO {}", n))
-            .spawn(move || Self::run(base_path, block_store, receiver));
+            .spawn(move || Self::run(base_path, block_store, limits, reachable, receiver));
         
         sender
     }
     fn handle_file<P: AsRef<Path>>(handle: &Handle, base_path: P, request: Request, path_str: String, responder: FileThreadResponder) -> Result<(), ()>{
-        use hyper::StatusCode;
         use hyper::Method;
         use std::io::ErrorKind;
 
@@ -286,44 +484,257 @@ impl FileThread{
         Ok(())
     }
 
-    fn handle_block<P: AsRef<Path>>(handle: &Handle, store: &BlockStore, request: Request, path_str: String, responder: FileThreadResponder) -> Result<(), ()>{
+    // GET /block/{hash} sends the block byte for byte, so it always hashes to its name.
+    // GET /object/{hash} reassembles the object if the block is a manifest.
+    fn handle_block<P: AsRef<Path>>(handle: &Handle, store: &BlockStore, request: Request, path_str: String, responder: FileThreadResponder, object: bool) -> Result<(), ()>{
         use regex::{Regex};
         use block::BlockHash;
-        use hyper::StatusCode;
+        use hyper::Method;
 
         lazy_static!{
-            static ref BLOCK_REGEX: Regex = Regex::new("/block/([-_A-Za-z0-9]{43})").unwrap();
+            // any length, so that every hash algorithm fits
+            static ref BLOCK_REGEX: Regex = Regex::new("^/(?:block|object)/([-_A-Za-z0-9]+)$").unwrap();
+        }
+        let hash_b64 = match BLOCK_REGEX.captures(path_str.as_str()).and_then(|c| c.get(1)){
+            Some(hash_b64) => hash_b64,
+            None => {
+                error_response(responder, StatusCode::NotFound, "No such block".into());
+                return Ok(());
+            }
+        };
+        let hash = match hash_b64.as_str().parse::<BlockHash>(){
+            Ok(hash) => hash,
+            Err(e) => {
+                error_response(responder, StatusCode::BadRequest, format!("{}", e));
+                return Ok(());
+            }
+        };
+        if request.method() == &Method::Head{
+            let fut = store.has(hash)
+                .then(move |r| {
+                    match r{
+                        Ok(Ok(true)) =>
+                            responder.send(Response::new()
+                                           .with_status(StatusCode::Ok)).unwrap(),
+                        Ok(Ok(false)) =>
+                            responder.send(Response::new()
+                                           .with_status(StatusCode::NotFound)).unwrap(),
+                        r => ise(responder, format!("{:?}", r))
+                    }
+                    Ok(())
+                });
+            handle.spawn(fut);
+            return Ok(());
         }
-        let captures = BLOCK_REGEX.captures(path_str.as_str()).ok_or(())?;
-        if let Some(hash_b64) = captures.get(1){
-            let hash = BlockHash::from(hash_b64.as_str());
-            let fut = store.get(hash)
-                .map_err(|_| ())
-                .and_then(|r| Ok(r))
-                .then(|r: io::Result<BlockData>| match r{
-                    Ok(k) =>
+        let store = store.clone();
+        let chunk_handle = handle.clone();
+        let fut = store.get(hash)
+            .map_err(|_| ())
+            .and_then(|r| Ok(r))
+            .then(move |r: io::Result<BlockData>| match r{
+                Ok(k) => match if object { Manifest::decode(&k[..]) } else { None }{
+                    // reassemble large objects as they're sent
+                    Some(manifest) => {
+                        let len = manifest.size;
+                        let (sender, receiver) = bounded_channel(CHUNK_CHANNEL_BOUND);
+                        let sender = sender.sink_map_err(|_| ());
+                        let chunks = ObjectReader::new(store, manifest)
+                            .then(|r| Ok::<_, ()>(
+                                r.map(|d: BlockData| Chunk::from((*d).clone()))
+                                 .map_err(HyperError::Io)));
+                        chunk_handle.spawn(chunks.forward(sender)
+                                           .map(|_| ())
+                                           .map_err(|_| ()));
                         responder.send(
                             Response::new()
                                 .with_status(StatusCode::Ok)
-                                .with_body((*k).clone())).unwrap(),
-                    Err(e) => ise(responder, format!("{:?}", e))
-                });
-            handle.spawn(fut);
+                                .with_header(ContentLength(len))
+                                .with_body(receiver)).unwrap()
+                    },
+                    None =>
+                        responder.send(
+                            Response::new()
+                                .with_status(StatusCode::Ok)
+                                .with_body((*k).clone())).unwrap()
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+                    error_response(responder, StatusCode::NotFound,
+                                   "No such block".into()),
+                Err(e) => ise(responder, format!("{:?}", e))
+            });
+        handle.spawn(fut);
+        Ok(())
+    }
+
+    fn handle_upload(handle: &Handle, store: &BlockStore, max_block_size: usize, request: Request, path_str: String, responder: FileThreadResponder) -> Result<(), ()>{
+        // blocks are named by their hash, so there's nothing to put below /block/
+        if path_str != "/block/"{
+            error_response(responder, StatusCode::MethodNotAllowed,
+                           "Blocks can only be uploaded to /block/".into());
+            return Ok(());
         }
-        else{
-            ise(responder, "Bad block name".into());
+        if let Err(e) = check_content_length(&request, max_block_size){
+            upload_failed(responder, e, max_block_size);
+            return Ok(());
+        }
+
+        let store = store.clone();
+        let fut = request.body()
+            .map_err(UploadError::Body)
+            .fold(Vec::new(), move |mut data, chunk| -> Result<Vec<u8>, UploadError>{
+                // Content-Length is optional (and could be lying) so check as we go
+                if data.len() + chunk.len() > max_block_size{
+                    return Err(UploadError::TooLarge);
+                }
+                data.extend_from_slice(&chunk);
+                Ok(data)
+            })
+            .and_then(|data|
+                // only ObjectWriter makes manifests, out of chunks it has stored itself
+                if Manifest::has_magic(&data[..]){
+                    Err(UploadError::Manifest)
+                }
+                else{
+                    Ok(data)
+                })
+            .and_then(move |data| store.insert(Arc::new(data))
+                      .map_err(|_| UploadError::Cancelled))
+            .then(move |r| {
+                match r{
+                    Ok(Ok((hash, new))) => upload_stored(responder, "/block/", hash, new),
+                    Ok(Err(e)) => upload_failed(responder, UploadError::Store(e), max_block_size),
+                    Err(e) => upload_failed(responder, e, max_block_size)
+                }
+                Ok(())
+            });
+        handle.spawn(fut);
+        Ok(())
+    }
+
+    // like handle_upload, but split into chunks under a manifest as it arrives
+    fn handle_upload_object(handle: &Handle, store: &BlockStore, max_object_size: usize, request: Request, responder: FileThreadResponder) -> Result<(), ()>{
+        if let Err(e) = check_content_length(&request, max_object_size){
+            upload_failed(responder, e, max_object_size);
+            return Ok(());
         }
+
+        let fut = request.body()
+            .map_err(UploadError::Body)
+            .fold(ObjectWriter::new(store.clone()), move |mut writer, chunk| -> Result<ObjectWriter, UploadError>{
+                if writer.size() + chunk.len() as u64 > max_object_size as u64{
+                    return Err(UploadError::TooLarge);
+                }
+                writer.write(&chunk);
+                Ok(writer)
+            })
+            .and_then(|writer| writer.finish().map_err(UploadError::Store))
+            .then(move |r| {
+                match r{
+                    // chunks are deduplicated individually so there's no telling if the
+                    // whole object already existed
+                    Ok(hash) => upload_stored(responder, "/object/", hash, true),
+                    Err(e) => upload_failed(responder, e, max_object_size)
+                }
+                Ok(())
+            });
+        handle.spawn(fut);
+        Ok(())
+    }
+
+    // Responds with those of the hashes in the body that are stored, so a client can
+    // work out what it needs to upload.
+    fn handle_have(handle: &Handle, store: &BlockStore, max_size: usize, request: Request, responder: FileThreadResponder) -> Result<(), ()>{
+        let store = store.clone();
+        let fut = read_hash_list(request, max_size, responder)
+            .and_then(move |(hashes, responder)|{
+                store.has_many(hashes.clone())
+                    .then(move |r| {
+                        match r{
+                            Ok(Ok(have)) => {
+                                let have = hashes.iter()
+                                    .zip(have)
+                                    .filter(|&(_, h)| h)
+                                    .map(|(hash, _)| hash);
+                                send_hash_list(responder, have);
+                            },
+                            r => ise(responder, format!("{:?}", r))
+                        }
+                        Ok(())
+                    })
+            });
+        handle.spawn(fut);
+        Ok(())
+    }
+
+    // Responds with every block reachable from the roots in the body, for a peer
+    // replicating from this server (see replicate.rs). Only if serve_reachable is set.
+    // ?from-height=H stops at the checkpoint a replica verifying from H starts from.
+    fn handle_reachable(handle: &Handle, reachable: &Option<ReachableSender>, max_size: usize, request: Request, responder: FileThreadResponder) -> Result<(), ()>{
+        let reachable = match *reachable{
+            Some(ref reachable) => reachable.clone(),
+            None => {
+                error_response(responder, StatusCode::Forbidden,
+                               "This server isn't replicated from (see --serve-reachable)".into());
+                return Ok(());
+            }
+        };
+        let from_height = match from_height(request.query()){
+            Ok(from_height) => from_height,
+            Err(e) => {
+                error_response(responder, StatusCode::BadRequest, e);
+                return Ok(());
+            }
+        };
+        let fut = read_hash_list(request, max_size, responder)
+            .map(move |(roots, responder)|{
+                if roots.len() > MAX_REACHABLE_ROOTS{
+                    error_response(responder, StatusCode::BadRequest,
+                                   format!("At most {} roots may be asked about at once", MAX_REACHABLE_ROOTS));
+                }
+                else if let Err(mpsc::SendError((_, _, responder))) = reachable.send((roots, from_height, responder)){
+                    ise(responder, "The reachable thread has stopped".into());
+                }
+            });
+        handle.spawn(fut);
+        Ok(())
+    }
+
+    fn handle_status(handle: &Handle, store: &BlockStore, responder: FileThreadResponder) -> Result<(), ()>{
+        use serde_json;
+
+        let fut = store.stats()
+            .then(move |r| {
+                let status = r.map(|blocks| StatusPage{ blocks });
+                match status.map(|s| serde_json::to_string_pretty(&s)){
+                    Ok(Ok(body)) =>
+                        responder.send(
+                            Response::new()
+                                .with_header(ContentLength(body.len() as u64))
+                                .with_header(ContentType::json())
+                                .with_status(StatusCode::Ok)
+                                .with_body(body)).unwrap(),
+                    r => ise(responder, format!("{:?}", r))
+                }
+                Ok(())
+            });
+        handle.spawn(fut);
         Ok(())
     }
 
-    fn run(base_path: Arc<PathBuf>, block_store: BlockStore, receiver: FileThreadReceiver){
+    fn run(base_path: Arc<PathBuf>, block_store: BlockStore, limits: UploadLimits, reachable: Option<ReachableSender>, receiver: FileThreadReceiver){
         use self::FileThreadRequestKind::*;
         let mut core = tokio_core::reactor::Core::new().unwrap();
         let handle = core.handle();
         let recv_fut = receiver.for_each(move |(kind, request, path, responder)| match kind{
             File => Self::handle_file(&handle, base_path.as_ref(), request, path, responder),
-            Block => Self::handle_block(&handle, &block_store, request, path, responder),
-            Upload => unimplemented!()
+            Block => Self::handle_block(&handle, &block_store, request, path, responder, false),
+            Object => Self::handle_block(&handle, &block_store, request, path, responder, true),
+            Upload => Self::handle_upload(&handle, &block_store, limits.max_block_size, request, path, responder),
+            UploadObject => Self::handle_upload_object(&handle, &block_store, limits.max_object_size, request, responder),
+            // a line per hash, so allow about as many hashes as there are in a block's worth of manifest
+            Have => Self::handle_have(&handle, &block_store, limits.max_block_size, request, responder),
+            Reachable => Self::handle_reachable(&handle, &reachable, limits.max_block_size, request, responder),
+            Status => Self::handle_status(&handle, &block_store, responder)
         });
         core.run(recv_fut).unwrap();
     }
@@ -337,9 +748,15 @@ struct FileThreadPoolInner{
 struct FileThreadPool(Arc<FileThreadPoolInner>);
 
 impl FileThreadPool{
-    fn new(n_threads: usize, base_path: Arc<PathBuf>, block_store: BlockStore) -> FileThreadPool {
+    fn new(n_threads: usize, base_path: Arc<PathBuf>, block_store: BlockStore, limits: UploadLimits) -> FileThreadPool {
+        let reachable = if limits.serve_reachable{
+            Some(ReachableThread::spawn(block_store.clone()))
+        }
+        else{
+            None
+        };
         let threads = (0..n_threads)
-            .map(|n| FileThread::spawn(base_path.clone(), block_store.clone(), n))
+            .map(|n| FileThread::spawn(base_path.clone(), block_store.clone(), limits, reachable.clone(), n))
             .collect();
 
         FileThreadPool(Arc::new(FileThreadPoolInner{
@@ -354,23 +771,13 @@ impl FileThreadPool{
         self.0.threads[current_thread].clone()
     }
 
-    fn get_file(&self, request: Request, path: String)
-        -> FileThreadResponse
-    {
-        let thread = self.next();
-       
-        let (responder, response) = oneshot();
-        thread.unbounded_send((FileThreadRequestKind::File, request, path, responder))
-            .unwrap();
-        response
-    }
-    fn get_block(&self, request: Request, path: String)
+    fn dispatch(&self, kind: FileThreadRequestKind, request: Request, path: String)
         -> FileThreadResponse
     {
         let thread = self.next();
        
         let (responder, response) = oneshot();
-        thread.unbounded_send((FileThreadRequestKind::Block, request, path, responder))
+        thread.unbounded_send((kind, request, path, responder))
             .unwrap();
         response
     }
@@ -390,26 +797,40 @@ impl Service for MainService {
 
     fn call(&self, req: Request) -> Self::Future {
         use hyper::Method;
+        use self::FileThreadRequestKind::*;
         
         let path = decode_percent(&req);
-        let method = req.method();
+        let method = req.method().clone();
         
         trace!("{} - {} {}",
                thread::current().name().unwrap(), method, path);
 
-        if path.starts_with("/block/"){
-            if req.method() == &Method::Get{
-                return Box::new(self.file_threads.get_block(req, path).map_err(|_| HyperError::Closed));
-            }
-            else if req.method() == &Method::Put{
-                //return Box::new(self.file_threads.upload_block(req, path).map_err(|_| HyperError::Closed));
+        if method == Method::Put && path.starts_with("/map/"){
+            return Box::new(self.map_thread.call(req, path));
+        }
+        let kind = if path.starts_with("/block/"){
+            match method{
+                Method::Get | Method::Head => Block,
+                Method::Post if path == "/block/have" => Have,
+                Method::Post if path == "/block/reachable" => Reachable,
+                Method::Put | Method::Post => Upload,
+                _ => File
             }
         }
-        if req.method() == &Method::Put && path.starts_with("/map/"){
-            return Box::new(self.map_thread.call(req, path).map_err(|_| HyperError::Closed));
+        else if path == "/status" && method == Method::Get{
+            Status
         }
+        else if path == "/object/" && (method == Method::Put || method == Method::Post){
+            UploadObject
+        }
+        else if path.starts_with("/object/") && (method == Method::Get || method == Method::Head){
+            Object
+        }
+        else{
+            File
+        };
 
-        Box::new(self.file_threads.get_file(req, path).map_err(|_| HyperError::Closed))
+        Box::new(self.file_threads.dispatch(kind, req, path).map_err(|_| HyperError::Closed))
     }
 }
 
@@ -418,12 +839,12 @@ struct ServiceFactory{
 }
 
 impl ServiceFactory{
-    fn new(n_threads: usize, block_store: BlockStore, map_thread: MapThreadHandle)
+    fn new(n_threads: usize, block_store: BlockStore, limits: UploadLimits, map_thread: MapThreadHandle)
         -> ServiceFactory {
         ServiceFactory {
             proto:
                 MainService{
-                    file_threads: FileThreadPool::new(n_threads, Arc::new(PathBuf::from("public/".to_string())), block_store),
+                    file_threads: FileThreadPool::new(n_threads, Arc::new(PathBuf::from("public/".to_string())), block_store, limits),
                     map_thread
                 }
         }
@@ -536,18 +957,23 @@ impl Stream for ChunkReader{ // actually blocks so is always ready from the pers
     }
 }
 
-pub fn spawn_thread(n_threads: usize, block_store: BlockStore, map_thread: MapThreadHandle)
-    -> JoinHandle<()>{
-    thread::Builder::new()
+// also returns the address actually listened on, i.e. the port picked if listen's was 0
+pub fn spawn_thread(listen: SocketAddr, n_threads: usize, block_store: BlockStore, limits: UploadLimits, map_thread: MapThreadHandle)
+    -> (JoinHandle<()>, SocketAddr){
+    let (bound_sender, bound) = ::std::sync::mpsc::channel();
+    let thread = thread::Builder::new()
         .name("HTTP".into())
         .spawn(move ||{
-    let addr_string = "127.0.0.1:3000";
-    let addr        = addr_string.parse().unwrap();
-    let factory     = ServiceFactory::new(n_threads, block_store, map_thread);
+    let addr        = listen;
+    let factory     = ServiceFactory::new(n_threads, block_store, limits, map_thread);
     let server      = Http::new().bind(&addr, factory).unwrap();
+    let addr        = server.local_addr().unwrap();
+    bound_sender.send(addr).unwrap();
 
-    info!("Starting server on http://{}", addr_string);
+    info!("Starting server on http://{}", addr);
     server.run().unwrap();
-    }).unwrap()
+    }).unwrap();
+    let addr = bound.recv().expect("HTTP thread failed to bind");
+    (thread, addr)
 }
 
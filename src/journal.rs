@@ -0,0 +1,139 @@
+// Append-only log of every new latest BlockHash a Verifier accepts.
+// The Verifier's own file is only rewritten by VerifierMap::to_dir, so without this
+// a crash forgets every update accepted since the last sync.
+// Entries are msgpack JournalEntry values written back to back. A torn final entry
+// (crash halfway through a write) is dropped on replay.
+
+use rmp_serde::{to_vec_named as serialize, from_read as deserialize_from};
+
+use std::io::{self, Read, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use block::BlockHash;
+use ltime::SerializableTime;
+
+// how hard to try to get each entry onto the disk before the update is acknowledged
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncMode{
+    None, // leave it to the OS, a crash may lose recent entries
+    Data, // fdatasync after every entry
+    Full, // fsync (data and metadata) after every entry
+}
+
+impl Default for SyncMode{
+    fn default() -> Self{
+        SyncMode::Data
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry{
+    timestamp: SerializableTime,
+    latest:    BlockHash
+}
+
+#[derive(Debug)]
+pub struct Journal{
+    path: PathBuf,
+    file: fs::File,
+    sync: SyncMode
+}
+
+impl Journal{
+    // opens (creating if needed) the journal at path and replays it,
+    // returning the last latest recorded in it if there is one
+    pub fn open<P: AsRef<Path>>(path: P, sync: SyncMode) -> io::Result<(Journal, Option<BlockHash>)>{
+        if let Some(parent) = path.as_ref().parent(){
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path.as_ref())?;
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        let (latest, valid_len) = replay_entries(path.as_ref(), &contents[..]);
+        if valid_len < contents.len() as u64{
+            file.set_len(valid_len)?;
+        }
+        trace!("Replayed journal {:?}, latest {:?}", path.as_ref(), latest);
+
+        Ok((Journal{
+            path: path.as_ref().to_path_buf(),
+            file,
+            sync
+        }, latest))
+    }
+
+    // replays the journal at path without opening it for writing, for looking at the
+    // journal of a Verifier that is owned by someone else (i.e. a running server)
+    pub fn replay<P: AsRef<Path>>(path: P) -> io::Result<Option<BlockHash>>{
+        let mut contents = Vec::new();
+        match fs::File::open(path.as_ref()){
+            Ok(mut file) => file.read_to_end(&mut contents)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        Ok(replay_entries(path.as_ref(), &contents[..]).0)
+    }
+
+    pub fn append(&mut self, latest: &BlockHash) -> io::Result<()>{
+        let entry = JournalEntry{
+            timestamp: SerializableTime::from_system_now()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+            latest: latest.clone()
+        };
+        let data = serialize(&entry)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // one write so that a crash can only ever tear the last entry
+        self.file.write_all(&data[..])?;
+        self.sync()
+    }
+
+    // forget every entry, to be called once the Verifier itself has been written out
+    pub fn reset(&mut self) -> io::Result<()>{
+        self.file.set_len(0)?;
+        self.sync()
+    }
+
+    pub fn set_sync(&mut self, sync: SyncMode){
+        self.sync = sync;
+    }
+
+    fn sync(&mut self) -> io::Result<()>{
+        let result = match self.sync{
+            SyncMode::None => Ok(()),
+            SyncMode::Data => self.file.sync_data(),
+            SyncMode::Full => self.file.sync_all()
+        };
+        result.map_err(|e|{
+            error!("Failed to sync journal {:?}: {:?}", self.path, e);
+            e
+        })
+    }
+}
+
+// returns the last latest in contents and how many bytes of it were valid entries
+fn replay_entries(path: &Path, contents: &[u8]) -> (Option<BlockHash>, u64){
+    let mut rdr = io::Cursor::new(contents);
+    let mut latest = None;
+    let mut valid_len = 0;
+    while (rdr.position() as usize) < contents.len(){
+        match deserialize_from::<_, JournalEntry>(&mut rdr){
+            Ok(entry) => {
+                latest = Some(entry.latest);
+                valid_len = rdr.position();
+            },
+            Err(e) => {
+                error!("Journal {:?} has a bad entry at byte {} ({:?}), discarding the rest",
+                       path, valid_len, e);
+                break;
+            }
+        }
+    }
+    (latest, valid_len)
+}
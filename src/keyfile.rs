@@ -0,0 +1,79 @@
+// Encrypts, decrypts and re-encrypts keyfiles in place: plain KeyPair files such as
+// secret/root_key and secret/map_verifier, and Verifier files (i.e. secret/tile_library/*)
+// which have their KeyPair flattened into them.
+// The current passphrase comes from signed::PASSPHRASE_VAR and the new one from
+// NEW_PASSPHRASE_VAR, either being asked for if it isn't set.
+
+use serde_json::{self, Value};
+use clap::ArgMatches;
+
+use std::env;
+use std::fs;
+use std::io;
+
+use signed::{KeyPair, Passphrase, prompt};
+use verify::Verifier;
+
+pub const NEW_PASSPHRASE_VAR: &'static str = "HTG_NEW_KEY_PASSPHRASE";
+
+fn new_passphrase() -> io::Result<String>{
+    if let Ok(passphrase) = env::var(NEW_PASSPHRASE_VAR){
+        return Ok(passphrase);
+    }
+    let passphrase = prompt("New key passphrase: ")?;
+    if prompt("Repeat new key passphrase: ")? != passphrase{
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Passphrases don't match"));
+    }
+    Ok(passphrase)
+}
+
+// loads the KeyPair in path, changes it and writes the whole file back with write_then_rename
+fn rewrite<F>(path: &str, change: F) -> io::Result<()>
+    where F: FnOnce(&mut KeyPair) -> io::Result<()>
+{
+    let value: Value = serde_json::from_reader(fs::File::open(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if value.get("allowed").is_some(){
+        let mut verifier = Verifier::from_file(path, Passphrase::Ask)?;
+        change(&mut verifier.keypair)?;
+        verifier.to_file(path)
+    }
+    else{
+        let mut keypair = KeyPair::from_file(path, Passphrase::Ask)?;
+        change(&mut keypair)?;
+        keypair.to_file(path)
+    }
+}
+
+// must not be run alongside a server using the same keyfiles
+pub fn main(args: &ArgMatches){
+    let action = args.value_of("action").unwrap();
+    let new = match action{
+        "encrypt" | "reencrypt" => match new_passphrase(){
+            Ok(passphrase) => Some(passphrase),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        },
+        _ => None
+    };
+
+    for path in args.values_of("file").unwrap(){
+        let result = rewrite(path, |keypair| match action{
+            "encrypt" if keypair.is_sealed() =>
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "already encrypted")),
+            "decrypt" if !keypair.is_sealed() =>
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "not encrypted")),
+            "decrypt" => {
+                keypair.unseal();
+                Ok(())
+            },
+            _ => keypair.seal(new.as_ref().unwrap())
+        });
+        match result{
+            Ok(()) => println!("{}: {}ed", path, action),
+            Err(e) => println!("{}: failed to {}, {}", path, action, e)
+        }
+    }
+}
@@ -34,3 +34,12 @@ pub fn check_stale(virtual_now: SystemTime, timestamp: SystemTime, stale_seconds
 pub fn now_check_stale(timestamp: SystemTime, stale_seconds: u64) -> bool{
     check_stale(SystemTime::now(), timestamp, stale_seconds)
 }
+
+// whether timestamp is less than past seconds behind now, and no more than future seconds
+// (in whole seconds, as timestamps are) ahead of it
+pub fn within_window(now: SystemTime, timestamp: SystemTime, past: u64, future: u64) -> bool{
+    match now.duration_since(timestamp){
+        Ok(behind) => behind.as_secs() < past,
+        Err(e) => e.duration().as_secs() <= future
+    }
+}
@@ -2,6 +2,7 @@
 #![feature(nll)]
 #![feature(box_syntax)]
 #![feature(core_intrinsics)]
+#![feature(try_from)]
 extern crate test;
 
 extern crate futures;
@@ -54,12 +55,26 @@ extern crate sled;
 
 mod router;
 mod block;
+mod object;
+mod backend;
 mod signed;
 mod verify;
+mod acl;
+mod replay;
 mod update;
 //mod websocket;
 mod http;
 mod ltime;
+mod journal;
+mod gc;
+mod scrub;
+mod archive;
+mod replicate;
+mod rotate;
+mod delegate;
+mod keyfile;
+mod audit;
+mod checkpoint;
 mod tile;
 mod map;
 mod rebuilder;
@@ -68,6 +83,14 @@ mod reloader;
 mod run;
 mod view;
 
+// where keys, Verifiers (with their journals) and GC pins are kept, unless --secret-dir
+// says otherwise. Instances with different ones share nothing but the blocks they're given.
+pub const DEFAULT_SECRET_DIR: &'static str = "secret/";
+
+pub fn secret_dir(args: &clap::ArgMatches) -> std::path::PathBuf{
+    std::path::PathBuf::from(args.value_of("secret-dir").unwrap_or(DEFAULT_SECRET_DIR))
+}
+
 pub fn absolute_pathbuf<P: AsRef<std::path::Path>>(path: P) -> std::path::PathBuf{
     let path = path.as_ref();
     if path.is_absolute(){
@@ -118,8 +141,169 @@ fn main() {
         .version(env!("CARGO_PKG_VERSION"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .author(env!("CARGO_PKG_AUTHORS"))
+        .arg(Arg::with_name("store")
+             .long("store")
+             .global(true)
+             .takes_value(true)
+             .possible_values(&["sled", "dir", "memory"])
+             .help("Block storage backend (default sled)"))
+        .arg(Arg::with_name("blocks")
+             .long("blocks")
+             .global(true)
+             .takes_value(true)
+             .help("Where the block storage backend keeps its data (default public/blocks/ for sled, public/block_files/ for dir)"))
+        .arg(Arg::with_name("secret-dir")
+             .long("secret-dir")
+             .global(true)
+             .takes_value(true)
+             .help("Where keys, tile libraries and GC pins are kept (default secret/)"))
+        .arg(Arg::with_name("verify-reads")
+             .long("verify-reads")
+             .global(true)
+             .help("Rehash every block read from storage and refuse any that don't match"))
+        .arg(Arg::with_name("hash-algorithm")
+             .long("hash-algorithm")
+             .global(true)
+             .takes_value(true)
+             .possible_values(&["sha256", "blake2b"])
+             .help("Hash new blocks are stored under (default sha256)"))
+        .arg(Arg::with_name("ask-passphrase")
+             .long("ask-passphrase")
+             .global(true)
+             .help("Ask for the passphrase of encrypted keyfiles at startup (unless HTG_KEY_PASSPHRASE is set)"))
+        .arg(Arg::with_name("cache-bytes")
+             .long("cache-bytes")
+             .global(true)
+             .takes_value(true)
+             .help("Bytes of block data to keep cached in memory (default 16M)"))
         .subcommand(SubCommand::with_name("run")
-                    .about("Run the main server"))
+                    .about("Run the main server")
+                    .arg(Arg::with_name("max-block-size")
+                         .long("max-block-size")
+                         .takes_value(true)
+                         .help("Largest block (in bytes) accepted by PUT /block/"))
+                    .arg(Arg::with_name("max-object-size")
+                         .long("max-object-size")
+                         .takes_value(true)
+                         .help("Largest object (in bytes) accepted by PUT /object/"))
+                    .arg(Arg::with_name("listen")
+                         .long("listen")
+                         .takes_value(true)
+                         .help("Address to serve HTTP on (default 127.0.0.1:3000)"))
+                    .arg(Arg::with_name("serve-reachable")
+                         .long("serve-reachable")
+                         .help("Answer POST /block/reachable, so that other servers can replicate from this one"))
+                    .arg(Arg::with_name("no-reload")
+                         .long("no-reload")
+                         .help("Don't rebuild or live reload the client, i.e. for a second instance"))
+                    .arg(Arg::with_name("gc-interval")
+                         .long("gc-interval")
+                         .takes_value(true)
+                         .help("Collect unreachable blocks every this many seconds")))
+        .subcommand(SubCommand::with_name("gc")
+                    .about("Delete blocks unreachable from any tile library (server must not be running)")
+                    .arg(Arg::with_name("dry-run")
+                         .long("dry-run")
+                         .short("n")
+                         .help("Only report what would be deleted"))
+                    .arg(Arg::with_name("grace")
+                         .long("grace")
+                         .takes_value(true)
+                         .help("Spare blocks set within this many seconds")))
+        .subcommand(SubCommand::with_name("scrub")
+                    .about("Check every block for corruption and report orphans (server must not be running)"))
+        .subcommand(SubCommand::with_name("export")
+                    .about("Write every block reachable from some roots to an archive (server must not be running)")
+                    .arg(Arg::with_name("archive")
+                         .index(1)
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("verifier")
+                         .long("verifier")
+                         .short("v")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Export from the latest of this tile library"))
+                    .arg(Arg::with_name("hash")
+                         .long("hash")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Export from this block")))
+        .subcommand(SubCommand::with_name("import")
+                    .about("Check and store every block in an archive (server must not be running)")
+                    .arg(Arg::with_name("archive")
+                         .index(1)
+                         .required(true)
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("replicate")
+                    .about("Pull every block reachable from some roots from another server (this server must not be running)")
+                    .arg(Arg::with_name("peer")
+                         .index(1)
+                         .required(true)
+                         .takes_value(true)
+                         .help("Base URL of the server to pull from, i.e. http://127.0.0.1:3000"))
+                    .arg(Arg::with_name("hash")
+                         .long("hash")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .required(true)
+                         .help("Pull everything reachable from this block"))
+                    .arg(Arg::with_name("from-height")
+                         .long("from-height")
+                         .takes_value(true)
+                         .help("Only pull the states of each chain down to its checkpoint at about this height, trusting it for the rest")))
+        .subcommand(SubCommand::with_name("rotate-key")
+                    .about("Hand every tile library over to a new map verifier key (server must not be running)"))
+        .subcommand(SubCommand::with_name("delegate")
+                    .about("Issue a delegation letting a key update names within a scope")
+                    .arg(Arg::with_name("key")
+                         .index(1)
+                         .required(true)
+                         .takes_value(true)
+                         .help("Base64 public key to delegate to"))
+                    .arg(Arg::with_name("scope")
+                         .long("scope")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .required(true)
+                         .help("Names the key may update, i.e. tiles/* for every name starting tiles/"))
+                    .arg(Arg::with_name("expires")
+                         .long("expires")
+                         .takes_value(true)
+                         .help("Seconds until the delegation expires (default 30 days)"))
+                    .arg(Arg::with_name("keypair")
+                         .long("keypair")
+                         .takes_value(true)
+                         .help("Keypair file to issue with (default root_key in the secret dir)")))
+        .subcommand(SubCommand::with_name("keyfile")
+                    .about("Encrypt, decrypt or change the passphrase of keyfiles (server must not be running)")
+                    .arg(Arg::with_name("action")
+                         .index(1)
+                         .required(true)
+                         .takes_value(true)
+                         .possible_values(&["encrypt", "decrypt", "reencrypt"]))
+                    .arg(Arg::with_name("file")
+                         .index(2)
+                         .required(true)
+                         .multiple(true)
+                         .help("KeyPair or Verifier files, i.e. secret/root_key secret/tile_library/*")))
+        .subcommand(SubCommand::with_name("audit")
+                    .about("Replay the history of tile libraries to check it adds up to their latest (server must not be running)")
+                    .arg(Arg::with_name("verifier")
+                         .long("verifier")
+                         .short("v")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Audit this tile library (default all of them)"))
+                    .arg(Arg::with_name("from-height")
+                         .long("from-height")
+                         .takes_value(true)
+                         .help("Start from the checkpoint at about this height, trusting it for the chain below")))
         .subcommand(SubCommand::with_name("view")
                     .about("View a block")
                     .arg(Arg::with_name("type")
@@ -131,19 +315,59 @@ fn main() {
                     .arg(Arg::with_name("hash")
                          .short("h")
                          .index(2)
-                         .required(true)));
+                         .required(true))
+                    .arg(Arg::with_name("height")
+                         .long("height")
+                         .takes_value(true)
+                         .help("View the state this far from the root of the chain behind hash instead")));
     let args = app.clone().get_matches();
+
+    // before any threads start wanting keys
+    if args.is_present("ask-passphrase") ||
+        args.subcommand().1.map_or(false, |sub| sub.is_present("ask-passphrase"))
+    {
+        signed::ask_passphrase().expect("Failed to read passphrase");
+    }
     
-    if let Some(_run_args) = args.subcommand_matches("run"){
-        run::main()
+    if let Some(run_args) = args.subcommand_matches("run"){
+        run::main(run_args)
     }
     else if let Some(view_args) = args.subcommand_matches("view"){
         if let (Some(btype), Some(block)) =
             (view_args.value_of("type"), view_args.value_of("hash"))
         {
-            view::main(btype.to_string(), block.to_string())
+            view::main(block::BlockStoreConfig::from_args(view_args),
+                       btype.to_string(), block.to_string(),
+                       view_args.value_of("height").map(String::from))
         }
     }
+    else if let Some(gc_args) = args.subcommand_matches("gc"){
+        gc::main(gc_args)
+    }
+    else if let Some(scrub_args) = args.subcommand_matches("scrub"){
+        scrub::main(scrub_args)
+    }
+    else if let Some(export_args) = args.subcommand_matches("export"){
+        archive::export_main(export_args)
+    }
+    else if let Some(import_args) = args.subcommand_matches("import"){
+        archive::import_main(import_args)
+    }
+    else if let Some(replicate_args) = args.subcommand_matches("replicate"){
+        replicate::main(replicate_args)
+    }
+    else if let Some(rotate_args) = args.subcommand_matches("rotate-key"){
+        rotate::main(rotate_args)
+    }
+    else if let Some(delegate_args) = args.subcommand_matches("delegate"){
+        delegate::main(delegate_args)
+    }
+    else if let Some(keyfile_args) = args.subcommand_matches("keyfile"){
+        keyfile::main(keyfile_args)
+    }
+    else if let Some(audit_args) = args.subcommand_matches("audit"){
+        audit::main(audit_args)
+    }
     else{
         println!("No subcommand specified.");
         app.print_long_help().unwrap();
@@ -155,5 +379,74 @@ mod tests{
     use super::*;
     use test::Bencher;
 
+    use rmp_serde::{to_vec_named as serialize, from_slice as deserialize};
+    use rpds::HashTrieSet;
+
+    use block::BlockHash;
+    use signed::{Signed, KeyPair, check_batch};
+    use update::{Update, TestCommand};
+
+    const BENCH_UPDATES: usize = 256;
+
+    // encoded, like blocks, so every iteration decodes Signed that haven't been checked
+    fn signed_updates() -> (KeyPair, Vec<Vec<u8>>){
+        let keypair = KeyPair::generate();
+        let updates = (0..BENCH_UPDATES)
+            .map(|i|{
+                let update = TestCommand::Add(i as u64).into_update(BlockHash::of(&[i as u8]));
+                serialize(&Signed::sign(update, &keypair).unwrap()).unwrap()
+            })
+            .collect();
+        (keypair, updates)
+    }
+
+    #[bench]
+    fn verify_serially(b: &mut Bencher){
+        let (keypair, updates) = signed_updates();
+        let allowed = HashTrieSet::new().insert(keypair.public.clone());
+        b.bytes = updates.iter().map(|u| u.len() as u64).sum();
+        b.iter(||{
+            for update in updates.iter(){
+                let signed: Signed = deserialize(&update[..]).unwrap();
+                assert!(signed.verify::<Update<TestCommand>>(&allowed).is_ok());
+            }
+        });
+    }
+
+    // check_batch across so many threads, then verify as verify_serially does
+    fn verify_batched(b: &mut Bencher, threads: usize){
+        let (keypair, updates) = signed_updates();
+        let allowed = HashTrieSet::new().insert(keypair.public.clone());
+        b.bytes = updates.iter().map(|u| u.len() as u64).sum();
+        b.iter(||{
+            let mut batch: Vec<Signed> = updates.iter()
+                .map(|update| deserialize(&update[..]).unwrap())
+                .collect();
+            assert!(check_batch(batch.iter_mut().collect(), threads).into_iter().all(|good| good));
+            for signed in batch.iter(){
+                assert!(signed.verify::<Update<TestCommand>>(&allowed).is_ok());
+            }
+        });
+    }
+
+    #[bench]
+    fn verify_batched_1_thread(b: &mut Bencher){
+        verify_batched(b, 1);
+    }
+
+    #[bench]
+    fn verify_batched_2_threads(b: &mut Bencher){
+        verify_batched(b, 2);
+    }
+
+    #[bench]
+    fn verify_batched_4_threads(b: &mut Bencher){
+        verify_batched(b, 4);
+    }
+
+    #[bench]
+    fn verify_batched_8_threads(b: &mut Bencher){
+        verify_batched(b, 8);
+    }
 }
 
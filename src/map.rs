@@ -9,15 +9,18 @@ use futures::{sync::{mpsc::{UnboundedReceiver, UnboundedSender,
               Future,
               Stream};
 use serde::{Serialize, Deserialize};
-use rmp_serde::to_vec_named as serialize;
+use rmp_serde::{to_vec_named as serialize, from_slice as deserialize};
 
 use std::thread;
 use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use verify::{Verifier, VerifierMap, VerifierError, store_verified};
-use signed::{Signed, KeyPair};
+use signed::{Signed, KeyPair, Passphrase};
 use block::{BlockStore, BlockData, BlockHash};
 use update::{Command, NamedHash, NamedHashCommand};
+use acl::Acl;
 
 /* later
 type TileId = u8;
@@ -49,18 +52,27 @@ struct Map{
     links:  [Option<BlockHash>; 4] // connected map for each cardinal direction
 }*/
 
-use hyper::{Request, Response};
+use hyper::{Request, Response, Method, StatusCode, Error as HyperError};
 type PathString        = String;
-type MapThreadSender   = UnboundedSender<(Request, PathString, MapResponder)>;
-type MapThreadReceiver = UnboundedReceiver<(Request, PathString, MapResponder)>;
+type RequestBody       = Vec<u8>;
+type MapThreadSender   = UnboundedSender<(Method, PathString, RequestBody, MapResponder)>;
+type MapThreadReceiver = UnboundedReceiver<(Method, PathString, RequestBody, MapResponder)>;
 type MapResponder      = OneshotSender<Response>;
 type MapResponse       = OneshotReceiver<Response>;
 
+// a Signed update is tiny, anything bigger than this is not one
+const MAX_REQUEST_SIZE: usize = 1<<16; // 64K
+// journals keep every accepted update durable, so the Verifier files (and the journals they
+// replace) are only rewritten after an update at most this often, and when the thread stops
+const COMPACT_SECONDS: u64 = 60;
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag="Req")]
 pub enum VerifierRequest{
     Latest,
-    Update(Signed)
+    Update(Signed),
+    Acl,
+    ChangeAcl(Signed) // a Signed Update<AclCommand>
 }
 
 type FileThreadResponder = OneshotSender<Response>;
@@ -69,35 +81,44 @@ type FileThreadResponder = OneshotSender<Response>;
 #[serde(tag="Response", content="Result")]
 enum VerifierResponse{
     Latest(Option<BlockHash>),
-    VerifierResult(Result<BlockHash, VerifierError>)
+    VerifierResult(Result<BlockHash, VerifierError>),
+    Acl(Result<Acl, VerifierError>)
 }
 
+// both under the secret dir
+pub const TILE_LIBRARY_DIR: &'static str = "tile_library/";
+// signs new tile libraries, see rotate for changing it
+pub const MAP_VERIFIER_KEY: &'static str = "map_verifier";
+
 struct MapThread{
     store: BlockStore,
     tile_libraries: VerifierMap,
-    root_key: PublicKey
+    root_key: PublicKey,
+    compacted: Instant // when tile_libraries was last written out
 }
 
 impl MapThread{
-    fn new(store: BlockStore, root_key: PublicKey) -> MapThread{
-        const TILE_LIBRARY_DIR: &'static str = "secret/tile_library/";
-        const MAP_VERIFIER_KEY: &'static str = "secret/map_verifier";
-
-        let kp = KeyPair::from_file_or_new(MAP_VERIFIER_KEY);
+    fn new(store: BlockStore, secret_dir: PathBuf, root_key: PublicKey) -> MapThread{
+        let kp = KeyPair::from_file_or_new(secret_dir.join(MAP_VERIFIER_KEY), Passphrase::Ask);
+        let allowed = HashTrieSet::new().insert(root_key.clone());
         let empty_namedhash = // get hash of a namedhash root signed by the MAP_VERIFIER_KEY
             store_verified(&store,
                            NamedHash(HashTrieMap::<String, BlockHash>::new()),
-                           &kp)
+                           &kp,
+                           &allowed)
             .unwrap(); // XXX handle this properly
                                              
         MapThread{
             store,
-            tile_libraries: VerifierMap::from_dir(TILE_LIBRARY_DIR)
+            tile_libraries: VerifierMap::from_dir(secret_dir.join(TILE_LIBRARY_DIR), Passphrase::Ask)
                 .unwrap_or_else(|e| {
+                    // i.e. the wrong passphrase, creating new would overwrite main
+                    if e.kind() != io::ErrorKind::NotFound{
+                        panic!("Failed to load tile library VerifierMap: {}", e);
+                    }
                     error!("Failed to load tile library VerifierMap({}), creating new",
                            e);
-                    let mut vm = VerifierMap::new(TILE_LIBRARY_DIR);
-                    let allowed = HashTrieSet::new().insert(root_key.clone());
+                    let mut vm = VerifierMap::new(secret_dir.join(TILE_LIBRARY_DIR));
                     vm.add_new("main".into(),
                                Some(kp.clone()),
                                Some(allowed),
@@ -107,17 +128,19 @@ impl MapThread{
                     vm
                 }),
             root_key,
+            compacted: Instant::now()
         }
     }
     fn run(mut self, receiver: MapThreadReceiver){
         let mut core = tokio_core::reactor::Core::new().unwrap();
         let handle = core.handle();
 
-        let main = receiver.for_each(|(req, path, responder)| {
-            use hyper::{Method, header::{ContentLength, ContentType}, StatusCode};
+        let main = receiver.for_each(|(method, path, body, responder)| {
+            use hyper::header::{ContentLength, ContentType};
             use regex::{Regex, RegexSet};
 
-            const MAPLIBRARY_STR: &'static str = r"/map/library/([^/]+)(/(.+))?";
+            // the library itself, or its Acl. Anything else under it is no such map object
+            const MAPLIBRARY_STR: &'static str = r"^/map/library/([^/]+)(/acl)?$";
             const MAPLIBRARY_INDEX: usize = 0;
             lazy_static!{
                 static ref MAPLIBRARY_REGEX: Regex =
@@ -137,12 +160,15 @@ impl MapThread{
                         .with_body(error_page)).unwrap();
             };
 
-            let send_data = |responder: MapResponder, d: Vec<u8>|
+            let send_status_data = |responder: MapResponder, status: StatusCode, d: Vec<u8>|
                 responder.send(
                     Response::new()
                         .with_header(ContentLength(d.len() as u64))
-                        .with_status(StatusCode::Ok)
+                        .with_status(status)
                         .with_body(d)).unwrap();
+
+            let send_data = |responder: MapResponder, d: Vec<u8>|
+                send_status_data(responder, StatusCode::Ok, d);
             
             let send_block = |responder: MapResponder, b: io::Result<BlockData>| match b{
                 Ok(d) =>
@@ -162,13 +188,27 @@ impl MapThread{
                     }));
 
 
-            let method = req.method();
             let command = VALID_COMMANDS.matches(path.as_ref());
             if command.matched(MAPLIBRARY_INDEX){
                 let captures = MAPLIBRARY_REGEX.captures(path.as_ref()).unwrap(); // shouldn't fail
                 let lib_name = captures.get(1).unwrap().as_str(); // shouldn't fail
+                // the library's Acl rather than its value
+                let acl = captures.get(2).is_some();
                 // XXX maybe handle retreiving specific tile
-                if method == &Method::Get{
+                if method == Method::Get && acl{
+                    let response = verifier::<NamedHash, NamedHashCommand>(&self.store,
+                                                                           &mut self.tile_libraries,
+                                                                           lib_name.to_string(),
+                                                                           VerifierRequest::Acl);
+                    let status = match response{
+                        VerifierResponse::Acl(Err(e)) => verifier_error_status(e),
+                        _ => StatusCode::Ok
+                    };
+                    match serialize(&response){
+                        Ok(d) => send_status_data(responder, status, d),
+                        Err(e) => ise(responder, format!("Failed to encode {:?}: {:?}", response, e))
+                    }
+                } else if method == Method::Get{
                     if let VerifierResponse::Latest(latest) =
                         verifier::<NamedHash, NamedHashCommand>(&self.store,
                                                                 &mut self.tile_libraries,
@@ -177,20 +217,88 @@ impl MapThread{
                     {
                         send_data(responder, serialize(&latest).unwrap())
                     }
-                } else if method == &Method::Put{
-                    panic!("blah");
-                    // VerifierRequest::Update(
-                    // XXX sync less often, this is EXTREMELY inefficient!
-                    self.tile_libraries
-                        .to_dir()
-                        .unwrap();
+                } else if method == Method::Put{
+                    let response = match deserialize::<Signed>(&body[..]){
+                        Ok(signed) =>
+                            verifier::<NamedHash, NamedHashCommand>(&self.store,
+                                                                    &mut self.tile_libraries,
+                                                                    lib_name.to_string(),
+                                                                    if acl{
+                                                                        VerifierRequest::ChangeAcl(signed)
+                                                                    } else{
+                                                                        VerifierRequest::Update(signed)
+                                                                    }),
+                        Err(e) => {
+                            debug!("PUT {} body is not a Signed: {:?}", path, e);
+                            VerifierResponse::VerifierResult(Err(VerifierError::DecodeFailed))
+                        }
+                    };
+                    let status = match response{
+                        VerifierResponse::VerifierResult(Err(e)) => verifier_error_status(e),
+                        _ => {
+                            if self.compacted.elapsed() >= Duration::from_secs(COMPACT_SECONDS){
+                                if let Err(e) = self.tile_libraries.to_dir(){
+                                    error!("Failed to write tile library VerifierMap: {:?}", e);
+                                }
+                                self.compacted = Instant::now();
+                            }
+                            StatusCode::Ok
+                        }
+                    };
+                    match serialize(&response){
+                        Ok(d) => send_status_data(responder, status, d),
+                        Err(e) => ise(responder, format!("Failed to encode {:?}: {:?}", response, e))
+                    }
+                } else{
+                    let error_page = format!("<h1>Method {} not allowed</h1>", method);
+                    responder.send(
+                        Response::new()
+                            .with_header(ContentLength(error_page.len() as u64))
+                            .with_header(ContentType::html())
+                            .with_status(StatusCode::MethodNotAllowed)
+                            .with_body(error_page)).unwrap();
                 }
             }
+            else{
+                let error_page = format!("<h1>No such map object {}</h1>", path);
+                responder.send(
+                    Response::new()
+                        .with_header(ContentLength(error_page.len() as u64))
+                        .with_header(ContentType::html())
+                        .with_status(StatusCode::NotFound)
+                        .with_body(error_page)).unwrap();
+            }
 
             Ok(())
         });
 
         core.run(main).unwrap();
+
+        // every MapThreadHandle is gone, so nothing more will be journaled
+        if let Err(e) = self.tile_libraries.to_dir(){
+            error!("Failed to write tile library VerifierMap: {:?}", e);
+        }
+    }
+}
+
+fn verifier_error_status(e: VerifierError) -> StatusCode{
+    use self::VerifierError::*;
+    match e{
+        DisallowedKey => StatusCode::Forbidden,
+        BadSignature  => StatusCode::Unauthorized,
+        DecodeFailed  => StatusCode::BadRequest,
+        Stale         => StatusCode::UnprocessableEntity,
+        NotLatest     => StatusCode::Conflict,
+        UpdateErr     => StatusCode::UnprocessableEntity,
+        NoVerifier    => StatusCode::NotFound,
+        BadDelegation => StatusCode::Unauthorized,
+        Expired       => StatusCode::Unauthorized,
+        OutOfScope    => StatusCode::Forbidden,
+        Pending       => StatusCode::Accepted,
+        Replayed      => StatusCode::Conflict,
+        WrongContext  => StatusCode::BadRequest,
+        LastErr |
+        StoreErr      => StatusCode::InternalServerError,
     }
 }
 
@@ -201,15 +309,19 @@ fn verifier<T, C>(store: &BlockStore, vmap: &mut VerifierMap, name: String, vreq
               T: Serialize + ::std::fmt::Debug,
               C: Command<T>
 {
-    use self::VerifierRequest::{Latest as ReqLatest, *};
-    use self::VerifierResponse::{Latest as RespLatest, *};
+    use self::VerifierRequest::{Latest as ReqLatest, Acl as ReqAcl, *};
+    use self::VerifierResponse::{Latest as RespLatest, Acl as RespAcl, *};
     match vreq{
        ReqLatest =>
            RespLatest(vmap.latest(&name)),
        Update(signed) =>
            VerifierResult(
                vmap.verify::<T,C>(store, signed, &name)
-                   .wait())
+                   .wait()),
+       ReqAcl =>
+           RespAcl(vmap.acl::<T>(store, &name)),
+       ChangeAcl(signed) =>
+           VerifierResult(vmap.change_acl::<T>(store, signed, &name))
     }
 }
 
@@ -218,24 +330,45 @@ pub struct MapThreadHandle(MapThreadSender);
 
 impl MapThreadHandle{
     pub fn call(&self, req: Request, path: PathString)
-        -> impl Future<Item=Response, Error=OneshotCanceled>
+        -> impl Future<Item=Response, Error=HyperError>
     {
-        let (responder, response) = oneshot();
-        if let Err(_) = self.0.unbounded_send((req, path, responder)){
-            error!("MapThread closed its Receiver!");
-            panic!("MapThread closed its Receiver!");
-        }
-        response
+        use futures::future::{self, Either};
+
+        // read the whole body here rather than on the MapThread so that a slow client
+        // can't stall every other map request
+        let sender = self.0.clone();
+        let method = req.method().clone();
+        req.body()
+            .fold((Vec::new(), false), |(mut body, too_large), chunk| -> Result<_, HyperError>{
+                if too_large || body.len() + chunk.len() > MAX_REQUEST_SIZE{
+                    return Ok((body, true)); // keep draining but stop buffering
+                }
+                body.extend_from_slice(&chunk);
+                Ok((body, false))
+            })
+            .and_then(move |(body, too_large)|{
+                if too_large{
+                    return Either::A(future::ok(
+                        Response::new()
+                            .with_status(StatusCode::PayloadTooLarge)));
+                }
+                let (responder, response) = oneshot();
+                if let Err(_) = sender.unbounded_send((method, path, body, responder)){
+                    error!("MapThread closed its Receiver!");
+                    panic!("MapThread closed its Receiver!");
+                }
+                Either::B(response.map_err(|_: OneshotCanceled| HyperError::Closed))
+            })
     }
 }
 
-pub fn spawn_thread(store: BlockStore, root_key: PublicKey) -> MapThreadHandle{
+pub fn spawn_thread(store: BlockStore, secret_dir: PathBuf, root_key: PublicKey) -> MapThreadHandle{
     let (sender, receiver) = unbounded_channel();
 
     let _thread = thread::Builder::new()
         .name("Map".into())
         .spawn(move ||{
-            let map = MapThread::new(store, root_key);
+            let map = MapThread::new(store, secret_dir, root_key);
             map.run(receiver);
         });
 
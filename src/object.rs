@@ -0,0 +1,226 @@
+// Large objects (tilesets, music, map archives) stored as a Merkle DAG of blocks.
+// An object is split into fixed size chunks, each stored as its own block, and a
+// manifest block lists the chunks in order. Manifests with too many chunks are split
+// the same way, so a manifest's children may be manifests themselves; each manifest says
+// which by its level. Only an object's root is taken to be a manifest because of how it
+// starts, anything under it is whatever its parent says it is.
+// Objects no larger than one chunk are just stored as a plain block, unless they'd pass
+// for a manifest.
+
+use futures::{Future, Stream, Async, Poll, future};
+use rmp_serde::{to_vec_named as serialize, from_slice as deserialize};
+
+use std::io;
+use std::sync::Arc;
+
+use block::{BlockStore, BlockData, BlockHash, BlockGetResponse, BlockSetResponse, hung_up};
+
+// chunks are kept to the size the BlockStore cache is tuned for
+pub const OBJECT_CHUNK_SIZE: usize = 1<<16; // 64K
+// keeps a manifest itself comfortably below OBJECT_CHUNK_SIZE
+const MAX_MANIFEST_CHUNKS: usize = 512;
+// 512^4 chunks of 64K is far more than will ever be stored
+pub const MAX_MANIFEST_LEVEL: u8 = 4;
+// marks a block as a manifest rather than plain data
+const MANIFEST_MAGIC: &'static [u8] = b"HTG-MANIFEST-1\0";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest{
+    pub size:   u64, // total bytes once fully reassembled
+    pub level:  u8,  // 1 if chunks are data, otherwise they're manifests of level - 1
+    pub chunks: Vec<BlockHash>
+}
+
+impl Manifest{
+    // None if data, as the root of an object, is not a manifest
+    pub fn decode(data: &[u8]) -> Option<Manifest>{
+        if !data.starts_with(MANIFEST_MAGIC){
+            return None;
+        }
+        deserialize(&data[MANIFEST_MAGIC.len()..])
+            .map_err(|e| debug!("Block has manifest magic but doesn't decode: {:?}", e))
+            .ok()
+            .and_then(|manifest: Manifest|
+                if manifest.level == 0 || manifest.level > MAX_MANIFEST_LEVEL ||
+                    manifest.chunks.len() > MAX_MANIFEST_CHUNKS
+                {
+                    debug!("Manifest of level {} with {} chunks is out of bounds",
+                           manifest.level, manifest.chunks.len());
+                    None
+                }
+                else{
+                    Some(manifest)
+                })
+    }
+    // whether data starts like a manifest, whether or not it decodes to one
+    pub fn has_magic(data: &[u8]) -> bool{
+        data.starts_with(MANIFEST_MAGIC)
+    }
+    // None unless data is a manifest of level, as a manifest one level up says it is
+    pub fn decode_at(data: &[u8], level: u8) -> Option<Manifest>{
+        Self::decode(data).and_then(|manifest| if manifest.level == level { Some(manifest) } else { None })
+    }
+    pub fn encode(&self) -> Vec<u8>{
+        let mut data = MANIFEST_MAGIC.to_vec();
+        // can't fail, Manifest is just numbers and bytes
+        data.extend(serialize(self).unwrap());
+        data
+    }
+}
+
+// Splits everything written into chunks and sends them to the BlockStore as it goes.
+// Hashes are computed here so that nothing has to wait for the BlockStore until finish.
+pub struct ObjectWriter{
+    store:   BlockStore,
+    buf:     Vec<u8>,
+    size:    u64,
+    chunks:  Vec<(BlockHash, u64)>, // (hash, bytes under it)
+    pending: Vec<BlockSetResponse>
+}
+
+impl ObjectWriter{
+    pub fn new(store: BlockStore) -> ObjectWriter{
+        ObjectWriter{
+            store,
+            buf:     Vec::with_capacity(OBJECT_CHUNK_SIZE),
+            size:    0,
+            chunks:  Vec::new(),
+            pending: Vec::new()
+        }
+    }
+
+    pub fn size(&self) -> u64{
+        self.size
+    }
+
+    pub fn write(&mut self, mut data: &[u8]){
+        self.size += data.len() as u64;
+        while !data.is_empty(){
+            let take = ::std::cmp::min(OBJECT_CHUNK_SIZE - self.buf.len(), data.len());
+            self.buf.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buf.len() == OBJECT_CHUNK_SIZE{
+                self.flush_chunk();
+            }
+        }
+    }
+
+    fn flush_chunk(&mut self){
+        let chunk = ::std::mem::replace(&mut self.buf, Vec::with_capacity(OBJECT_CHUNK_SIZE));
+        let len = chunk.len() as u64;
+        let hash = self.store_block(chunk);
+        self.chunks.push((hash, len));
+    }
+
+    fn store_block(&mut self, data: Vec<u8>) -> BlockHash{
+        // must match what the BlockStore will store it under
+        let hash = BlockHash::with(self.store.algorithm(), &data[..]);
+        let response = self.store.set(Arc::new(data));
+        self.pending.push(response);
+        hash
+    }
+
+    // stores what's left and the manifests, resolving to the object's hash once
+    // every block has been stored
+    pub fn finish(mut self) -> impl Future<Item=BlockHash, Error=io::Error>{
+        let hash =
+            if self.chunks.is_empty() && !self.buf.starts_with(MANIFEST_MAGIC){
+                // small enough to not need a manifest
+                let data = ::std::mem::replace(&mut self.buf, Vec::new());
+                self.store_block(data)
+            }
+            else{
+                if !self.buf.is_empty(){
+                    self.flush_chunk();
+                }
+                // an object of one chunk gets a manifest too if it got here
+                let mut level = ::std::mem::replace(&mut self.chunks, Vec::new());
+                let mut height = 0;
+                while level.len() > 1 || height == 0{
+                    height += 1;
+                    let mut next = Vec::new();
+                    for group in level.chunks(MAX_MANIFEST_CHUNKS){
+                        let manifest = Manifest{
+                            size:   group.iter().map(|&(_, size)| size).sum(),
+                            level:  height,
+                            chunks: group.iter().map(|&(ref hash, _)| hash.clone()).collect()
+                        };
+                        let size = manifest.size;
+                        next.push((self.store_block(manifest.encode()), size));
+                    }
+                    level = next;
+                }
+                level.pop().unwrap().0
+            };
+
+        let pending = ::std::mem::replace(&mut self.pending, Vec::new());
+        future::join_all(pending.into_iter().map(|response| response
+                .map_err(hung_up)
+                .and_then(|r| r)))
+            .map(move |_| hash)
+    }
+}
+
+// Streams the data under a manifest's chunks in order, expanding nested manifests.
+// Fails rather than send more or less than the manifest's size.
+pub struct ObjectReader{
+    store:     BlockStore,
+    stack:     Vec<(BlockHash, u8)>, // still to read with their levels (0 for data), next on top
+    current:   Option<(BlockGetResponse, u8)>,
+    remaining: u64 // of the size
+}
+
+impl ObjectReader{
+    pub fn new(store: BlockStore, manifest: Manifest) -> ObjectReader{
+        let level = manifest.level - 1;
+        ObjectReader{
+            store,
+            stack:     manifest.chunks.into_iter().rev().map(|hash| (hash, level)).collect(),
+            current:   None,
+            remaining: manifest.size
+        }
+    }
+}
+
+fn wrong_size(s: &str) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, s)
+}
+
+impl Stream for ObjectReader{
+    type Item = BlockData;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error>{
+        loop{
+            if self.current.is_none(){
+                match self.stack.pop(){
+                    Some((hash, level)) => self.current = Some((self.store.get(hash), level)),
+                    None if self.remaining > 0 =>
+                        return Err(wrong_size("object is smaller than its manifest says")),
+                    None => return Ok(Async::Ready(None))
+                }
+            }
+            let result = match self.current.as_mut().unwrap().0.poll(){
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(result)) => result,
+                Err(e) => Err(hung_up(e))
+            };
+            // a finished response mustn't be polled again, even after an error
+            let level = self.current.take().unwrap().1;
+            let data = result?;
+            if level == 0{
+                if data.len() as u64 > self.remaining{
+                    return Err(wrong_size("object is larger than its manifest says"));
+                }
+                self.remaining -= data.len() as u64;
+                return Ok(Async::Ready(Some(data)));
+            }
+            match Manifest::decode_at(&data[..], level){
+                Some(manifest) =>
+                    self.stack.extend(manifest.chunks.into_iter().rev().map(|hash| (hash, level - 1))),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("a manifest's chunk isn't a manifest of level {}", level)))
+            }
+        }
+    }
+}
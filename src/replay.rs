@@ -0,0 +1,185 @@
+// Replay protection for Verifiers. An update must be timestamped within the Verifier's
+// Freshness window and carry a nonce that the key answerable for it hasn't used in any update
+// still remembered. Nonces are forgotten once their updates would be stale anyway, or (the
+// oldest first) once a key has too many, after which nothing of that key's timestamped at or
+// before a forgotten one is accepted. Nonces are only kept in memory, so nothing from before
+// the window as it was when the Verifier was loaded is accepted either.
+
+use sodiumoxide::randombytes::randombytes_into;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::SystemTime;
+
+use signed::PublicKey;
+use ltime::{SerializableTime, within_window};
+
+pub const NONCE_BYTES: usize = 16;
+pub type Nonce = [u8; NONCE_BYTES];
+
+pub fn new_nonce() -> Nonce{
+    let mut nonce = [0u8; NONCE_BYTES];
+    randombytes_into(&mut nonce);
+    nonce
+}
+
+// how far an update's timestamp may be from the Verifier's clock, and how many nonces
+// it remembers for each key
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Freshness{
+    pub past:   u64,   // seconds behind
+    pub future: u64,   // seconds ahead, for clients whose clocks run fast
+    pub seen:   usize, // nonces remembered at most, per key
+}
+
+impl Default for Freshness{
+    fn default() -> Self{
+        Freshness{
+            past:   5,
+            future: 2,
+            seen:   4096
+        }
+    }
+}
+
+impl Freshness{
+    // past is given separately as a Threshold replaces it with its window
+    pub fn allows(&self, timestamp: &SerializableTime, past: u64) -> bool{
+        within_window(SystemTime::now(), timestamp.to_system(), past, self.future)
+    }
+}
+
+fn now() -> u64{
+    SerializableTime::from_system_now().map(|t| t.to_u64()).unwrap_or(0)
+}
+
+#[derive(Debug, Default)]
+struct KeyNonces{
+    floor: u64, // nothing of the key's timestamped at or before this is new
+    seen:  HashSet<Nonce>,
+    order: VecDeque<(u64, Nonce)>, // (timestamp, nonce) as recorded
+}
+
+#[derive(Debug)]
+pub struct SeenNonces{
+    loaded: u64, // when this was made, nonces from before then weren't kept
+    keys:   HashMap<PublicKey, KeyNonces>,
+}
+
+impl Default for SeenNonces{
+    fn default() -> Self{
+        SeenNonces{
+            loaded: now(),
+            keys:   HashMap::new()
+        }
+    }
+}
+
+impl SeenNonces{
+    // past as for Freshness::allows, so that the window reaches back as far as it did
+    // when this was made
+    pub fn is_new(&self, key: &PublicKey, nonce: &Nonce, timestamp: &SerializableTime, past: u64) -> bool{
+        let timestamp = timestamp.to_u64();
+        if timestamp <= self.loaded.saturating_sub(past){
+            return false;
+        }
+        match self.keys.get(key){
+            Some(nonces) => timestamp > nonces.floor && !nonces.seen.contains(nonce),
+            None => true
+        }
+    }
+
+    // remembers an applied update's nonce, forgetting what key no longer needs to
+    pub fn record(&mut self, key: PublicKey, nonce: Nonce, timestamp: &SerializableTime,
+                  freshness: &Freshness, past: u64)
+    {
+        let timestamp = timestamp.to_u64();
+        let now = now();
+        let forget = {
+            let nonces = self.keys.entry(key.clone()).or_insert_with(KeyNonces::default);
+            nonces.seen.insert(nonce);
+            nonces.order.push_back((timestamp, nonce));
+
+            while let Some((oldest, nonce)) = nonces.order.pop_front(){
+                let stale = oldest.saturating_add(past) <= now;
+                if !stale && nonces.order.len() < freshness.seen{
+                    nonces.order.push_front((oldest, nonce));
+                    break;
+                }
+                if !stale{
+                    nonces.floor = nonces.floor.max(oldest);
+                }
+                nonces.seen.remove(&nonce);
+            }
+            // once its floor is stale too there's nothing left to keep
+            nonces.order.is_empty() && nonces.floor.saturating_add(past) <= now
+        };
+        if forget{
+            self.keys.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::ops::Sub;
+    use std::time::Duration;
+
+    use signed::KeyPair;
+
+    fn ago(secs: u64) -> SerializableTime{
+        SerializableTime::from_system(SystemTime::now().sub(Duration::from_secs(secs))).unwrap()
+    }
+
+    #[test]
+    fn recorded_nonce_is_not_new_for_its_key(){
+        let (key, other) = (KeyPair::generate().public, KeyPair::generate().public);
+        let freshness = Freshness::default();
+        let past = freshness.past;
+        let mut seen = SeenNonces::default();
+        let nonce = new_nonce();
+        assert!(seen.is_new(&key, &nonce, &ago(0), past));
+        seen.record(key.clone(), nonce, &ago(0), &freshness, past);
+        assert!(!seen.is_new(&key, &nonce, &ago(0), past));
+        assert!(!seen.is_new(&key, &nonce, &ago(1), past));
+        assert!(seen.is_new(&other, &nonce, &ago(0), past));
+        assert!(seen.is_new(&key, &new_nonce(), &ago(0), past));
+    }
+
+    #[test]
+    fn window_reaches_back_from_when_it_was_loaded(){
+        let key = KeyPair::generate().public;
+        let past = Freshness::default().past;
+        let seen = SeenNonces::default();
+        // e.g. sent just before a restart
+        assert!(seen.is_new(&key, &new_nonce(), &ago(2), past));
+        assert!(!seen.is_new(&key, &new_nonce(), &ago(past + 1), past));
+        // a wider window, as a Threshold has, reaches back further
+        assert!(seen.is_new(&key, &new_nonce(), &ago(past + 1), past + 60));
+    }
+
+    #[test]
+    fn overflowing_seen_raises_the_floor_for_that_key_only(){
+        let (key, other) = (KeyPair::generate().public, KeyPair::generate().public);
+        let freshness = Freshness{ seen: 2, ..Freshness::default() };
+        let past = freshness.past;
+        let mut seen = SeenNonces::default();
+        let recorded: Vec<(Nonce, SerializableTime)> = (1..4).rev().map(|secs| (new_nonce(), ago(secs))).collect();
+        for &(nonce, ref timestamp) in recorded.iter(){
+            seen.record(key.clone(), nonce, timestamp, &freshness, past);
+        }
+
+        // the oldest was forgotten, so nothing of key's as old as it is new any more
+        let nonces = &seen.keys[&key];
+        assert_eq!(nonces.order.len(), 2);
+        assert_eq!(nonces.floor, recorded[0].1.to_u64());
+        assert!(!seen.is_new(&key, &recorded[0].0, &recorded[0].1, past));
+        assert!(!seen.is_new(&key, &new_nonce(), &recorded[0].1, past));
+        // the rest are still remembered
+        assert!(!seen.is_new(&key, &recorded[1].0, &recorded[1].1, past));
+        assert!(seen.is_new(&key, &new_nonce(), &recorded[1].1, past));
+        // other keys are as they were
+        assert!(seen.is_new(&other, &new_nonce(), &recorded[0].1, past));
+    }
+}
@@ -0,0 +1,280 @@
+// Pulls blocks from a peer server over its HTTP endpoints.
+// The peer is asked for everything reachable from some roots (POST /block/reachable, which
+// it only answers if run with --serve-reachable),
+// the hashes already stored locally are dropped (BlockStore::has_many) and the rest are
+// fetched as they are (GET /block/{hash}), rehashed, and stored.
+// With a from-height the peer stops each chain at a checkpoint, and the replica is only
+// complete enough for verify_history (or audit) from the same height, which trusts the
+// checkpoint's signer for the chain below it.
+// To try it with two instances on one machine, run the second with its own --blocks,
+// --secret-dir, --listen and --no-reload, then replicate into it from the first.
+
+use hyper::{self, Client, Method, Request, StatusCode, Uri};
+use futures::{Future, Stream, stream};
+use tokio_core::reactor::Core;
+use clap::ArgMatches;
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use block::{BlockHash, BlockStore, BlockStoreConfig, spawn_thread as spawn_block_thread, hung_up};
+use http::{parse_hash_list, format_hash_list};
+
+// blocks fetched from the peer at once
+const PULL_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Default)]
+pub struct ReplicationReport{
+    pub reachable: usize, // blocks under the roots on the peer
+    pub pulled:    usize, // blocks that were missing here and have now been stored
+    pub bytes:     u64,
+}
+
+impl fmt::Display for ReplicationReport{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{} blocks reachable on peer, pulled {} ({} bytes)",
+               self.reachable, self.pulled, self.bytes)
+    }
+}
+
+fn hyper_to_io(e: hyper::Error) -> io::Error{
+    match e{
+        hyper::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e)
+    }
+}
+
+fn bad_peer(s: String) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, s)
+}
+
+fn peer_uri(peer: &str, path: &str) -> io::Result<Uri>{
+    format!("{}{}", peer.trim_right_matches('/'), path)
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+// blocking, peer is the base URL of the server i.e. http://127.0.0.1:3000
+pub fn pull(store: &BlockStore, peer: &str, roots: Vec<BlockHash>, from_height: Option<u64>)
+    -> io::Result<ReplicationReport>
+{
+    let mut core = Core::new()?;
+    let client = Client::new(&core.handle());
+    let mut report = ReplicationReport::default();
+
+    let path = match from_height{
+        Some(height) => format!("/block/reachable?from-height={}", height),
+        None => "/block/reachable".into()
+    };
+    let mut request = Request::new(Method::Post, peer_uri(peer, &path)?);
+    request.set_body(format_hash_list(roots.iter()));
+    let reachable = client.request(request)
+        .map_err(hyper_to_io)
+        .and_then(|response|{
+            let status = response.status();
+            response.body().concat2()
+                .map_err(hyper_to_io)
+                .and_then(move |body|
+                    if status != StatusCode::Ok{
+                        Err(bad_peer(format!("peer refused the reachable list with {}: {}",
+                                             status, String::from_utf8_lossy(&body[..]))))
+                    }
+                    else{
+                        parse_hash_list(&body[..])
+                            .map_err(|e| bad_peer(format!("peer sent a bad reachable list: {}", e)))
+                    })
+        });
+    let reachable = core.run(reachable)?;
+    report.reachable = reachable.len();
+
+    let have = store.has_many(reachable.clone()).wait().map_err(hung_up)??;
+    let want: Vec<BlockHash> = reachable.into_iter()
+        .zip(have)
+        .filter(|&(_, have)| !have)
+        .map(|(hash, _)| hash)
+        .collect();
+    debug!("Pulling {} of {} blocks from {}", want.len(), report.reachable, peer);
+
+    let fetches = want.into_iter()
+        .map(|hash|{
+            peer_uri(peer, &format!("/block/{}", hash.to_base64()))
+                .map(|uri| (hash, uri))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let pulled = stream::iter_ok(fetches)
+        .map(|(hash, uri)| client.get(uri)
+             .map_err(hyper_to_io)
+             .and_then(move |response|{
+                 let status = response.status();
+                 response.body().concat2()
+                     .map_err(hyper_to_io)
+                     .and_then(move |body|
+                         if status != StatusCode::Ok{
+                             Err(bad_peer(format!("peer responded to {:?} with {}", hash, status)))
+                         }
+                         // the peer is no more trusted than an archive is
+                         else if !hash.is_hash_of(&body[..]){
+                             Err(bad_peer(format!("peer sent a block that isn't {:?}", hash)))
+                         }
+                         else{
+                             Ok((hash, body.to_vec()))
+                         })
+             }))
+        .buffer_unordered(PULL_CONCURRENCY)
+        .and_then(|(hash, data)|{
+            let len = data.len() as u64;
+            store.insert_with(hash.algorithm(), Arc::new(data))
+                .map_err(hung_up)
+                .and_then(|r| r)
+                .map(move |_| len)
+        })
+        .fold((0, 0), |(blocks, bytes), len| Ok::<_, io::Error>((blocks + 1, bytes + len)));
+    let (blocks, bytes) = core.run(pulled)?;
+    report.pulled = blocks;
+    report.bytes  = bytes;
+
+    Ok(report)
+}
+
+// must not be run alongside a server using the same blocks
+pub fn main(args: &ArgMatches){
+    let peer = args.value_of("peer").unwrap();
+    let roots: Result<Vec<BlockHash>, _> = args.values_of("hash")
+        .map(|hashes| hashes.map(str::parse).collect())
+        .unwrap_or(Ok(Vec::new()));
+    let roots = match roots{
+        Ok(roots) => roots,
+        Err(e) => {
+            println!("Bad --hash: {}", e);
+            return;
+        }
+    };
+    let from_height = match args.value_of("from-height").map(str::parse::<u64>).map_or(Ok(None), |r| r.map(Some)){
+        Ok(from_height) => from_height,
+        Err(e) => {
+            println!("Bad --from-height: {}", e);
+            return;
+        }
+    };
+    let block_store = spawn_block_thread(BlockStoreConfig::from_args(args));
+
+    match pull(&block_store, peer, roots, from_height){
+        Ok(report) => println!("{}", report),
+        Err(e) => println!("Replication failed: {:?}", e)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use futures::IntoFuture;
+    use rpds::{HashTrieMap, HashTrieSet};
+
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::process;
+
+    use block::spawn_memory_thread;
+    use http::{self, UploadLimits};
+    use map;
+    use object::{ObjectWriter, OBJECT_CHUNK_SIZE};
+    use ltime::SerializableTime;
+    use replay::new_nonce;
+    use signed::{KeyPair, Signed};
+    use update::{NamedHash, NamedHashCommand, Update};
+    use verify::{Verifier, store_verified, verify_history};
+    use gc;
+
+    // an instance with its own blocks and secret dir, listening on a port of its own
+    fn instance(name: &str, serve_reachable: bool) -> (BlockStore, SocketAddr, PathBuf){
+        let secret_dir = env::temp_dir().join(format!("htg-replicate-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&secret_dir);
+        fs::create_dir_all(&secret_dir).unwrap();
+
+        let store = spawn_memory_thread();
+        let map_thread = map::spawn_thread(store.clone(), secret_dir.clone(), KeyPair::generate().public);
+        let (_, addr) = http::spawn_thread("127.0.0.1:0".parse().unwrap(), 2, store.clone(),
+                                           UploadLimits{ serve_reachable, ..UploadLimits::default() }, map_thread);
+        (store, addr, secret_dir)
+    }
+
+    fn hashes(store: &BlockStore) -> HashSet<BlockHash>{
+        store.hashes().wait().unwrap().unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn pulls_everything_reachable_between_instances(){
+        let (first, first_addr, first_secrets) = instance("first", true);
+        let (second, second_addr, second_secrets) = instance("second", false);
+
+        // a state referring to an object big enough to need a manifest
+        let mut writer = ObjectWriter::new(first.clone());
+        let data: Vec<u8> = (0..3 * OBJECT_CHUNK_SIZE + 5).map(|i| (i % 251) as u8).collect();
+        writer.write(&data[..]);
+        let object = writer.finish().wait().unwrap();
+        let value = NamedHash(HashTrieMap::new().insert("tiles".to_string(), object));
+        let root = store_verified(&first, value, &KeyPair::generate(), &HashTrieSet::new()).unwrap();
+
+        let reachable: HashSet<BlockHash> = gc::reachable(&first, vec![root.clone()]).into_iter().collect();
+        assert_eq!(reachable.len(), 7); // the state, its Acl, the manifest and four chunks
+        assert!(reachable.iter().all(|hash| !hashes(&second).contains(hash)));
+
+        let peer = format!("http://{}", first_addr);
+        let report = pull(&second, &peer, vec![root.clone()], None).unwrap();
+        assert_eq!(report.reachable, reachable.len());
+        assert_eq!(report.pulled, reachable.len());
+        let pulled = hashes(&second);
+        assert!(reachable.iter().all(|hash| pulled.contains(hash)));
+        // nothing else the first instance has came along
+        let first_hashes = hashes(&first);
+        assert!(pulled.iter().all(|hash| reachable.contains(hash) || !first_hashes.contains(hash)));
+
+        // everything is already there the second time
+        let report = pull(&second, &peer, vec![root.clone()], None).unwrap();
+        assert_eq!(report.pulled, 0);
+
+        // the second instance doesn't serve reachable lists
+        assert!(pull(&first, &format!("http://{}", second_addr), vec![root], None).is_err());
+
+        let _ = fs::remove_dir_all(first_secrets);
+        let _ = fs::remove_dir_all(second_secrets);
+    }
+
+    #[test]
+    fn pulls_from_a_height_down_to_a_checkpoint(){
+        let (first, first_addr, first_secrets) = instance("first-from-height", true);
+        let (second, _, second_secrets) = instance("second-from-height", false);
+
+        let admin = KeyPair::generate();
+        let mut verifier = Verifier::new(None, Some(HashTrieSet::new().insert(admin.public.clone())), None);
+        verifier.checkpoint_every = Some(2);
+        verifier.force(&first, NamedHash::default()).unwrap();
+        for n in 0..8u8{
+            let name = format!("tiles/{}", n);
+            let tile = first.set(Arc::new(name.clone().into_bytes())).wait().unwrap().unwrap();
+            let update = Update{
+                timestamp: SerializableTime::from_system_now().unwrap(),
+                command:   NamedHashCommand::Set(name, tile),
+                last:      verifier.latest.borrow().clone().unwrap(),
+                nonce:     Some(new_nonce()),
+            };
+            let signed = Signed::sign(update, &admin).unwrap();
+            verifier.verify::<NamedHash, NamedHashCommand>(&first, signed).into_future().wait().unwrap();
+        }
+        let latest = verifier.latest.borrow().clone().unwrap();
+        let key = verifier.keypair.public.clone();
+
+        let report = pull(&second, &format!("http://{}", first_addr), vec![latest.clone()], Some(3)).unwrap();
+        assert!(report.pulled < gc::reachable(&first, vec![latest.clone()]).len());
+        assert_eq!(verify_history::<NamedHash, NamedHashCommand>(&second, latest.clone(), &key, Some(3)), Ok(5));
+        // but not all the way to the root
+        assert!(verify_history::<NamedHash, NamedHashCommand>(&second, latest, &key, None).is_err());
+
+        let _ = fs::remove_dir_all(first_secrets);
+        let _ = fs::remove_dir_all(second_secrets);
+    }
+}
@@ -0,0 +1,83 @@
+// Rotates the map verifier key, handing every tile library signed by it over to a new key.
+// The new key is kept in MAP_VERIFIER_KEY + "_next" until every library has been rotated
+// (each one atomically, see VerifierMap::rotate_key) and is then renamed over
+// MAP_VERIFIER_KEY, so a rotation that fails partway picks up where it left off when rerun.
+// Both are under the secret dir, as are the tile libraries.
+
+use clap::ArgMatches;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use block::{BlockHash, BlockStore, BlockStoreConfig, spawn_thread as spawn_block_thread};
+use signed::{KeyPair, Passphrase, passphrase};
+use update::{NamedHash, NamedHashCommand};
+use verify::VerifierMap;
+use map::{TILE_LIBRARY_DIR, MAP_VERIFIER_KEY};
+
+#[derive(Debug, Default)]
+pub struct RotationReport{
+    pub rotated: Vec<(String, BlockHash)>, // library and its new latest
+    pub skipped: Vec<String>, // libraries signed by some other key
+}
+
+fn next_key_path(secret_dir: &Path) -> PathBuf{
+    secret_dir.join(format!("{}_next", MAP_VERIFIER_KEY))
+}
+
+// blocking
+pub fn rotate_map_verifier(store: &BlockStore, secret_dir: &Path) -> io::Result<RotationReport>{
+    let key_path = secret_dir.join(MAP_VERIFIER_KEY);
+    let old = KeyPair::from_file(&key_path, Passphrase::Ask)?;
+    let next_path = next_key_path(secret_dir);
+    let new = match KeyPair::from_file(&next_path, Passphrase::Ask){
+        Ok(new) => {
+            info!("Resuming rotation to the key in {}", next_path.display());
+            new
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut new = KeyPair::generate();
+            if old.is_sealed(){
+                new.seal(&passphrase(true)?.unwrap())?; // the one that opened old
+            }
+            new.to_file(&next_path)?;
+            new
+        },
+        Err(e) => return Err(e)
+    };
+
+    let mut report = RotationReport::default();
+    let mut libraries = VerifierMap::from_dir(secret_dir.join(TILE_LIBRARY_DIR), Passphrase::Ask)?;
+    for (name, key) in libraries.keys(){
+        if key == new.public{
+            continue; // rotated by an earlier run
+        }
+        if key != old.public{
+            report.skipped.push(name);
+            continue;
+        }
+        let latest = libraries.rotate_key::<NamedHash, NamedHashCommand>(store, &name, new.clone())?;
+        report.rotated.push((name, latest));
+    }
+
+    fs::rename(&next_path, &key_path)?;
+    Ok(report)
+}
+
+// must not be run alongside a server using the same blocks and tile libraries
+pub fn main(args: &ArgMatches){
+    let block_store = spawn_block_thread(BlockStoreConfig::from_args(args));
+
+    match rotate_map_verifier(&block_store, &::secret_dir(args)){
+        Ok(report) => {
+            for &(ref name, ref latest) in report.rotated.iter(){
+                println!("rotated {}, latest is now {:?}", name, latest);
+            }
+            for name in report.skipped.iter(){
+                println!("skipped {}, not signed by the map verifier key", name);
+            }
+        },
+        Err(e) => println!("Key rotation failed: {:?}", e)
+    }
+}
@@ -1,44 +1,70 @@
-use std::path::{PathBuf};
+use clap::ArgMatches;
 
-use signed::{KeyPair};
-use block::{self};
+use signed::{KeyPair, Passphrase};
+use block::{self, BlockStoreConfig};
 use http;
 //use websocket;
 use map;
 use router;
 use rebuilder;
 use reloader;
+use gc;
 
-pub fn main(){
-    const BLOCKS_DIR: &'static str = "public/blocks/";
-    const ROOTKEY_FILE: &'static str = "secret/root_key";
+use std::time::Duration;
 
-    // quickfix: make sure secret/ exists
-    ::std::fs::create_dir_all("secret/").unwrap();
+// allowed to update every new tile library, and issues delegations. Under the secret dir.
+pub const ROOTKEY_FILE: &'static str = "root_key";
 
-    let pubsub  = router::PubSub::spawn_thread();
-    
-    rebuilder::spawn_thread(pubsub.clone());
-   
-    let block_store = block::spawn_thread(PathBuf::from(BLOCKS_DIR));
-    
-    let root = match KeyPair::from_file(ROOTKEY_FILE){
-        Ok(rk) => rk,
-        Err(e) => {
-            error!("Failed to load root keypair ({}), creating new one", e);
-            let root = KeyPair::generate();
-            root.to_file(ROOTKEY_FILE).unwrap();
-            root
-        }
+pub fn main(args: &ArgMatches){
+    let limits = http::UploadLimits{
+        max_block_size: args.value_of("max-block-size")
+            .map(|s| s.parse().expect("--max-block-size must be a number of bytes"))
+            .unwrap_or(http::DEFAULT_MAX_BLOCK_SIZE),
+        max_object_size: args.value_of("max-object-size")
+            .map(|s| s.parse().expect("--max-object-size must be a number of bytes"))
+            .unwrap_or(http::DEFAULT_MAX_OBJECT_SIZE),
+        serve_reachable: args.is_present("serve-reachable"),
     };
 
+    let listen = args.value_of("listen")
+        .unwrap_or(http::DEFAULT_LISTEN)
+        .parse()
+        .expect("--listen must be an address like 127.0.0.1:3000");
+
+    // quickfix: make sure the secret dir exists
+    let secret_dir = ::secret_dir(args);
+    ::std::fs::create_dir_all(&secret_dir).unwrap();
+
+    let block_store = block::spawn_thread(BlockStoreConfig::from_args(args));
+    
+    let root = KeyPair::from_file_or_new(secret_dir.join(ROOTKEY_FILE), Passphrase::Ask);
+
+    if let Some(interval) = args.value_of("gc-interval"){
+        let interval = interval.parse().expect("--gc-interval must be a number of seconds");
+        gc::spawn_thread(block_store.clone(),
+                         secret_dir.clone(),
+                         Duration::from_secs(interval),
+                         gc::GcOptions::default());
+    }
+
     let map_thread =
         map::spawn_thread(block_store.clone(),
+                          secret_dir,
                           root.public.clone());
 
-    http::spawn_thread(4, block_store.clone(), map_thread);
+    let (http_thread, _) = http::spawn_thread(listen, 4, block_store.clone(), limits, map_thread);
+
+    // the reloader listens on a fixed port, so only one instance per machine can have it
+    if args.is_present("no-reload"){
+        http_thread.join().unwrap();
+    }
+    else{
+        let pubsub  = router::PubSub::spawn_thread();
+
+        rebuilder::spawn_thread(pubsub.clone());
 
-    reloader::spawn_thread(pubsub).join().unwrap();
+        reloader::spawn_thread(pubsub).join().unwrap();
+    }
 
     //websocket::spawn_thread(block_store, map_thread).join().unwrap();
 }
@@ -0,0 +1,103 @@
+// Offline integrity check of every block in a BlockStore.
+// Each block is reread from the backend and rehashed, each Signed envelope that can
+// be decoded has its signature checked, and anything not reachable from a tile
+// library or pinned is reported as orphaned (i.e. what gc would collect).
+
+use rmp_serde::{from_slice as deserialize};
+use serde::de::IgnoredAny;
+use futures::Future;
+use clap::ArgMatches;
+
+use std::fmt;
+use std::io;
+
+use block::{BlockHash, BlockStore, BlockStoreConfig, spawn_thread as spawn_block_thread, hung_up};
+use signed::{Signed, VerifyError};
+use update::{NamedHash, NamedHashCommand};
+use gc;
+
+#[derive(Debug, Default)]
+pub struct ScrubReport{
+    pub examined:    usize,
+    pub signed:      usize, // valid Signed envelopes
+    pub raw:         usize, // not Signed at all, i.e. uploaded files
+    pub corrupt:     Vec<(BlockHash, String)>, // unreadable or doesn't match its hash
+    pub undecodable: Vec<(BlockHash, String)>, // a Signed envelope that doesn't verify
+    pub orphaned:    Vec<BlockHash>, // not reachable from any root or pin
+}
+
+impl fmt::Display for ScrubReport{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "examined {} blocks: {} signed, {} raw, {} corrupt, {} undecodable, {} orphaned",
+               self.examined, self.signed, self.raw,
+               self.corrupt.len(), self.undecodable.len(), self.orphaned.len())?;
+        for &(ref hash, ref why) in self.corrupt.iter(){
+            write!(f, "\ncorrupt     {:?}: {}", hash, why)?;
+        }
+        for &(ref hash, ref why) in self.undecodable.iter(){
+            write!(f, "\nundecodable {:?}: {}", hash, why)?;
+        }
+        for hash in self.orphaned.iter(){
+            write!(f, "\norphaned    {:?}", hash)?;
+        }
+        Ok(())
+    }
+}
+
+// store should have been spawned with verify_reads, otherwise corruption goes unnoticed
+pub fn scrub(store: &BlockStore, roots: Vec<BlockHash>, pins: Vec<BlockHash>)
+    -> io::Result<ScrubReport>
+{
+
+    let mut marked = gc::mark::<NamedHash, NamedHashCommand>(store, roots);
+    marked.reachable.extend(pins);
+
+    let mut report = ScrubReport::default();
+    for hash in store.hashes().wait().map_err(hung_up)??{
+        report.examined += 1;
+        if !marked.reachable.contains(&hash){
+            report.orphaned.push(hash.clone());
+        }
+
+        let data = match store.get(hash.clone()).wait().map_err(hung_up)?{
+            Ok(data) => data,
+            Err(e) => {
+                report.corrupt.push((hash, format!("{}", e)));
+                continue;
+            }
+        };
+
+        let signed: Signed = match deserialize(&data[..]){
+            Ok(signed) => signed,
+            Err(_) => {
+                report.raw += 1;
+                continue;
+            }
+        };
+        // whatever it was signed as
+        let payload = signed.open_payload()
+            .and_then(|(_, payload)| deserialize::<IgnoredAny>(&payload[..])
+                      .map_err(|_| VerifyError::DecodeFailed));
+        match payload{
+            Ok(_) => report.signed += 1,
+            Err(e) => report.undecodable.push((hash, format!("{:?}", e)))
+        }
+    }
+    Ok(report)
+}
+
+// must not be run alongside a server using the same blocks
+pub fn main(args: &ArgMatches){
+    let mut config = BlockStoreConfig::from_args(args);
+    config.verify_reads = true;
+    let block_store = spawn_block_thread(config);
+
+    let secret_dir = ::secret_dir(args);
+    let result = gc::tile_library_roots(&secret_dir)
+        .and_then(|roots| Ok((roots, gc::load_pins(secret_dir.join(gc::PINS_FILE))?)))
+        .and_then(|(roots, pins)| scrub(&block_store, roots, pins));
+    match result{
+        Ok(report) => println!("{}", report),
+        Err(e) => println!("Scrub failed: {:?}", e)
+    }
+}
@@ -1,27 +1,178 @@
-use serde::{Serialize, de::DeserializeOwned};
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de::DeserializeOwned};
 use serde_json::{to_writer as serialize_readable_file, from_reader as deserialize_readable_file};
 use rmp_serde::{to_vec as serialize_packed, to_vec_named as serialize, from_slice as deserialize};
 use rmpv::{decode::read_value as read_mp_value};
-use sodiumoxide::crypto::sign::ed25519::{sign as crypto_sign, verify as crypto_verify, gen_keypair};
+use sodiumoxide::crypto::sign::ed25519::{sign_detached, verify_detached, gen_keypair, Signature, SIGNATUREBYTES};
+use sodiumoxide::crypto::{pwhash, secretbox};
+use base64::{self, URL_SAFE_NO_PAD};
 use rpds::HashTrieSet;
 
-use std::io;
+use std::cmp;
+use std::env;
+use std::io::{self, Write};
 use std::fs;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use ltime::SerializableTime;
 
 pub use sodiumoxide::crypto::sign::ed25519::{PublicKey, SecretKey};
 
-#[derive(Debug, Serialize, Deserialize)]
+// Everything is signed inside an envelope naming what it was signed as, so that a signature
+// made for one type can't be passed off as another whose encoding happens to match:
+//   SIGNED_MAGIC, u8 SIGNED_VERSION, u8 length of the context, the context, msgpack payload
+// Blocks signed before envelopes existed are just the msgpack payload, see verify.
+// The message is kept apart from its signatures (see Signed), so any number of keys can sign
+// the one copy of it.
+const SIGNED_MAGIC: &'static [u8] = b"HTG-SIGNED\0";
+const SIGNED_VERSION: u8 = 1;
+
+// what a signature over a type is for
+pub trait Context{
+    fn context() -> String;
+}
+
+impl<'a, T: Context> Context for &'a T{
+    fn context() -> String{
+        T::context()
+    }
+}
+
+// the context (None if it was signed before envelopes) and payload of signed bytes
+fn open_envelope(message: &[u8]) -> VerifyResult<(Option<&str>, &[u8])>{
+    if !message.starts_with(SIGNED_MAGIC){
+        return Ok((None, message));
+    }
+    let rest = &message[SIGNED_MAGIC.len()..];
+    if rest.len() < 2 || rest[0] != SIGNED_VERSION{
+        return Err(VerifyError::DecodeFailed);
+    }
+    let len = rest[1] as usize;
+    let rest = &rest[2..];
+    if rest.len() < len{
+        return Err(VerifyError::DecodeFailed);
+    }
+    let context = ::std::str::from_utf8(&rest[..len])
+        .map_err(|_| VerifyError::DecodeFailed)?;
+    Ok((Some(context), &rest[len..]))
+}
+
+#[derive(Debug)]
 pub struct Signed{
-    pub user: PublicKey,
-    data: Vec<u8>,
+    payload: Vec<u8>, // the message, see SIGNED_MAGIC
+    // detached signatures over payload, never empty. The first signer is user.
+    signatures: Vec<(PublicKey, Signature)>,
+    // Signed Delegations leading from an allowed key to user, see verify_scoped
+    pub delegations: Vec<Signed>,
+    checked: bool, // the signatures (and delegations') are known to be good, see check_batch
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// how a Signed is read. Combined is what crypto_sign made before signatures were detached,
+// data being the signature followed by the message, and is converted when read.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SignedRepr{
+    Detached{
+        payload: Vec<u8>,
+        signatures: Vec<(PublicKey, Signature)>,
+        #[serde(default)]
+        delegations: Vec<Signed>,
+    },
+    Combined{
+        user: PublicKey,
+        data: Vec<u8>,
+        #[serde(default)]
+        delegations: Vec<Signed>,
+    }
+}
+
+// always written out detached
+impl Serialize for Signed{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>{
+        use serde::ser::SerializeStruct;
+        let fields = if self.delegations.is_empty() { 2 } else { 3 };
+        let mut state = serializer.serialize_struct("Signed", fields)?;
+        state.serialize_field("payload", &self.payload)?;
+        state.serialize_field("signatures", &self.signatures)?;
+        if !self.delegations.is_empty(){
+            state.serialize_field("delegations", &self.delegations)?;
+        }
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Signed{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Signed, D::Error>{
+        use serde::de::Error;
+        let signed = match SignedRepr::deserialize(deserializer)?{
+            SignedRepr::Detached{ payload, signatures, delegations } =>
+                Signed{ payload, signatures, delegations, checked: false },
+            SignedRepr::Combined{ user, data, delegations } =>
+                Signed::from_combined(user, data, delegations)
+                    .ok_or_else(|| Error::custom("combined Signed is shorter than a signature"))?
+        };
+        if signed.signatures.is_empty(){
+            return Err(Error::custom("Signed has no signatures"));
+        }
+        Ok(signed)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum VerifyError{
     DisallowedKey,
     BadSignature,
-    DecodeFailed
+    DecodeFailed,
+    BadDelegation, // a delegation chain that is malformed or widens its scope
+    Expired,       // a delegation in the chain had expired
+    WrongContext,  // signed as something else, or (where that's required) without an envelope
+}
+
+// names a delegated key may act on. A pattern ending in * matches every name it is a
+// prefix of (less the *), anything else matches only itself.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Scope(pub Vec<String>);
+
+fn pattern_matches(pattern: &str, name: &str) -> bool{
+    if pattern.ends_with('*'){
+        name.starts_with(&pattern[..pattern.len() - 1])
+    }
+    else{
+        pattern == name
+    }
+}
+
+impl Scope{
+    pub fn allows(&self, name: &str) -> bool{
+        self.0.iter().any(|pattern| pattern_matches(pattern, name))
+    }
+    // whether every name inner allows is allowed here too
+    pub fn covers(&self, inner: &Scope) -> bool{
+        inner.0.iter().all(|pattern| self.allows(pattern))
+    }
+}
+
+// issued (as a Signed) by a key for another key, letting it act for the issuer within
+// scope until expires
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Delegation{
+    pub key:     PublicKey,
+    pub scope:   Scope,
+    pub expires: SerializableTime,
+}
+
+impl Context for Delegation{
+    fn context() -> String{
+        "Delegation".into()
+    }
+}
+
+impl Delegation{
+    pub fn issue(self, issuer: &KeyPair) -> SignResult{
+        Signed::sign(self, issuer)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,25 +185,229 @@ pub type AllowedKeys = HashTrieSet<PublicKey>;
 pub type SignResult      = Result<Signed, SignError>;
 pub type VerifyResult<T> = Result<T, VerifyError>;
 impl Signed{
-    pub fn sign<T: Serialize>(t: T, keypair: &KeyPair) -> SignResult
+    pub fn sign<T: Serialize + Context>(t: T, keypair: &KeyPair) -> SignResult
     {
-        let serialized = serialize(&t)
-            .map_err(|_| SignError::EncodeFailed)?;
-        let signed = crypto_sign(&serialized[..], &keypair.secret);
+        let context = T::context();
+        if context.len() > u8::max_value() as usize{
+            return Err(SignError::EncodeFailed);
+        }
+        let mut message = SIGNED_MAGIC.to_vec();
+        message.push(SIGNED_VERSION);
+        message.push(context.len() as u8);
+        message.extend_from_slice(context.as_bytes());
+        message.extend(serialize(&t)
+            .map_err(|_| SignError::EncodeFailed)?);
+        let signature = sign_detached(&message[..], &keypair.secret);
         Ok(Signed{
-            user: keypair.public.clone(),
-            data: signed,
+            payload: message,
+            signatures: vec![(keypair.public.clone(), signature)],
+            delegations: Vec::new(),
+            checked: false
         })
     }
 
-    pub fn verify<T: DeserializeOwned>(&self, allowed: &AllowedKeys) -> VerifyResult<T>{
-        if !allowed.contains(&self.user){
+    // like sign, for a key acting under delegations (outermost first)
+    pub fn sign_delegated<T: Serialize + Context>(t: T, keypair: &KeyPair, delegations: Vec<Signed>) -> SignResult{
+        let mut signed = Self::sign(t, keypair)?;
+        signed.delegations = delegations;
+        Ok(signed)
+    }
+
+    // from crypto_sign's combined form, the signature followed by the message. None if
+    // data is too short to hold a signature, which isn't checked until verifying as usual.
+    pub fn from_combined(user: PublicKey, data: Vec<u8>, delegations: Vec<Signed>) -> Option<Signed>{
+        if data.len() < SIGNATUREBYTES{
+            return None;
+        }
+        let signature = Signature::from_slice(&data[..SIGNATUREBYTES])?;
+        Some(Signed{
+            payload: data[SIGNATUREBYTES..].to_vec(),
+            signatures: vec![(user, signature)],
+            delegations,
+            checked: false
+        })
+    }
+
+    // adds keypair's signature over the same payload, unless it has already signed
+    pub fn cosign(&mut self, keypair: &KeyPair){
+        if self.signers().any(|signer| *signer == keypair.public){
+            return;
+        }
+        let signature = sign_detached(&self.payload[..], &keypair.secret);
+        self.signatures.push((keypair.public.clone(), signature));
+        self.checked = false;
+    }
+
+    // takes other's signatures if it was signed over the same payload by keys acting for
+    // themselves, otherwise gives it back. Signatures aren't checked until verifying.
+    pub fn merge(&mut self, other: Signed) -> Result<(), Signed>{
+        if other.payload != self.payload || !other.delegations.is_empty(){
+            return Err(other);
+        }
+        for (signer, signature) in other.signatures{
+            if !self.signers().any(|s| *s == signer){
+                self.signatures.push((signer, signature));
+                self.checked = false;
+            }
+        }
+        Ok(())
+    }
+
+    // whoever signed first, the key any delegations are for
+    pub fn user(&self) -> &PublicKey{
+        &self.signatures[0].0
+    }
+
+    // every key that signed, user first
+    pub fn signers<'a>(&'a self) -> impl Iterator<Item=&'a PublicKey> + 'a{
+        self.signatures.iter().map(|&(ref signer, _)| signer)
+    }
+
+    // every signature must be good, not just the ones from keys that are allowed
+    fn check_signatures(&self) -> VerifyResult<()>{
+        if self.checked || all_good(&self.payload[..], &self.signatures[..]){
+            Ok(())
+        }
+        else{
+            Err(VerifyError::BadSignature)
+        }
+    }
+
+    // this one's signatures and its delegations', for check_batch
+    fn jobs(&self, jobs: &mut Vec<Job>){
+        jobs.push((self.payload.clone(), self.signatures.clone()));
+        for cert in self.delegations.iter(){
+            cert.jobs(jobs);
+        }
+    }
+
+    fn mark_checked(&mut self){
+        self.checked = true;
+        for cert in self.delegations.iter_mut(){
+            cert.mark_checked();
+        }
+    }
+
+    // the signed bytes without decoding them, if the signatures are good
+    pub fn open(&self) -> VerifyResult<Vec<u8>>{
+        self.check_signatures()?;
+        Ok(self.payload.clone())
+    }
+
+    // the context (None if signed before envelopes) and payload, whatever it was signed as
+    pub fn open_payload(&self) -> VerifyResult<(Option<String>, Vec<u8>)>{
+        let message = self.open()?;
+        let (context, payload) = open_envelope(&message[..])?;
+        Ok((context.map(String::from), payload.to_vec()))
+    }
+
+    // whether this was signed in an envelope, which everything newly submitted must be.
+    // Only looks, the signature isn't checked.
+    pub fn is_enveloped(&self) -> bool{
+        self.payload.starts_with(SIGNED_MAGIC)
+    }
+
+    // this and every delegation it carries must be enveloped
+    fn check_submitted(&self) -> VerifyResult<()>{
+        if !self.is_enveloped() || self.delegations.iter().any(|cert| !cert.is_enveloped()){
+            return Err(VerifyError::WrongContext);
+        }
+        Ok(())
+    }
+
+    // verify for anything newly received (updates, auth responses), which unlike stored
+    // blocks can't predate envelopes
+    pub fn verify_submitted<T: DeserializeOwned + Context>(&self, allowed: &AllowedKeys) -> VerifyResult<T>{
+        self.check_submitted()?;
+        self.verify(allowed)
+    }
+
+    // verify_scoped for anything newly received, delegations included
+    pub fn verify_submitted_scoped<T: DeserializeOwned + Context>(&self, allowed: &AllowedKeys, at: SystemTime)
+        -> VerifyResult<(T, Option<Scope>)>
+    {
+        self.check_submitted()?;
+        self.verify_scoped(allowed, at)
+    }
+
+    // the key ultimately answerable for this: whoever issued the first delegation,
+    // or user if there are none
+    pub fn root(&self) -> &PublicKey{
+        self.delegations.first().map_or(self.user(), |d| d.user())
+    }
+
+    // like verify, but user may instead hold a chain of Delegations from an allowed key,
+    // each unexpired at the time given and no wider than the one before. Returns the scope
+    // user is limited to, None if user is allowed outright.
+    // verify itself ignores delegations, so only callers that enforce the scope accept them.
+    pub fn verify_scoped<T: DeserializeOwned + Context>(&self, allowed: &AllowedKeys, at: SystemTime)
+        -> VerifyResult<(T, Option<Scope>)>
+    {
+        let mut issuers = allowed.clone();
+        let mut scope: Option<Scope> = None;
+        for cert in self.delegations.iter(){
+            if !cert.delegations.is_empty(){
+                return Err(VerifyError::BadDelegation); // the chain is given flat
+            }
+            let delegation: Delegation = cert.verify(&issuers)?;
+            if delegation.expires.to_system() <= at{
+                return Err(VerifyError::Expired);
+            }
+            if let Some(ref outer) = scope{
+                if !outer.covers(&delegation.scope){
+                    return Err(VerifyError::BadDelegation);
+                }
+            }
+            issuers = HashTrieSet::new().insert(delegation.key);
+            scope = Some(delegation.scope);
+        }
+        // the last delegation must have been to user
+        Ok((self.verify(&issuers)?, scope))
+    }
+
+    // an envelope must be for T's context, but without one the payload is taken as T as
+    // it always was, so that blocks stored before envelopes still verify. Only for reading
+    // what's stored, see verify_submitted.
+    pub fn verify<T: DeserializeOwned + Context>(&self, allowed: &AllowedKeys) -> VerifyResult<T>{
+        self.check(allowed)?;
+        self.peek()
+    }
+
+    // verify without decoding, for what's already been peeked at
+    pub fn check(&self, allowed: &AllowedKeys) -> VerifyResult<()>{
+        if !allowed.contains(self.user()){
             return Err(VerifyError::DisallowedKey);
         }
+        self.check_signatures()
+    }
 
-        let data =
-            crypto_verify(&self.data[..], &self.user)
-                .map_err(|_| VerifyError::BadSignature)?;
+    // like verify, but any of the signers may be allowed rather than just user. Returns
+    // those that are, in the order they signed.
+    pub fn verify_signers<T: DeserializeOwned + Context>(&self, allowed: &AllowedKeys)
+        -> VerifyResult<(T, Vec<PublicKey>)>
+    {
+        let mut signers: Vec<PublicKey> = Vec::new();
+        for signer in self.signers().filter(|signer| allowed.contains(*signer)){
+            if !signers.contains(signer){
+                signers.push(signer.clone());
+            }
+        }
+        if signers.is_empty(){
+            return Err(VerifyError::DisallowedKey);
+        }
+        self.check_signatures()?;
+        Ok((self.peek()?, signers))
+    }
+
+    // decodes the payload as T without checking who signed it or whether the signatures
+    // are good. Only for finding out what else needs checking, see check_batch.
+    pub fn peek<T: DeserializeOwned + Context>(&self) -> VerifyResult<T>{
+        let (context, data) = open_envelope(&self.payload[..])?;
+        if let Some(context) = context{
+            if context != T::context(){
+                return Err(VerifyError::WrongContext);
+            }
+        }
         let result = deserialize::<T>(&data[..]).map_err(|e|{
             error!("Failed to decode: {:?}", e);
 
@@ -74,30 +429,271 @@ impl Signed{
     }
 }
 
+// how many threads check_batch is normally spread across
+pub const BATCH_THREADS: usize = 4;
+
+// a payload and its signatures, copied out of a Signed to be checked on another thread
+type Job = (Vec<u8>, Vec<(PublicKey, Signature)>);
+
+fn all_good(payload: &[u8], signatures: &[(PublicKey, Signature)]) -> bool{
+    signatures.iter().all(|&(ref signer, ref signature)| verify_detached(signature, payload, signer))
+}
+
+fn check_job(job: &Job) -> bool{
+    all_good(&job.0[..], &job.1[..])
+}
+
+fn check_jobs(jobs: Vec<Job>, threads: usize) -> Vec<bool>{
+    let threads = cmp::max(1, cmp::min(threads, jobs.len()));
+    if threads == 1{
+        return jobs.iter().map(check_job).collect();
+    }
+    let per_thread = (jobs.len() + threads - 1) / threads;
+    let total = jobs.len();
+    let jobs = Arc::new(jobs);
+    let handles: Vec<_> = (0..threads)
+        .map(|i|{
+            let start = cmp::min(i * per_thread, total);
+            let end = cmp::min(start + per_thread, total);
+            let jobs = jobs.clone();
+            (end - start, thread::spawn(move || jobs[start..end].iter().map(check_job).collect::<Vec<bool>>()))
+        })
+        .collect();
+    // a thread that panicked vouches for nothing
+    handles.into_iter()
+        .flat_map(|(len, handle)| handle.join().unwrap_or_else(|_| vec![false; len]))
+        .collect()
+}
+
+// checks the signatures of every one of batch, and of their delegations, in parallel
+// across up to threads threads. Each signature still costs one ed25519 verification, as
+// this doesn't batch them into one equation; it only stops them being checked one after
+// another. Each Signed that's good is marked so that verifying it afterwards (however many
+// times) doesn't check it again. Returns whether each was good.
+pub fn check_batch(batch: Vec<&mut Signed>, threads: usize) -> Vec<bool>{
+    let mut jobs = Vec::new();
+    let mut counts = Vec::with_capacity(batch.len());
+    for signed in batch.iter(){
+        let before = jobs.len();
+        signed.jobs(&mut jobs);
+        counts.push(jobs.len() - before);
+    }
+    let mut results = check_jobs(jobs, threads).into_iter();
+    batch.into_iter()
+        .zip(counts)
+        .map(|(signed, count)|{
+            let good = results.by_ref().take(count).fold(true, |all, good| all && good);
+            if good{
+                signed.mark_checked();
+            }
+            good
+        })
+        .collect()
+}
+
+// the passphrase for encrypted keyfiles is taken from here if it's set, otherwise it's
+// asked for on the terminal
+pub const PASSPHRASE_VAR: &'static str = "HTG_KEY_PASSPHRASE";
+
+lazy_static!{
+    // the passphrase that last opened a keyfile, or was asked for at startup, so it isn't
+    // asked for again. One that fails to open a keyfile isn't kept.
+    static ref PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+pub fn prompt(message: &str) -> io::Result<String>{
+    eprint!("{}", message);
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim_right_matches(|c| c == '\r' || c == '\n').to_string())
+}
+
+// the passphrase for encrypted keyfiles, from PASSPHRASE_VAR or PASSPHRASE, or asked for
+// if ask_now. One that's asked for isn't kept, as it hasn't opened anything yet.
+pub fn passphrase(ask_now: bool) -> io::Result<Option<String>>{
+    let kept = PASSPHRASE.lock().unwrap();
+    match env::var(PASSPHRASE_VAR){
+        Ok(passphrase) => Ok(Some(passphrase)),
+        Err(_) if kept.is_some() => Ok(kept.clone()),
+        Err(_) if ask_now => Ok(Some(prompt("Key passphrase: ")?)),
+        Err(_) => Ok(None)
+    }
+}
+
+// asks for the passphrase at startup rather than whenever the first encrypted keyfile is
+// read, keeping it until it fails to open one
+pub fn ask_passphrase() -> io::Result<()>{
+    let mut kept = PASSPHRASE.lock().unwrap();
+    if env::var(PASSPHRASE_VAR).is_err() && kept.is_none(){
+        *kept = Some(prompt("Key passphrase: ")?);
+    }
+    Ok(())
+}
+
+// where the passphrase to open an encrypted keyfile comes from
+#[derive(Copy, Clone, Debug)]
+pub enum Passphrase<'a>{
+    Given(&'a str),
+    Ask,      // as passphrase(true), keeping it in PASSPHRASE only once it has opened the keyfile
+    NotGiven, // encrypted keyfiles can't be opened
+}
+
+impl<'a> Passphrase<'a>{
+    fn open(self, sealed: &SealedSecret) -> io::Result<SecretKey>{
+        match self{
+            Passphrase::Given(passphrase) => sealed.open(passphrase),
+            Passphrase::Ask => {
+                // held while asking, so threads reading keyfiles at once only ask once
+                let mut kept = PASSPHRASE.lock().unwrap();
+                let passphrase = match env::var(PASSPHRASE_VAR){
+                    Ok(passphrase) => passphrase,
+                    Err(_) => match kept.take(){
+                        Some(passphrase) => passphrase,
+                        None => prompt("Key passphrase: ")?
+                    }
+                };
+                let secret = sealed.open(&passphrase)?;
+                *kept = Some(passphrase);
+                Ok(secret)
+            },
+            Passphrase::NotGiven =>
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "Keyfile is encrypted and no passphrase was given"))
+        }
+    }
+}
+
+// a secret key encrypted with secretbox, under a key derived from a passphrase with pwhash
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SealedSecret{
+    salt:       String, // base64
+    nonce:      String, // base64
+    ciphertext: String, // base64
+    opslimit:   usize,
+    memlimit:   usize,
+}
+
+fn sealing_key(passphrase: &str, salt: &pwhash::Salt, opslimit: usize, memlimit: usize)
+    -> io::Result<secretbox::Key>
+{
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    {
+        let secretbox::Key(ref mut bytes) = key;
+        pwhash::derive_key(bytes, passphrase.as_bytes(), salt,
+                           pwhash::OpsLimit(opslimit), pwhash::MemLimit(memlimit))
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to derive key from passphrase"))?;
+    }
+    Ok(key)
+}
+
+impl SealedSecret{
+    pub fn seal(secret: &SecretKey, passphrase: &str) -> io::Result<SealedSecret>{
+        let pwhash::OpsLimit(opslimit) = pwhash::OPSLIMIT_INTERACTIVE;
+        let pwhash::MemLimit(memlimit) = pwhash::MEMLIMIT_INTERACTIVE;
+        let salt = pwhash::gen_salt();
+        let nonce = secretbox::gen_nonce();
+        let key = sealing_key(passphrase, &salt, opslimit, memlimit)?;
+        let &SecretKey(ref secret) = secret;
+        let ciphertext = secretbox::seal(&secret[..], &nonce, &key);
+        Ok(SealedSecret{
+            salt:       base64::encode_config(salt.as_ref(), URL_SAFE_NO_PAD),
+            nonce:      base64::encode_config(nonce.as_ref(), URL_SAFE_NO_PAD),
+            ciphertext: base64::encode_config(&ciphertext[..], URL_SAFE_NO_PAD),
+            opslimit, memlimit
+        })
+    }
+
+    pub fn open(&self, passphrase: &str) -> io::Result<SecretKey>{
+        let invalid = |s: &str| io::Error::new(io::ErrorKind::InvalidData, s.to_string());
+        let decode = |s: &str| base64::decode_config(s, URL_SAFE_NO_PAD)
+            .map_err(|_| invalid("Encrypted secret key isn't base64"));
+        let salt = pwhash::Salt::from_slice(&decode(&self.salt)?[..])
+            .ok_or_else(|| invalid("Bad salt for encrypted secret key"))?;
+        let nonce = secretbox::Nonce::from_slice(&decode(&self.nonce)?[..])
+            .ok_or_else(|| invalid("Bad nonce for encrypted secret key"))?;
+        let key = sealing_key(passphrase, &salt, self.opslimit, self.memlimit)?;
+        let secret = secretbox::open(&decode(&self.ciphertext)?[..], &nonce, &key)
+            .map_err(|_| invalid("Wrong passphrase for encrypted secret key"))?;
+        SecretKey::from_slice(&secret[..])
+            .ok_or_else(|| invalid("Encrypted secret key is the wrong length"))
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct KeyPair{
-    #[serde(with="base64_public")]
     pub public: PublicKey,
-    #[serde(with="base64_secret")]
-    pub secret: SecretKey
+    pub secret: SecretKey,
+    sealed: Option<SealedSecret>, // what's written out instead of secret, if encrypted
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct PlainSecret(#[serde(with="base64_secret")] SecretKey);
+
+// how a KeyPair is written out, with either secret or encrypted. Reading one (on its own or
+// flattened into a Verifier) never needs the passphrase, it's only needed to open it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyFile{
+    #[serde(with="base64_public")]
+    public: PublicKey,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    secret: Option<PlainSecret>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    encrypted: Option<SealedSecret>,
+}
+
+impl Serialize for KeyPair{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>{
+        let file = KeyFile{
+            public:    self.public.clone(),
+            secret:    if self.sealed.is_none() { Some(PlainSecret(self.secret.clone())) } else { None },
+            encrypted: self.sealed.clone()
+        };
+        file.serialize(serializer)
+    }
+}
+
+impl KeyFile{
+    pub fn is_sealed(&self) -> bool{
+        self.encrypted.is_some()
+    }
+
+    // the KeyPair, the passphrase only being used if it's encrypted
+    pub fn open(self, passphrase: Passphrase) -> io::Result<KeyPair>{
+        let invalid = |s: &str| io::Error::new(io::ErrorKind::InvalidData, s.to_string());
+        let (secret, sealed) = match (self.secret, self.encrypted){
+            (Some(PlainSecret(secret)), None) => (secret, None),
+            (None, Some(sealed)) => (passphrase.open(&sealed)?, Some(sealed)),
+            _ => return Err(invalid("KeyPair needs one of secret or encrypted"))
+        };
+        // the second half of an ed25519 secret key is its public key
+        if secret.0[32..] != self.public.0[..]{
+            return Err(invalid("secret key doesn't belong to the public key"));
+        }
+        Ok(KeyPair{ public: self.public, secret, sealed })
+    }
 }
 
 impl KeyPair{
-    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<KeyPair>{
+    pub fn from_file<P: AsRef<Path>>(path: P, passphrase: Passphrase) -> io::Result<KeyPair>{
         use std::io::{Error, ErrorKind};
-        deserialize_readable_file(fs::File::open(path)?).map_err(|e| match e {
+        let file: KeyFile = deserialize_readable_file(fs::File::open(path)?).map_err(|e| match e {
             _ => Error::new(ErrorKind::InvalidData, e)
-        })
+        })?;
+        file.open(passphrase)
     }
-    pub fn from_file_or_new<P: AsRef<Path> + Clone>(path: P) -> KeyPair{
-        Self::from_file(path.clone())
-            .unwrap_or_else(move |e|{
-                error!("Failed to open keypair file {:?} ({:?}), creating new keypair",
-                      path.as_ref(), e);
+    // only a missing file is replaced, one that can't be read (i.e. the wrong passphrase)
+    // mustn't be overwritten
+    pub fn from_file_or_new<P: AsRef<Path> + Clone>(path: P, passphrase: Passphrase) -> KeyPair{
+        match Self::from_file(path.clone(), passphrase){
+            Ok(kp) => kp,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                error!("No keypair file {:?}, creating new keypair", path.as_ref());
                 let kp = KeyPair::generate();
                 kp.to_file(path).unwrap();
                 kp
-            })
+            },
+            Err(e) => panic!("Failed to open keypair file {:?}: {}", path.as_ref(), e)
+        }
     }
 
     pub fn to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()>{
@@ -111,7 +707,20 @@ impl KeyPair{
     
     pub fn generate() -> KeyPair{
         let (public, secret) = gen_keypair();
-        KeyPair{ public, secret }
+        KeyPair{ public, secret, sealed: None }
+    }
+
+    // from now on the secret key is written out encrypted under passphrase
+    pub fn seal(&mut self, passphrase: &str) -> io::Result<()>{
+        self.sealed = Some(SealedSecret::seal(&self.secret, passphrase)?);
+        Ok(())
+    }
+    // from now on the secret key is written out in the clear
+    pub fn unseal(&mut self){
+        self.sealed = None;
+    }
+    pub fn is_sealed(&self) -> bool{
+        self.sealed.is_some()
     }
 }
 
@@ -160,3 +769,153 @@ pub mod base64_secret{
         key
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::ops::{Add, Sub};
+    use std::time::Duration;
+
+    use block::BlockHash;
+    use update::{Update, TestCommand};
+
+    fn scope(patterns: &[&str]) -> Scope{
+        Scope(patterns.iter().map(|pattern| pattern.to_string()).collect())
+    }
+
+    fn delegate(issuer: &KeyPair, key: &KeyPair, patterns: &[&str], expires: SystemTime) -> Signed{
+        Delegation{
+            key:     key.public.clone(),
+            scope:   scope(patterns),
+            expires: SerializableTime::from_system(expires).unwrap()
+        }.issue(issuer).unwrap()
+    }
+
+    fn in_a_minute() -> SystemTime{
+        SystemTime::now().add(Duration::from_secs(60))
+    }
+
+    fn update(keypair: &KeyPair, delegations: Vec<Signed>) -> Signed{
+        let update = TestCommand::Add(1).into_update(BlockHash::of(&[0]));
+        Signed::sign_delegated(update, keypair, delegations).unwrap()
+    }
+
+    fn allowed(keypair: &KeyPair) -> AllowedKeys{
+        HashTrieSet::new().insert(keypair.public.clone())
+    }
+
+    fn verify_scoped(signed: &Signed, allowed: &AllowedKeys, at: SystemTime) -> VerifyResult<Option<Scope>>{
+        signed.verify_scoped::<Update<TestCommand>>(allowed, at).map(|(_, scope)| scope)
+    }
+
+    #[test]
+    fn delegated_key_is_limited_to_its_scope(){
+        let (root, key) = (KeyPair::generate(), KeyPair::generate());
+        let signed = update(&key, vec![delegate(&root, &key, &["tiles/*"], in_a_minute())]);
+        let scope = verify_scoped(&signed, &allowed(&root), SystemTime::now()).unwrap().unwrap();
+        assert!(scope.allows("tiles/a"));
+        assert!(!scope.allows("tiles"));
+        assert!(!scope.allows("other"));
+        // without a delegation the key would have to be allowed itself
+        assert_eq!(update(&key, Vec::new()).verify::<Update<TestCommand>>(&allowed(&root)).err(),
+                   Some(VerifyError::DisallowedKey));
+    }
+
+    #[test]
+    fn expired_delegation_is_refused(){
+        let (root, key) = (KeyPair::generate(), KeyPair::generate());
+        let expires = SystemTime::now().sub(Duration::from_secs(60));
+        let signed = update(&key, vec![delegate(&root, &key, &["*"], expires)]);
+        assert_eq!(verify_scoped(&signed, &allowed(&root), SystemTime::now()), Err(VerifyError::Expired));
+        // it was good before it expired
+        let before = expires.sub(Duration::from_secs(60));
+        assert!(verify_scoped(&signed, &allowed(&root), before).is_ok());
+    }
+
+    #[test]
+    fn delegation_must_be_rooted_in_an_allowed_key(){
+        let (root, stranger, key) = (KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+        let signed = update(&key, vec![delegate(&stranger, &key, &["*"], in_a_minute())]);
+        assert_eq!(verify_scoped(&signed, &allowed(&root), SystemTime::now()), Err(VerifyError::DisallowedKey));
+        // nor may a delegation be for some other key than the one that signed
+        let signed = update(&key, vec![delegate(&root, &stranger, &["*"], in_a_minute())]);
+        assert_eq!(verify_scoped(&signed, &allowed(&root), SystemTime::now()), Err(VerifyError::DisallowedKey));
+    }
+
+    // signed straight over the msgpack, as blocks were before envelopes
+    fn unenveloped<T: Serialize>(t: T, keypair: &KeyPair, delegations: Vec<Signed>) -> Signed{
+        let payload = serialize(&t).unwrap();
+        let mut data = sign_detached(&payload[..], &keypair.secret).0.to_vec();
+        data.extend(payload);
+        Signed::from_combined(keypair.public.clone(), data, delegations).unwrap()
+    }
+
+    #[test]
+    fn submitted_input_must_be_enveloped(){
+        let (root, key) = (KeyPair::generate(), KeyPair::generate());
+        let legacy = unenveloped(TestCommand::Add(1).into_update(BlockHash::of(&[0])), &root, Vec::new());
+        // stored blocks from before envelopes still read
+        assert!(legacy.verify::<Update<TestCommand>>(&allowed(&root)).is_ok());
+        assert_eq!(legacy.verify_submitted::<Update<TestCommand>>(&allowed(&root)).err(),
+                   Some(VerifyError::WrongContext));
+        assert!(update(&root, Vec::new()).verify_submitted::<Update<TestCommand>>(&allowed(&root)).is_ok());
+
+        // nor may any delegation a submitted update carries
+        let cert = unenveloped(Delegation{
+            key:     key.public.clone(),
+            scope:   scope(&["*"]),
+            expires: SerializableTime::from_system(in_a_minute()).unwrap()
+        }, &root, Vec::new());
+        let signed = update(&key, vec![cert]);
+        assert!(verify_scoped(&signed, &allowed(&root), SystemTime::now()).is_ok());
+        assert_eq!(signed.verify_submitted_scoped::<Update<TestCommand>>(&allowed(&root), SystemTime::now()).err(),
+                   Some(VerifyError::WrongContext));
+    }
+
+    fn sealed(passphrase: &str) -> (KeyPair, KeyFile){
+        let mut keypair = KeyPair::generate();
+        keypair.seal(passphrase).unwrap();
+        let data = ::serde_json::to_vec(&keypair).unwrap();
+        (keypair, ::serde_json::from_slice(&data[..]).unwrap())
+    }
+
+    #[test]
+    fn sealed_keyfile_is_read_without_its_passphrase(){
+        let (keypair, file) = sealed("right");
+        assert!(file.is_sealed());
+        assert!(file.clone().open(Passphrase::NotGiven).is_err());
+        assert!(file.clone().open(Passphrase::Given("wrong")).is_err());
+        let opened = file.open(Passphrase::Given("right")).unwrap();
+        assert_eq!(opened.public, keypair.public);
+        assert!(opened.is_sealed());
+    }
+
+    #[test]
+    fn failed_passphrase_is_not_kept(){
+        env::remove_var(PASSPHRASE_VAR);
+        let (_, file) = sealed("right");
+        *PASSPHRASE.lock().unwrap() = Some("wrong".into());
+        assert!(file.clone().open(Passphrase::Ask).is_err());
+        assert_eq!(*PASSPHRASE.lock().unwrap(), None);
+
+        *PASSPHRASE.lock().unwrap() = Some("right".into());
+        file.open(Passphrase::Ask).unwrap();
+        assert_eq!(*PASSPHRASE.lock().unwrap(), Some("right".to_string()));
+    }
+
+    #[test]
+    fn nested_delegation_may_not_widen_its_scope(){
+        let (root, middle, key) = (KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+        let outer = || delegate(&root, &middle, &["tiles/*"], in_a_minute());
+
+        let widened = update(&key, vec![outer(), delegate(&middle, &key, &["*"], in_a_minute())]);
+        assert_eq!(verify_scoped(&widened, &allowed(&root), SystemTime::now()), Err(VerifyError::BadDelegation));
+        let beside = update(&key, vec![outer(), delegate(&middle, &key, &["other/*"], in_a_minute())]);
+        assert_eq!(verify_scoped(&beside, &allowed(&root), SystemTime::now()), Err(VerifyError::BadDelegation));
+
+        let narrowed = update(&key, vec![outer(), delegate(&middle, &key, &["tiles/a/*"], in_a_minute())]);
+        let scope = verify_scoped(&narrowed, &allowed(&root), SystemTime::now()).unwrap().unwrap();
+        assert!(scope.allows("tiles/a/b"));
+        assert!(!scope.allows("tiles/b"));
+    }
+}
@@ -5,9 +5,21 @@ use std::fmt::{self, Debug};
 
 use block::BlockHash;
 use ltime::SerializableTime;
+use gc::References;
+use signed::{Scope, Context};
+use replay::{Nonce, new_nonce};
 
-pub trait Command<T: Sized + Serialize>: Serialize{
+pub trait Command<T: Sized + Serialize>: Serialize + Context{
     fn process(self, input: T) -> Result<T, ()>;
+    // whether a key delegated only scope may submit this
+    fn within(&self, _scope: &Scope) -> bool{
+        false
+    }
+    // whether applying this after other gives the same state as applying it before, so an
+    // update made without seeing other can be rebased over it (see Verifier::rebase)
+    fn commutes_with(&self, _other: &Self) -> bool{
+        false
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,12 +27,21 @@ pub struct Update<T>{
   pub timestamp: SerializableTime,
   pub command: T,
   pub last:    BlockHash,
+  // never used twice by the same key, see replay. Only updates stored before nonces lack one.
+  #[serde(default, skip_serializing_if="Option::is_none")]
+  pub nonce:   Option<Nonce>,
+}
+
+impl<T: Context> Context for Update<T>{
+    fn context() -> String{
+        format!("Update<{}>", T::context())
+    }
 }
 
 // XXX rename this
 // XXX should cache unserialized blocks so that HashTrieMap can share memory
 /// Maps String names to a BlockHash, maintaining a persistant log like any other Verifier<Command<T>>
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Default, PartialEq)]
 pub struct NamedHash(pub HashTrieMap<String, BlockHash>);
 
 // print a little bit prettier so it can actually be read
@@ -40,6 +61,12 @@ pub enum NamedHashCommand{
     Set(String, BlockHash),
 }
 
+impl Context for NamedHashCommand{
+    fn context() -> String{
+        "NamedHashCommand".into()
+    }
+}
+
 impl Command<NamedHash> for NamedHashCommand{
     fn process(self, old: NamedHash) -> Result<NamedHash, ()>{
         match self{
@@ -48,11 +75,36 @@ impl Command<NamedHash> for NamedHashCommand{
             },
         }
     }
+    fn within(&self, scope: &Scope) -> bool{
+        match *self{
+            NamedHashCommand::Set(ref id, _) => scope.allows(id)
+        }
+    }
+    // setting different names
+    fn commutes_with(&self, other: &NamedHashCommand) -> bool{
+        match (self, other){
+            (&NamedHashCommand::Set(ref id, _), &NamedHashCommand::Set(ref other_id, _)) => id != other_id
+        }
+    }
+}
+
+impl References for NamedHash{
+    fn references(&self) -> Vec<BlockHash>{
+        self.0.values().cloned().collect()
+    }
+}
+
+impl References for NamedHashCommand{
+    fn references(&self) -> Vec<BlockHash>{
+        match *self{
+            NamedHashCommand::Set(_, ref hash) => vec![hash.clone()]
+        }
+    }
 }
 
 
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize, Default)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
 pub struct TestObject(u64);
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -66,10 +118,29 @@ impl TestCommand{
             timestamp: SerializableTime::from_system_now().unwrap(),
             command: self,
             last,
+            nonce: Some(new_nonce()),
         }
     }
 }
 
+impl References for TestObject{
+    fn references(&self) -> Vec<BlockHash>{
+        Vec::new()
+    }
+}
+
+impl References for TestCommand{
+    fn references(&self) -> Vec<BlockHash>{
+        Vec::new()
+    }
+}
+
+impl Context for TestCommand{
+    fn context() -> String{
+        "TestCommand".into()
+    }
+}
+
 impl Command<TestObject> for TestCommand{
     fn process(self, input: TestObject) -> Result<TestObject, ()>{
         match self{
@@ -6,26 +6,143 @@ use serde_json::{to_writer as serialize_readable_file, from_reader as deserializ
 use rpds::{HashTrieSet, HashTrieMap};
 
 use update::{Update, Command};
-use signed::{Signed, VerifyError, AllowedKeys, KeyPair};
-use block::{BlockHash, BlockStore};
-use ltime::{now_check_stale};
+use signed::{Signed, VerifyError, AllowedKeys, KeyPair, KeyFile, Passphrase, Context, check_batch, BATCH_THREADS};
+use block::{BlockHash, BlockStore, hung_up};
+use ltime::SerializableTime;
+use journal::{Journal, SyncMode};
+use acl::{Acl, AclCommand, Role};
+use replay::{Freshness, SeenNonces, Nonce};
+use checkpoint::{self, Checkpoint};
 
 use std::sync::Arc;
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::fmt::Debug;
 use std::io;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::collections::HashMap;
 //use std::marker::PhantomData;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VerifiedData<T: Debug + Serialize>{
     pub value: T,
     pub update: Option<Signed>, // None if root
+    // a Signed KeyRotation if this is the first state under a new key
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub rotation: Option<Signed>,
+    // the Acl in force from this state on, which the root records too (None in states
+    // stored before it did)
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub acl: Option<BlockHash>,
+    // a Signed Update<AclCommand> if this state changed the Acl rather than the value
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub acl_update: Option<Signed>,
+    // the other signatures over update's payload that a Threshold needed but that couldn't be
+    // merged into update's own, being from keys acting under delegations
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub cosigners: Vec<Signed>,
+    // set if update was replayed on top of a newer state than its last, see Verifier::rebase
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub rebase: Option<Rebase>,
+    // the newest Checkpoint (of some state before this one) when this state was made
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub checkpoint: Option<BlockHash>,
 }
 
-#[derive(Debug, Copy, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rebase{
+    pub original: BlockHash, // the last the update was made against
+    pub parent:   BlockHash, // the state it was applied to instead
+}
+
+// issued by a Verifier's old key for its new one. The new key's first state carries it,
+// so the chain can still be followed (and trusted) back past the change of key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyRotation{
+    pub timestamp: SerializableTime,
+    pub new_key:   PublicKey,
+    pub last:      BlockHash, // the final state signed by the old key
+}
+
+impl<T: Debug + Serialize> Context for VerifiedData<T>{
+    fn context() -> String{
+        "VerifiedData".into()
+    }
+}
+
+impl Context for KeyRotation{
+    fn context() -> String{
+        "KeyRotation".into()
+    }
+}
+
+impl<T: Debug + Serialize> VerifiedData<T>{
+    // the old key's final state and the old key, if this is the first state under a new
+    // key. signer is whoever signed this VerifiedData.
+    pub fn rotated_from(&self, signer: &PublicKey)
+        -> Result<Option<(BlockHash, PublicKey)>, VerifierError>
+    {
+        let rotation = match self.rotation{
+            Some(ref rotation) => rotation,
+            None => return Ok(None)
+        };
+        let old_key = rotation.user().clone();
+        let rotation: KeyRotation = rotation.verify(&HashTrieSet::new().insert(old_key.clone()))?;
+        if rotation.new_key != *signer{
+            return Err(VerifierError::DisallowedKey);
+        }
+        Ok(Some((rotation.last, old_key)))
+    }
+
+    // the state before this one, given the last its update was made against
+    pub fn update_parent(&self, last: BlockHash) -> BlockHash{
+        self.rebase.as_ref().map_or(last, |rebase| rebase.parent.clone())
+    }
+
+    // the state before this one, found by peeking at whatever links to it. C is what the
+    // chain's updates are made of.
+    pub fn previous_state<C: Command<T>>(&self) -> Result<Option<BlockHash>, VerifierError>
+        where for <'de> C: Deserialize<'de>
+    {
+        // in the order verify_history follows them
+        Ok(if let Some(ref rotation) = self.rotation{
+            Some(rotation.peek::<KeyRotation>()?.last)
+        }
+        else if let Some(ref acl_update) = self.acl_update{
+            Some(acl_update.peek::<Update<AclCommand>>()?.last)
+        }
+        else if let Some(ref update) = self.update{
+            Some(self.update_parent(update.peek::<Update<C>>()?.last))
+        }
+        else{
+            None
+        })
+    }
+
+    // every Signed this carries, i.e. to check them with check_batch
+    fn signed_mut(&mut self) -> Vec<&mut Signed>{
+        self.update.iter_mut()
+            .chain(self.rotation.iter_mut())
+            .chain(self.acl_update.iter_mut())
+            .chain(self.cosigners.iter_mut())
+            .collect()
+    }
+
+    // who changed the Acl and how, if this state is an ACL change
+    pub fn acl_changed(&self) -> Result<Option<(PublicKey, Update<AclCommand>)>, VerifierError>{
+        let acl_update = match self.acl_update{
+            Some(ref acl_update) => acl_update,
+            None => return Ok(None)
+        };
+        let user = acl_update.user().clone();
+        let update = acl_update.verify(&HashTrieSet::new().insert(user.clone()))?;
+        Ok(Some((user, update)))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
 #[serde(tag="Error")]
 pub enum VerifierError{
     DisallowedKey,
@@ -37,6 +154,12 @@ pub enum VerifierError{
     UpdateErr,  // error processing update
     StoreErr,   // error storing update
     NoVerifier, // used by VerifierMap to indicate there was no verifier by the given name
+    BadDelegation, // delegation chain malformed or widens its scope
+    Expired,       // a delegation in the chain had expired
+    OutOfScope,    // a delegated key's command is outside its scope
+    Pending,       // signature recorded, the Threshold needs more before the update is made
+    Replayed,      // no nonce, or one already used by the key (replay protection)
+    WrongContext,  // signed as something other than an update, or not in an envelope
 }
 
 impl From<VerifyError> for VerifierError{
@@ -44,33 +167,118 @@ impl From<VerifyError> for VerifierError{
         match v{
             VerifyError::DisallowedKey => VerifierError::DisallowedKey,
             VerifyError::BadSignature => VerifierError::BadSignature,
-            VerifyError::DecodeFailed => VerifierError::DecodeFailed
+            VerifyError::DecodeFailed => VerifierError::DecodeFailed,
+            VerifyError::BadDelegation => VerifierError::BadDelegation,
+            VerifyError::Expired => VerifierError::Expired,
+            VerifyError::WrongContext => VerifierError::WrongContext
         }
     }
 }
 
 //pub type VerifierResult = Result<BlockHash, VerifierError>;
 
-#[derive(Debug, Serialize, Deserialize)]
+// how many states an update may be rebased across
+const MAX_REBASE: usize = 64;
+
+// an M-of-N policy: an update is only made once `required` distinct keys the Acl allows
+// (of however many it allows) have signed the same payload
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Threshold{
+    pub required: usize,
+    pub window:   u64, // seconds a proposal may wait for the rest of its signatures
+}
+
+// signatures over one payload waiting for their Threshold. Only kept in memory, so a
+// restart means signing again.
+#[derive(Debug)]
+struct Proposal{
+    first_seen: SystemTime,
+    signatures: Vec<(PublicKey, Signed)>, // (root of the signer, signature)
+}
+
+// read as a VerifierFile, see from_reader
+#[derive(Debug, Serialize)]
 pub struct Verifier{
     #[serde(flatten)]
     pub keypair: KeyPair,
-    pub allowed: AllowedKeys,
+    pub allowed: AllowedKeys, // the Admins in the Acl of the root force makes
     pub latest:  Rc<RefCell<Option<BlockHash>>>,
+    pub sync:    SyncMode, // how every new latest is flushed to the journal
+    pub threshold: Option<Threshold>, // None if one signature is enough
+    // replay updates made against an older state on top of latest, if their commands
+    // commute with every one since
+    pub rebase:  bool,
+    pub freshness: Freshness,
+    // states between checkpoints, None for checkpoint::DEFAULT_EVERY and 0 for none at all
+    pub checkpoint_every: Option<u64>,
+    #[serde(skip)]
+    journal:     Option<Rc<RefCell<Journal>>>,
+    #[serde(skip)]
+    pending:     Rc<RefCell<HashMap<BlockHash, Proposal>>>, // by hash of the payload
+    #[serde(skip)]
+    seen:        Rc<RefCell<SeenNonces>>,
+    // only counted since the Verifier was loaded, so a restart can put off a checkpoint
+    #[serde(skip)]
+    since_checkpoint: Rc<Cell<u64>>,
+    // a Checkpoint of the state it's paired with, for the next state on top of that one
+    // to refer to. Lost on restart, leaving the Checkpoint for gc.
+    #[serde(skip)]
+    new_checkpoint: Rc<RefCell<Option<(BlockHash, BlockHash)>>>,
+}
+
+// a Verifier as it's written out, before its KeyPair has been opened
+#[derive(Deserialize)]
+struct VerifierFile{
+    #[serde(flatten)]
+    keypair: KeyFile,
+    allowed: AllowedKeys,
+    latest:  Option<BlockHash>,
+    #[serde(default)]
+    sync:    SyncMode,
+    #[serde(default)]
+    threshold: Option<Threshold>,
+    #[serde(default)]
+    rebase:  bool,
+    #[serde(default)]
+    freshness: Freshness,
+    #[serde(default)]
+    checkpoint_every: Option<u64>,
+}
+
+// journal a new latest before making it visible, so latest is never ahead of what
+// would be recovered after a crash
+fn commit_latest(latest: &Rc<RefCell<Option<BlockHash>>>,
+                 journal: &Option<Rc<RefCell<Journal>>>,
+                 hash: BlockHash)
+    -> io::Result<()>
+{
+    if let Some(ref journal) = *journal{
+        journal.borrow_mut().append(&hash)?;
+    }
+    latest.replace(Some(hash));
+    Ok(())
 }
 
 impl Verifier{
-    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Verifier>{
-        Self::from_reader(fs::File::open(path)?)
+    pub fn from_file<P: AsRef<Path>>(path: P, passphrase: Passphrase) -> io::Result<Verifier>{
+        Self::from_reader(fs::File::open(path)?, passphrase)
     }
     pub fn to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()>{
-        self.to_writer(&mut fs::File::create(path)?)
+        ::write_then_rename(path, |wtr| self.to_writer(wtr))
     }
     
-    pub fn from_reader<R: io::Read>(rdr: R) -> io::Result<Verifier>{
-        deserialize_readable_file(rdr).map_err(|e| match e {
+    // the passphrase is only used if the KeyPair is encrypted
+    pub fn from_reader<R: io::Read>(rdr: R, passphrase: Passphrase) -> io::Result<Verifier>{
+        let file: VerifierFile = deserialize_readable_file(rdr).map_err(|e| match e {
             _ => io::Error::new(io::ErrorKind::InvalidData, e)
-        })
+        })?;
+        let mut verifier = Verifier::new(Some(file.keypair.open(passphrase)?), Some(file.allowed), file.latest);
+        verifier.sync = file.sync;
+        verifier.threshold = file.threshold;
+        verifier.rebase = file.rebase;
+        verifier.freshness = file.freshness;
+        verifier.checkpoint_every = file.checkpoint_every;
+        Ok(verifier)
     }
     pub fn to_writer<W: io::Write>(&self, wtr: W) -> io::Result<()>{
         serialize_readable_file(wtr, &self).map_err(|e| match e{
@@ -104,7 +312,35 @@ impl Verifier{
 
         Verifier{
             keypair, allowed,
-            latest: Rc::new(RefCell::new(with_latest))
+            latest: Rc::new(RefCell::new(with_latest)),
+            sync: SyncMode::default(),
+            threshold: None,
+            rebase: false,
+            freshness: Freshness::default(),
+            checkpoint_every: None,
+            journal: None,
+            pending: Rc::default(),
+            seen: Rc::default(),
+            since_checkpoint: Rc::default(),
+            new_checkpoint: Rc::default()
+        }
+    }
+
+    // replays the journal at path over latest and journals all future updates there
+    pub fn attach_journal<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()>{
+        let (journal, replayed) = Journal::open(path, self.sync)?;
+        if let Some(hash) = replayed{
+            debug!("Journal replayed latest {:?} (was {:?})", hash, *self.latest.borrow());
+            self.latest.replace(Some(hash));
+        }
+        self.journal = Some(Rc::new(RefCell::new(journal)));
+        Ok(())
+    }
+
+    pub fn set_sync(&mut self, sync: SyncMode){
+        self.sync = sync;
+        if let Some(ref journal) = self.journal{
+            journal.borrow_mut().set_sync(sync);
         }
     }
 
@@ -113,69 +349,391 @@ impl Verifier{
         self.allowed.insert_mut(key);
     }
 
-    pub fn force<T: Serialize + Debug>(&self, store: &BlockStore, input: T) -> BlockHash{ // blocking, panicing, replaces latest
-        let hash_result = store_verified(store, input, &self.keypair);
+    // blocking, replaces latest
+    pub fn force<T: Serialize + Debug>(&self, store: &BlockStore, input: T) -> io::Result<BlockHash>{
+        let hash_result = store_verified(store, input, &self.keypair, &self.allowed);
         trace!("force result {:?}", hash_result);
 
-        let hash = hash_result.unwrap();
-        *self.latest.borrow_mut() = Some(hash.clone());
+        let hash = hash_result?;
+        commit_latest(&self.latest, &self.journal, hash.clone())?;
+
+        Ok(hash)
+    }
+
+    // blocking. Hands the chain over to new_keypair: the current key signs a KeyRotation
+    // for it, then the new key signs a copy of the latest value carrying the rotation.
+    // Returns the Verifier to replace this one with, which must reach disk in one go
+    // (see VerifierMap::rotate_key).
+    pub fn rotate<T: Serialize + Debug>(&self, store: &BlockStore, new_keypair: KeyPair)
+        -> io::Result<Verifier>
+        where for <'de> T: Deserialize<'de>
+    {
+        let invalid = |s: String| io::Error::new(io::ErrorKind::InvalidData, s);
+
+        let last = self.latest.borrow().clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Verifier has no latest to hand over"))?;
+        let last_block = store.get(last.clone()).wait().map_err(hung_up)??;
+        let last_signed: Signed = deserialize(&last_block[..])
+            .map_err(|e| invalid(format!("latest {:?} is not a Signed: {:?}", last, e)))?;
+        let allow_self = HashTrieSet::new().insert(self.keypair.public.clone());
+        let last_verified: VerifiedData<T> = last_signed.verify(&allow_self)
+            .map_err(|e| invalid(format!("latest {:?} doesn't verify: {:?}", last, e)))?;
 
-        hash
+        let timestamp = SerializableTime::from_system_now()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let checkpoint = self.checkpoint_for(&last, last_verified.checkpoint);
+        let rotation = KeyRotation{
+            timestamp,
+            new_key: new_keypair.public.clone(),
+            last
+        };
+        let sign_failed = |_| io::Error::new(io::ErrorKind::Other, "Failed to sign data for storage");
+        let rotation = Signed::sign(rotation, &self.keypair).map_err(sign_failed)?;
+        let verified = VerifiedData{
+            value: last_verified.value,
+            update: None,
+            rotation: Some(rotation),
+            acl: last_verified.acl,
+            acl_update: None,
+            cosigners: Vec::new(),
+            rebase: None,
+            checkpoint
+        };
+        let signed_verified = Signed::sign(verified, &new_keypair).map_err(sign_failed)?;
+        let data = serialize(&signed_verified)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let hash = store.set(Arc::new(data)).wait().map_err(hung_up)??;
+
+        Ok(Verifier{
+            keypair: new_keypair,
+            allowed: self.allowed.clone(),
+            latest:  Rc::new(RefCell::new(Some(hash))),
+            sync:    self.sync,
+            threshold: self.threshold,
+            rebase:  self.rebase,
+            freshness: self.freshness,
+            checkpoint_every: self.checkpoint_every,
+            journal: self.journal.clone(),
+            pending: Rc::default(),
+            seen:    self.seen.clone(),
+            since_checkpoint: self.since_checkpoint.clone(),
+            new_checkpoint: Rc::default() // the rotation took it
+        })
     }
    
 
+    // the update (or the root of its delegations) is checked against the Acl as of latest,
+    // see append
     pub fn verify<T: Serialize + Debug, U: Command<T>>(&self, store: &BlockStore, input: Signed)
         -> impl IntoFuture<Item=BlockHash, Error=VerifierError>
         where for <'de> U: Deserialize<'de>,
               for <'de> T: Deserialize<'de>
     {
-        const STALE_SECONDS: u64 = 5; // if timestamp is more than this many seconds old, update is stale
-
-        let update: Update<U> = input
-            .verify(&self.allowed)
+        // a delegated key acts for whoever issued its first delegation
+        let root = input.root().clone();
+        let (update, scope): (Update<U>, _) = input
+            .verify_submitted_scoped(&HashTrieSet::new().insert(root.clone()), SystemTime::now())
             .map_err(|e| -> VerifierError {e.into()})?;
+        // cosigners sign a payload some time after it was proposed
+        let past = self.threshold.map_or(self.freshness.past, |t| t.window);
+        let signers: Vec<PublicKey> = Some(root.clone()).into_iter()
+            .chain(input.signers().skip(1).cloned())
+            .collect();
+        let rebase = match self.check_fresh(&update, &signers, past){
+            Err(VerifierError::NotLatest) if self.rebase => Some(self.rebase_onto::<T, U>(store, &update)?),
+            result => {
+                result?;
+                None
+            }
+        };
+        if let Some(ref scope) = scope{
+            if !update.command.within(scope){
+                return Err(VerifierError::OutOfScope);
+            }
+        }
+
+        let (input, cosigners) = match self.threshold{
+            Some(threshold) => self.propose::<T>(store, input, root, threshold)?,
+            None => (input, Vec::new())
+        };
+        let roots: Vec<PublicKey> = Some(input.root().clone()).into_iter()
+            .chain(input.signers().skip(1).cloned())
+            .chain(cosigners.iter().map(|c| c.root().clone()))
+            .collect();
+
+        let parent = rebase.as_ref().map_or(update.last, |rebase| rebase.parent.clone());
+        let (timestamp, nonce) = (update.timestamp, update.nonce);
+        let command = update.command;
+        let keys = roots.clone();
+        let hash = self.append::<T, _>(store, parent, move |last, acl|{
+            if !roots.iter().all(|root| acl.allows(root, Role::Writer)){
+                return Err(VerifierError::DisallowedKey);
+            }
+            let next:_ = command
+                .process(last.value)
+                .map_err(|_| VerifierError::UpdateErr)?;
+
+            Ok(VerifiedData{
+                value: next,
+                update: Some(input),
+                rotation: None,
+                acl: last.acl,
+                acl_update: None,
+                cosigners,
+                rebase,
+                checkpoint: None, // see append
+            })
+        })?;
+        self.remember(&keys, nonce, &timestamp, past);
+        self.checkpoint_if_due::<T, U>(store, &hash);
+        Ok(hash)
+    }
+
+    // blocking. Checks that update can be replayed on top of latest: every state since its
+    // last must be an ordinary update whose command it commutes with, going back no more
+    // than MAX_REBASE states
+    fn rebase_onto<T: Serialize + Debug, U: Command<T>>(&self, store: &BlockStore, update: &Update<U>)
+        -> Result<Rebase, VerifierError>
+        where for <'de> U: Deserialize<'de>,
+              for <'de> T: Deserialize<'de>
+    {
+        let latest = self.latest.borrow().clone().ok_or(VerifierError::NotLatest)?;
+        let mut hash = latest.clone();
+        for _ in 0..MAX_REBASE{
+            let state = self.load_own::<T>(store, &hash)?;
+            let newer = match state.update{
+                Some(ref newer) if state.rotation.is_none() && state.acl_update.is_none() => newer,
+                _ => return Err(VerifierError::NotLatest) // not something to commute with
+            };
+            // it was verified when it was made
+            let newer: Update<U> = newer.peek()?;
+            if !update.command.commutes_with(&newer.command){
+                return Err(VerifierError::NotLatest);
+            }
+            hash = state.update_parent(newer.last);
+            if hash == update.last{
+                return Ok(Rebase{
+                    original: update.last.clone(),
+                    parent:   latest
+                });
+            }
+        }
+        Err(VerifierError::NotLatest)
+    }
+
+    // records input's signature over its payload, and once threshold has enough distinct
+    // signers takes them all back out: the first to sign, with the rest merged into its
+    // signatures where they can be and as cosigners where they can't
+    fn propose<T: Serialize + Debug>(&self, store: &BlockStore, input: Signed, root: PublicKey,
+                                     threshold: Threshold)
+        -> Result<(Signed, Vec<Signed>), VerifierError>
+        where for <'de> T: Deserialize<'de>
+    {
+        // otherwise anyone could fill the pending area
+        if !self.acl::<T>(store)?.allows(&root, Role::Writer){
+            return Err(VerifierError::DisallowedKey);
+        }
 
+        let id = BlockHash::of(&input.open()?[..]);
+        let now = SystemTime::now();
+        let window = Duration::from_secs(threshold.window);
+        let mut pending = self.pending.borrow_mut();
+        pending.retain(|_, proposal|
+            now.duration_since(proposal.first_seen).map(|age| age < window).unwrap_or(true));
         {
-            if let Some(ref latest) = *self.latest.borrow(){
-                if update.last != *latest{
-                    return Err(VerifierError::NotLatest);
-                }
+            let proposal = pending.entry(id.clone()).or_insert_with(|| Proposal{
+                first_seen: now,
+                signatures: Vec::new()
+            });
+            if !proposal.signatures.iter().any(|&(ref signer, _)| *signer == root){
+                proposal.signatures.push((root, input));
+            }
+            if proposal.signatures.len() < threshold.required{
+                return Err(VerifierError::Pending);
+            }
+        }
+
+        let mut signatures = pending.remove(&id).unwrap().signatures.into_iter()
+            .map(|(_, signature)| signature);
+        let mut first = signatures.next().unwrap(); // there's at least one, it was just added
+        let cosigners = signatures
+            .filter_map(|signature| first.merge(signature).err())
+            .collect();
+        Ok((first, cosigners))
+    }
+
+    // applies a Signed Update<AclCommand>, which only an Admin may submit. The new state
+    // keeps the value as it was and refers to the changed Acl.
+    pub fn change_acl<T: Serialize + Debug>(&self, store: &BlockStore, input: Signed)
+        -> Result<BlockHash, VerifierError>
+        where for <'de> T: Deserialize<'de>
+    {
+        let update: Update<AclCommand> = input
+            .verify_submitted(&HashTrieSet::new().insert(input.user().clone()))?;
+        let past = self.freshness.past;
+        self.check_fresh(&update, &[input.user().clone()], past)?;
+
+        let user = input.user().clone();
+        let (timestamp, nonce) = (update.timestamp, update.nonce);
+        let command = update.command;
+        // the next update makes any checkpoint that's due, as only it knows the chain's commands
+        let hash = self.append::<T, _>(store, update.last, move |last, acl|{
+            if !acl.allows(&user, Role::Admin){
+                return Err(VerifierError::DisallowedKey);
             }
+            let acl = command
+                .process(acl)
+                .map_err(|_| VerifierError::UpdateErr)?
+                .store(store)
+                .map_err(|_| VerifierError::StoreErr)?;
+
+            Ok(VerifiedData{
+                value: last.value,
+                update: None,
+                rotation: None,
+                acl: Some(acl),
+                acl_update: Some(input),
+                cosigners: Vec::new(),
+                rebase: None,
+                checkpoint: None, // see append
+            })
+        })?;
+        self.remember(&[user], nonce, &timestamp, past);
+        Ok(hash)
+    }
+
+    // blocking. The Acl as of latest
+    pub fn acl<T: Serialize + Debug>(&self, store: &BlockStore) -> Result<Acl, VerifierError>
+        where for <'de> T: Deserialize<'de>
+    {
+        let latest = match *self.latest.borrow(){
+            Some(ref latest) => latest.clone(),
+            None => return Ok(Acl::from_allowed(&self.allowed))
+        };
+        let last = self.load_own::<T>(store, &latest)?;
+        self.acl_of(store, &last)
+    }
+
+    // every state has one, from the root on (see store_verified)
+    fn acl_of<T: Serialize + Debug>(&self, store: &BlockStore, verified: &VerifiedData<T>)
+        -> Result<Acl, VerifierError>
+    {
+        match verified.acl{
+            Some(ref acl) => Acl::load(store, acl).map_err(|_| VerifierError::LastErr),
+            None => Err(VerifierError::LastErr)
         }
-        
-        let timestamp = update.timestamp.to_system();
-        if now_check_stale(timestamp, STALE_SECONDS){
+    }
+
+    // blocking, a VerifiedData that this Verifier signed
+    fn load_own<T: Serialize + Debug>(&self, store: &BlockStore, hash: &BlockHash)
+        -> Result<VerifiedData<T>, VerifierError>
+        where for <'de> T: Deserialize<'de>
+    {
+        let block = store.get(hash.clone()).wait()
+            .map_err(|_| VerifierError::LastErr)? // Oneshot::Cancelled
+            .map_err(|_| VerifierError::LastErr)?; // io::Error
+        let signed: Signed = deserialize(block.as_slice())
+            .map_err(|_| VerifierError::LastErr)?;
+
+        // only the validator itself should be signing VerifiedData,
+        // therefore only our key should be valid
+        let allow_self = HashTrieSet::new()
+            .insert(self.keypair.public.clone());
+        signed
+            .verify(&allow_self)
+            .map_err(|_| VerifierError::LastErr)
+    }
+
+    // update must be timestamped within the Freshness window (with past seconds allowed
+    // behind), carry a nonce that none of keys has used in an update still remembered, and
+    // have been made against latest
+    fn check_fresh<C>(&self, update: &Update<C>, keys: &[PublicKey], past: u64)
+        -> Result<(), VerifierError>
+    {
+        // checked first, so that NotLatest means an update is otherwise fit to rebase
+        if !self.freshness.allows(&update.timestamp, past){
             return Err(VerifierError::Stale);
         }
+        let nonce = update.nonce.ok_or(VerifierError::Replayed)?;
+        {
+            let seen = self.seen.borrow();
+            if !keys.iter().all(|key| seen.is_new(key, &nonce, &update.timestamp, past)){
+                return Err(VerifierError::Replayed);
+            }
+        }
+
+        if let Some(ref latest) = *self.latest.borrow(){
+            if update.last != *latest{
+                return Err(VerifierError::NotLatest);
+            }
+        }
+        Ok(())
+    }
+
+    // once an update with nonce has been applied, so that keys can't have it applied again
+    fn remember(&self, keys: &[PublicKey], nonce: Option<Nonce>, timestamp: &SerializableTime, past: u64){
+        let nonce = match nonce{
+            Some(nonce) => nonce,
+            None => return // check_fresh refused it
+        };
+        let mut seen = self.seen.borrow_mut();
+        let mut recorded: Vec<&PublicKey> = Vec::new();
+        for key in keys.iter(){
+            if !recorded.contains(&key){
+                seen.record(key.clone(), nonce, timestamp, &self.freshness, past);
+                recorded.push(key);
+            }
+        }
+    }
+
+    // blocking. Makes a Checkpoint of state, which has just become the latest, if enough
+    // states have been made since the last one. Only ever after an update has been accepted,
+    // so nobody can make the Verifier do this work without being allowed to. Failing to make
+    // one isn't the update's fault, so it's only logged.
+    fn checkpoint_if_due<T: Serialize + Debug, C: Command<T>>(&self, store: &BlockStore, state: &BlockHash)
+        where for <'de> T: Deserialize<'de>,
+              for <'de> C: Deserialize<'de>
+    {
+        let every = self.checkpoint_every.unwrap_or(checkpoint::DEFAULT_EVERY);
+        if every == 0 || self.since_checkpoint.get() < every{
+            return;
+        }
+        self.since_checkpoint.set(0); // if it fails, try again an interval later
+        match checkpoint::emit::<T, C>(store, state, &self.keypair){
+            Ok(hash) => {
+                self.new_checkpoint.replace(Some((state.clone(), hash)));
+            },
+            Err(e) => error!("Failed to make a checkpoint of {:?}: {:?}", state, e)
+        }
+    }
+
+    // what a state made on top of last refers to: a Checkpoint of last if one was just made,
+    // otherwise the same as last (whose own is stored)
+    fn checkpoint_for(&self, last: &BlockHash, stored: Option<BlockHash>) -> Option<BlockHash>{
+        match *self.new_checkpoint.borrow(){
+            Some((ref state, ref checkpoint)) if state == last => Some(checkpoint.clone()),
+            _ => stored
+        }
+    }
 
+    // signs and stores whatever next makes of the last state and the Acl in force,
+    // then makes it the latest if last still is. See checkpoint_for for the Checkpoint the
+    // new state refers to.
+    fn append<T, F>(&self, store: &BlockStore, last: BlockHash, next: F)
+        -> Result<BlockHash, VerifierError>
+        where T: Serialize + Debug,
+              F: FnOnce(VerifiedData<T>, Acl) -> Result<VerifiedData<T>, VerifierError>,
+              for <'de> T: Deserialize<'de>
+    {
         let latest = self.latest.clone(); // kept until end
-        let command = update.command;
-        let last = update.last;
-        let last_block_future = store.get(last.clone())
-            .map_err(|_| VerifierError::LastErr); // Oneshot::Cancelled
-        let sign_future = last_block_future
-            .and_then(move |last_block| -> Result<Arc<Vec<u8>>, VerifierError> {
-                let last_block = last_block.map_err(|_| VerifierError::LastErr)?; // io::Error
-                let last_signed: Signed = deserialize(last_block.as_slice())
-                    .map_err(|_| VerifierError::LastErr)?;
-
-                // only the validator itself should be signing VerifiedData,
-                // therefore only our key should be valid
-                let allow_self = HashTrieSet::new()
-                    .insert(self.keypair.public.clone());
-
-                let last: VerifiedData<_> = last_signed
-                    .verify(&allow_self)
-                    .map_err(|_| VerifierError::LastErr)?;
-
-                let next:_ = command
-                    .process(last.value)
-                    .map_err(|_| VerifierError::UpdateErr)?;
-
-                let verified = VerifiedData{
-                    value: next,
-                    update: Some(input)
-                };
+        let journal = self.journal.clone();
+        let sign_future = future::lazy(|| -> Result<Arc<Vec<u8>>, VerifierError> {
+                let last_verified = self.load_own::<T>(store, &last)?;
+                let acl = self.acl_of(store, &last_verified)?;
+                let checkpoint = self.checkpoint_for(&last, last_verified.checkpoint.clone());
+                let mut verified = next(last_verified, acl)?;
+                verified.checkpoint = checkpoint;
 
                 let signed_verified = Signed::sign(verified, &self.keypair)
                     .map_err(|_| VerifierError::StoreErr)?;
@@ -193,12 +751,20 @@ impl Verifier{
                     if Some(last) != *latest.borrow(){
                         return Err(VerifierError::NotLatest);
                     }
-                    latest.replace(Some(hash.clone()));
+                    commit_latest(&latest, &journal, hash.clone())
+                        .map_err(|e|{
+                            error!("Failed to journal new latest {:?}: {:?}", hash, e);
+                            VerifierError::StoreErr
+                        })?;
                 
                     Ok(hash)
                 })
                .map_err(|_| VerifierError::StoreErr)
             ).wait()
+            .map(|hash|{
+                self.since_checkpoint.set(self.since_checkpoint.get() + 1);
+                hash
+            })
     }
 }
 
@@ -208,10 +774,23 @@ impl Default for Verifier{
             keypair: KeyPair::generate(),
             allowed: HashTrieSet::new(),
             latest: Rc::new(RefCell::new(None)),
+            sync: SyncMode::default(),
+            threshold: None,
+            rebase: false,
+            freshness: Freshness::default(),
+            checkpoint_every: None,
+            journal: None,
+            pending: Rc::default(),
+            seen: Rc::default(),
+            since_checkpoint: Rc::default(),
+            new_checkpoint: Rc::default(),
         }
     }
 }
 
+// journals live in a subdirectory so from_dir doesn't mistake them for Verifiers
+const JOURNAL_DIR: &'static str = "journal";
+
 // not to be confused with a Map Verifier, this maps string keys to verifiers of a certain type
 pub struct VerifierMap{
     dir:       PathBuf,
@@ -219,7 +798,17 @@ pub struct VerifierMap{
 }
 
 impl VerifierMap{
-    pub fn from_dir<P: AsRef<Path>>(dir: P) -> io::Result<VerifierMap>{
+    pub fn from_dir<P: AsRef<Path>>(dir: P, passphrase: Passphrase) -> io::Result<VerifierMap>{
+        Self::load_dir(dir, passphrase, true)
+    }
+
+    // loads a VerifierMap owned by someone else (i.e. a running server), replaying the
+    // journals without taking them over. Only good for reading latest.
+    pub fn peek_dir<P: AsRef<Path>>(dir: P, passphrase: Passphrase) -> io::Result<VerifierMap>{
+        Self::load_dir(dir, passphrase, false)
+    }
+
+    fn load_dir<P: AsRef<Path>>(dir: P, passphrase: Passphrase, attach_journals: bool) -> io::Result<VerifierMap>{
         use std::ffi::OsStr;
 
         let mut verifiers = HashTrieMap::new();
@@ -227,7 +816,7 @@ impl VerifierMap{
             let entry = rentry?;
             let path  = entry.path();
             if entry.file_type()?.is_file(){
-                let v = Verifier::from_file(&path)?;
+                let mut v = Verifier::from_file(&path, passphrase)?;
                 let name = path
                     .file_name()
                     .and_then(|o: &OsStr| o.to_str())
@@ -236,6 +825,13 @@ impl VerifierMap{
                         || io::Error::new(io::ErrorKind::InvalidInput, 
                                           format!("Error converting path {:?} to String while loading Verifiers from {:?}",
                                                   path, dir.as_ref())))?;
+                let journal_path = dir.as_ref().join(JOURNAL_DIR).join(&name);
+                if attach_journals{
+                    v.attach_journal(journal_path)?;
+                }
+                else if let Some(hash) = Journal::replay(journal_path)?{
+                    v.latest.replace(Some(hash));
+                }
                 trace!("Loaded verifier {}/{}", dir.as_ref().display(), name);
                 verifiers.insert_mut(name, v);
             }
@@ -264,7 +860,17 @@ impl VerifierMap{
         Ok(())
     }
     pub fn to_dir(&self) -> io::Result<()>{
-        self.to_new_dir(&self.dir)
+        self.to_new_dir(&self.dir)?;
+
+        // everything journaled is now in the Verifier files themselves
+        for (name, verifier) in self.verifiers.iter(){
+            if let Some(ref journal) = verifier.journal{
+                journal.borrow_mut().reset()?;
+                trace!("Reset journal for verifier {}", name);
+            }
+        }
+
+        Ok(())
     }
 
 
@@ -279,7 +885,8 @@ impl VerifierMap{
             return Err(io::Error::new(io::ErrorKind::AlreadyExists,
                                       format!("Verifier {} already exists", key)));
         }
-        let v = Verifier::new(with_keypair, with_allowed, with_latest);
+        let mut v = Verifier::new(with_keypair, with_allowed, with_latest);
+        v.attach_journal(self.dir.join(JOURNAL_DIR).join(&key))?;
         self.verifiers.insert_mut(key, v);
 
         Ok(())
@@ -305,6 +912,61 @@ impl VerifierMap{
             Either::B(future::err(VerifierError::NoVerifier))
         }
     }
+    pub fn change_acl<T: Serialize + Debug>(&self, store: &BlockStore, input: Signed, key: &String)
+        -> Result<BlockHash, VerifierError>
+        where for <'de> T: Deserialize<'de>
+    {
+        match self.verifiers.get(key){
+            Some(value) => value.change_acl::<T>(store, input),
+            None => Err(VerifierError::NoVerifier)
+        }
+    }
+    pub fn acl<T: Serialize + Debug>(&self, store: &BlockStore, key: &String) -> Result<Acl, VerifierError>
+        where for <'de> T: Deserialize<'de>
+    {
+        match self.verifiers.get(key){
+            Some(value) => value.acl::<T>(store),
+            None => Err(VerifierError::NoVerifier)
+        }
+    }
+    // blocking. Rotates the named Verifier to new_keypair, returning its new latest.
+    // Everything journaled is written out first, so the rotation (key and latest together)
+    // becomes real in the single rename of the Verifier's file.
+    pub fn rotate_key<T: Serialize + Debug, C: Command<T>>(&mut self, store: &BlockStore,
+                                                           name: &String, new_keypair: KeyPair)
+        -> io::Result<BlockHash>
+        where for <'de> T: Deserialize<'de>,
+              for <'de> C: Deserialize<'de>
+    {
+        self.to_dir()?;
+        let rotated = match self.verifiers.get(name){
+            Some(v) => v.rotate::<T>(store, new_keypair)?,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("no Verifier named {}", name)))
+        };
+        let latest = rotated.latest.borrow().clone().unwrap(); // rotate always sets it
+
+        // never hand over a chain that can't be followed back
+        verify_history::<T, C>(store, latest.clone(), &rotated.keypair.public, None)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                                        format!("history of {} doesn't verify after rotating: {:?}",
+                                                name, e)))?;
+
+        rotated.to_file(self.dir.join(name))?;
+        self.verifiers.insert_mut(name.clone(), rotated);
+        Ok(latest)
+    }
+    pub fn keys(&self) -> Vec<(String, PublicKey)>{
+        self.verifiers.iter()
+            .map(|(name, v)| (name.clone(), v.keypair.public.clone()))
+            .collect()
+    }
+    // the latest of every Verifier that has one
+    pub fn roots(&self) -> Vec<(String, BlockHash)>{
+        self.verifiers.iter()
+            .filter_map(|(name, v)| v.latest.borrow().clone().map(|l| (name.clone(), l)))
+            .collect()
+    }
     pub fn latest(&self, key: &String) -> Option<BlockHash>{
         if let Some(value) = self.verifiers.get(key){
             value.latest.borrow().clone()
@@ -315,22 +977,607 @@ impl VerifierMap{
     }
 }
 
-// blocking, panicing
-pub fn store_verified<T: Serialize + Debug>(store: &BlockStore, input: T, keypair: &KeyPair)
+// blocking. The root of a new chain, which records the Acl allowed makes so that the
+// chain's first updates are checked against the chain like any others
+pub fn store_verified<T: Serialize + Debug>(store: &BlockStore, input: T, keypair: &KeyPair,
+                                            allowed: &AllowedKeys)
     -> io::Result<BlockHash>
 {
+    let acl = Acl::from_allowed(allowed).store(store)?;
     let data = VerifiedData{
         value: input,
         update: None,
+        rotation: None,
+        acl: Some(acl),
+        acl_update: None,
+        cosigners: Vec::new(),
+        rebase: None,
+        checkpoint: None
     };
     Signed::sign(data, keypair)
         .map_err(|_| io::Error::new(io::ErrorKind::Other,
                                     "Failed to sign data for storage"))
         .and_then(
             |signed_data|
+            serialize(&signed_data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)))
+        .and_then(
+            |data|
             store
-                .set(Arc::new(serialize(&signed_data).unwrap()))
+                .set(Arc::new(data))
                 .wait()
-                .unwrap())
+                .map_err(hung_up)?)
+}
+
+// blocking. Walks the chain back from latest, checking that every state was signed by key
+// or, past a KeyRotation, by the key it was rotated from, and that every update was made by
+// a key the Acl allowed at the time (or one it delegated to, within scope). Returns how many
+// states were walked.
+// With from_height the walk stops at the state of the checkpoint trusted_from finds, which
+// must have been signed by the key that signed that state, and the chain below it is taken
+// on that key's word.
+// The states are loaded first so that their signatures can be checked in one batch.
+pub fn verify_history<T: Serialize + Debug, C: Command<T>>(store: &BlockStore, latest: BlockHash,
+                                                           key: &PublicKey, from_height: Option<u64>)
+    -> Result<usize, VerifierError>
+    where for <'de> T: Deserialize<'de>,
+          for <'de> C: Deserialize<'de>
+{
+    let (history, _) = load_checked_history::<T, C>(store, latest, key, from_height)?;
+    Ok(history.len())
+}
+
+// blocking. The chain behind latest as verify_history checks it, down to the root or with
+// from_height to a trusted checkpoint's state, with that checkpoint
+pub fn load_checked_history<T: Serialize + Debug, C: Command<T>>(store: &BlockStore, latest: BlockHash,
+                                                                 key: &PublicKey, from_height: Option<u64>)
+    -> Result<(History<T>, Option<Checkpoint>), VerifierError>
+    where for <'de> T: Deserialize<'de>,
+          for <'de> C: Deserialize<'de>
+{
+    let trusted = match from_height{
+        Some(height) => checkpoint::trusted_from::<T>(store, &latest, height)?,
+        None => None
+    };
+    let mut history = load_history::<T, C>(store, latest,
+                                           trusted.as_ref().map(|&(_, ref checkpoint)| &checkpoint.state))?;
+    check_history::<T, C>(store, &mut history, key)?;
+    if let Some((ref signer, ref checkpoint)) = trusted{
+        // only the key that signed the checkpoint's state can vouch for what's below it
+        let vouched = match history.last(){
+            Some(&(ref hash, ref signed, _)) => *hash == checkpoint.state && signed.user() == signer,
+            None => false
+        };
+        if !vouched{
+            return Err(VerifierError::BadSignature);
+        }
+    }
+    Ok((history, trusted.map(|(_, checkpoint)| checkpoint)))
+}
+
+// blocking. What verify_history checks, of a chain already loaded by load_history
+pub fn check_history<T: Serialize + Debug, C: Command<T>>(store: &BlockStore, history: &mut History<T>,
+                                                          key: &PublicKey)
+    -> Result<(), VerifierError>
+    where for <'de> C: Deserialize<'de>
+{
+    {
+        let mut batch: Vec<&mut Signed> = Vec::new();
+        for &mut (_, ref mut signed, ref mut verified) in history.iter_mut(){
+            batch.push(signed);
+            batch.extend(verified.signed_mut());
+        }
+        if !check_batch(batch, BATCH_THREADS).into_iter().all(|good| good){
+            return Err(VerifierError::BadSignature);
+        }
+    }
+
+    // the signer of the state, and whoever updated it with the role they needed
+    let mut key = key.clone();
+    let mut updated_by: Vec<(PublicKey, Role)> = Vec::new();
+    for &(_, ref signed, ref verified) in history.iter(){
+        signed.check(&HashTrieSet::new().insert(key.clone()))?;
+
+        // the update on top of this state must be allowed by its Acl, which even the root
+        // records, so a state without one allows nothing
+        if !updated_by.is_empty(){
+            let acl = match verified.acl{
+                Some(ref acl) => Acl::load(store, acl).map_err(|_| VerifierError::LastErr)?,
+                None => return Err(VerifierError::DisallowedKey)
+            };
+            if !updated_by.iter().all(|&(ref user, role)| acl.allows(user, role)){
+                return Err(VerifierError::DisallowedKey);
+            }
+        }
+
+        // load_history already followed the same links to get here
+        updated_by = if let Some((_, old_key)) = verified.rotated_from(&key)?{
+            key = old_key;
+            Vec::new()
+        }
+        else if let Some((user, _)) = verified.acl_changed()?{
+            vec![(user, Role::Admin)]
+        }
+        else if let Some(ref update) = verified.update{
+            let (decoded, root) = verify_past_update::<T, C>(update)?;
+            if let Some(ref rebase) = verified.rebase{
+                if rebase.original != decoded.last{
+                    return Err(VerifierError::UpdateErr);
+                }
+            }
+            let mut updated_by = vec![(root, Role::Writer)];
+            updated_by.extend(update.signers().skip(1).map(|signer| (signer.clone(), Role::Writer)));
+            let payload = update.open()?;
+            for cosigner in verified.cosigners.iter(){
+                if cosigner.open()? != payload{
+                    return Err(VerifierError::BadSignature);
+                }
+                let (_, root) = verify_past_update::<T, C>(cosigner)?;
+                updated_by.push((root, Role::Writer));
+            }
+            updated_by
+        }
+        else{
+            Vec::new()
+        };
+    }
+    Ok(())
+}
+
+// each state of a chain (newest first) with its hash, as Signed and as what it was signed as
+pub type History<T> = Vec<(BlockHash, Signed, VerifiedData<T>)>;
+
+// blocking. The chain behind latest, found by peeking at each state for the next without
+// verifying anything, down to the root or to until if it comes across it
+pub fn load_history<T: Serialize + Debug, C: Command<T>>(store: &BlockStore, latest: BlockHash,
+                                                         until: Option<&BlockHash>)
+    -> Result<History<T>, VerifierError>
+    where for <'de> T: Deserialize<'de>,
+          for <'de> C: Deserialize<'de>
+{
+    let mut history = Vec::new();
+    let mut next = Some(latest);
+    while let Some(hash) = next.take(){
+        let (signed, verified) = peek_state::<T>(store, &hash)?;
+        if Some(&hash) != until{
+            next = verified.previous_state::<C>()?;
+        }
+        history.push((hash, signed, verified));
+    }
+    Ok(history)
+}
+
+// blocking. A state as Signed and as what it was signed as, without verifying anything
+pub fn peek_state<T: Serialize + Debug>(store: &BlockStore, hash: &BlockHash)
+    -> Result<(Signed, VerifiedData<T>), VerifierError>
+    where for <'de> T: Deserialize<'de>
+{
+    let block = store.get(hash.clone()).wait()
+        .map_err(|_| VerifierError::LastErr)? // Oneshot::Cancelled
+        .map_err(|_| VerifierError::LastErr)?;
+    let signed: Signed = deserialize(&block[..])
+        .map_err(|_| VerifierError::DecodeFailed)?;
+    let verified = signed.peek()?;
+    Ok((signed, verified))
+}
+
+// an update as it was checked when made, returning it and the key answerable for it
+fn verify_past_update<T: Serialize + Debug, C: Command<T>>(update: &Signed)
+    -> Result<(Update<C>, PublicKey), VerifierError>
+    where for <'de> C: Deserialize<'de>
+{
+    // delegations are checked as of when the update was made
+    let at = update.verify::<Update<C>>(&HashTrieSet::new().insert(update.user().clone()))?
+        .timestamp
+        .to_system();
+    let root = update.root().clone();
+    let (update, scope): (Update<C>, _) =
+        update.verify_scoped(&HashTrieSet::new().insert(root.clone()), at)?;
+    if let Some(ref scope) = scope{
+        if !update.command.within(scope){
+            return Err(VerifierError::OutOfScope);
+        }
+    }
+    Ok((update, root))
 }
 
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::ops::{Add, Sub};
+
+    use block::spawn_memory_thread;
+    use update::{TestObject, TestCommand, NamedHash, NamedHashCommand};
+    use signed::{Delegation, Scope};
+    use replay::new_nonce;
+
+    fn timestamp() -> SerializableTime{
+        SerializableTime::from_system_now().unwrap()
+    }
+
+    fn sign<C: Serialize + Context>(command: C, last: &BlockHash, keypair: &KeyPair) -> Signed{
+        let update = Update{ timestamp: timestamp(), command, last: last.clone(), nonce: Some(new_nonce()) };
+        Signed::sign(update, keypair).unwrap()
+    }
+
+    // a Verifier of a chain with just its root, which admins may change
+    fn verifier<T: Serialize + Debug>(store: &BlockStore, admins: &[&KeyPair], root: T) -> Verifier{
+        let allowed = admins.iter()
+            .fold(HashTrieSet::new(), |allowed, admin| allowed.insert(admin.public.clone()));
+        let verifier = Verifier::new(None, Some(allowed), None);
+        verifier.force(store, root).unwrap();
+        verifier
+    }
+
+    fn latest(verifier: &Verifier) -> BlockHash{
+        verifier.latest.borrow().clone().unwrap()
+    }
+
+    fn add(store: &BlockStore, verifier: &Verifier, n: u64, keypair: &KeyPair)
+        -> Result<BlockHash, VerifierError>
+    {
+        let signed = sign(TestCommand::Add(n), &latest(verifier), keypair);
+        verifier.verify::<TestObject, TestCommand>(store, signed).into_future().wait()
+    }
+
+    fn change_acl(store: &BlockStore, verifier: &Verifier, command: AclCommand, keypair: &KeyPair)
+        -> Result<BlockHash, VerifierError>
+    {
+        verifier.change_acl::<TestObject>(store, sign(command, &latest(verifier), keypair))
+    }
+
+    // a state on top of last that the Verifier signed, but with an update by user that it
+    // was never asked to check
+    fn forge(store: &BlockStore, verifier: &Verifier, last: &BlockHash, user: &KeyPair) -> BlockHash{
+        let (_, parent) = peek_state::<TestObject>(store, last).unwrap();
+        let verified = VerifiedData{
+            value: TestCommand::Add(1).process(parent.value).unwrap(),
+            update: Some(sign(TestCommand::Add(1), last, user)),
+            rotation: None,
+            acl: parent.acl,
+            acl_update: None,
+            cosigners: Vec::new(),
+            rebase: None,
+            checkpoint: parent.checkpoint
+        };
+        let data = serialize(&Signed::sign(verified, &verifier.keypair).unwrap()).unwrap();
+        store.set(Arc::new(data)).wait().unwrap().unwrap()
+    }
+
+    #[test]
+    fn writer_is_refused_change_acl(){
+        let store = spawn_memory_thread();
+        let (admin, writer) = (KeyPair::generate(), KeyPair::generate());
+        let verifier = verifier(&store, &[&admin], TestObject::default());
+        change_acl(&store, &verifier, AclCommand::Grant(writer.public.clone()), &admin).unwrap();
+        add(&store, &verifier, 1, &writer).unwrap();
+
+        let before = latest(&verifier);
+        for command in vec![AclCommand::Promote(writer.public.clone()),
+                            AclCommand::Revoke(admin.public.clone())]{
+            assert_eq!(change_acl(&store, &verifier, command, &writer), Err(VerifierError::DisallowedKey));
+        }
+        assert_eq!(latest(&verifier), before);
+        let acl = verifier.acl::<TestObject>(&store).unwrap();
+        assert!(acl.allows(&admin.public, Role::Admin));
+        assert!(!acl.allows(&writer.public, Role::Admin));
+    }
+
+    #[test]
+    fn revoking_the_last_admin_is_refused(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let verifier = verifier(&store, &[&admin], TestObject::default());
+        assert_eq!(change_acl(&store, &verifier, AclCommand::Revoke(admin.public.clone()), &admin),
+                   Err(VerifierError::UpdateErr));
+        assert!(verifier.acl::<TestObject>(&store).unwrap().allows(&admin.public, Role::Admin));
+    }
+
+    #[test]
+    fn check_history_rejects_update_by_revoked_key(){
+        let store = spawn_memory_thread();
+        let (admin, writer) = (KeyPair::generate(), KeyPair::generate());
+        let verifier = verifier(&store, &[&admin], TestObject::default());
+        let granted = change_acl(&store, &verifier, AclCommand::Grant(writer.public.clone()), &admin).unwrap();
+        let revoked = change_acl(&store, &verifier, AclCommand::Revoke(writer.public.clone()), &admin).unwrap();
+        assert_eq!(add(&store, &verifier, 1, &writer), Err(VerifierError::DisallowedKey));
+
+        // the same update is fine where the writer was allowed
+        let allowed = forge(&store, &verifier, &granted, &writer);
+        assert!(verify_history::<TestObject, TestCommand>(&store, allowed, &verifier.keypair.public, None).is_ok());
+
+        let forged = forge(&store, &verifier, &revoked, &writer);
+        assert_eq!(verify_history::<TestObject, TestCommand>(&store, forged, &verifier.keypair.public, None),
+                   Err(VerifierError::DisallowedKey));
+    }
+
+    #[test]
+    fn root_records_the_starting_acl(){
+        let store = spawn_memory_thread();
+        let (admin, stranger) = (KeyPair::generate(), KeyPair::generate());
+        let verifier = verifier(&store, &[&admin], TestObject::default());
+        let root = latest(&verifier);
+        let acl = peek_state::<TestObject>(&store, &root).unwrap().1.acl.unwrap();
+        assert_eq!(Acl::load(&store, &acl).unwrap(), Acl::from_allowed(&verifier.allowed));
+
+        // so an update the Verifier never allowed is caught even before any ACL change
+        let forged = forge(&store, &verifier, &root, &stranger);
+        assert_eq!(verify_history::<TestObject, TestCommand>(&store, forged, &verifier.keypair.public, None),
+                   Err(VerifierError::DisallowedKey));
+        let allowed = forge(&store, &verifier, &root, &admin);
+        assert!(verify_history::<TestObject, TestCommand>(&store, allowed, &verifier.keypair.public, None).is_ok());
+    }
+
+    #[test]
+    fn delegated_key_is_refused_outside_its_scope(){
+        let store = spawn_memory_thread();
+        let (admin, delegate) = (KeyPair::generate(), KeyPair::generate());
+        let verifier = verifier(&store, &[&admin], NamedHash::default());
+        let expires = SerializableTime::from_system(SystemTime::now().add(Duration::from_secs(60))).unwrap();
+        let delegation = Delegation{ key: delegate.public.clone(), scope: Scope(vec!["tiles/*".into()]), expires };
+        let set = |name: &str|{
+            let command = NamedHashCommand::Set(name.into(), BlockHash::of(name.as_bytes()));
+            let update = Update{ timestamp: timestamp(), command, last: latest(&verifier), nonce: Some(new_nonce()) };
+            let signed = Signed::sign_delegated(update, &delegate, vec![delegation.clone().issue(&admin).unwrap()]).unwrap();
+            verifier.verify::<NamedHash, NamedHashCommand>(&store, signed).into_future().wait()
+        };
+
+        set("tiles/a").unwrap();
+        let before = latest(&verifier);
+        assert_eq!(set("other"), Err(VerifierError::OutOfScope));
+        assert_eq!(set("tiles"), Err(VerifierError::OutOfScope));
+        assert_eq!(latest(&verifier), before);
+    }
+
+    // the same update however many times it's signed, as cosigners sign it
+    fn proposal<C: Clone>(command: C, last: BlockHash) -> impl Fn() -> Update<C>{
+        let (timestamp, nonce) = (timestamp(), new_nonce());
+        move || Update{ timestamp: timestamp.clone(), command: command.clone(), last: last.clone(), nonce: Some(nonce) }
+    }
+
+    fn threshold_verifier<T: Serialize + Debug>(store: &BlockStore, admins: &[&KeyPair], root: T, required: usize)
+        -> Verifier
+    {
+        let mut verifier = verifier(store, admins, root);
+        verifier.threshold = Some(Threshold{ required, window: 60 });
+        verifier
+    }
+
+    fn submit(store: &BlockStore, verifier: &Verifier, signed: Signed) -> Result<BlockHash, VerifierError>{
+        verifier.verify::<TestObject, TestCommand>(store, signed).into_future().wait()
+    }
+
+    #[test]
+    fn proposal_is_pending_until_threshold(){
+        let store = spawn_memory_thread();
+        let keys: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate()).collect();
+        let verifier = threshold_verifier(&store, &keys.iter().collect::<Vec<_>>(), TestObject::default(), 3);
+        let root = latest(&verifier);
+        let update = proposal(TestCommand::Add(1), root.clone());
+
+        for key in keys[..2].iter(){
+            assert_eq!(submit(&store, &verifier, Signed::sign(update(), key).unwrap()), Err(VerifierError::Pending));
+        }
+        assert_eq!(latest(&verifier), root);
+        let hash = submit(&store, &verifier, Signed::sign(update(), &keys[2]).unwrap()).unwrap();
+        assert_eq!(latest(&verifier), hash);
+    }
+
+    #[test]
+    fn duplicate_signer_counts_once(){
+        let store = spawn_memory_thread();
+        let (first, second) = (KeyPair::generate(), KeyPair::generate());
+        let verifier = threshold_verifier(&store, &[&first, &second], TestObject::default(), 2);
+        let root = latest(&verifier);
+        let update = proposal(TestCommand::Add(1), root.clone());
+
+        for _ in 0..2{
+            assert_eq!(submit(&store, &verifier, Signed::sign(update(), &first).unwrap()), Err(VerifierError::Pending));
+        }
+        assert_eq!(latest(&verifier), root);
+
+        submit(&store, &verifier, Signed::sign(update(), &second).unwrap()).unwrap();
+        assert!(latest(&verifier) != root);
+    }
+
+    #[test]
+    fn proposal_expires_after_window(){
+        let store = spawn_memory_thread();
+        let keys: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate()).collect();
+        let verifier = threshold_verifier(&store, &keys.iter().collect::<Vec<_>>(), TestObject::default(), 2);
+        let root = latest(&verifier);
+        let update = proposal(TestCommand::Add(1), root.clone());
+
+        assert_eq!(submit(&store, &verifier, Signed::sign(update(), &keys[0]).unwrap()), Err(VerifierError::Pending));
+        // as if it had been waiting the whole window
+        for proposal in verifier.pending.borrow_mut().values_mut(){
+            proposal.first_seen = proposal.first_seen.sub(Duration::from_secs(60));
+        }
+        assert_eq!(submit(&store, &verifier, Signed::sign(update(), &keys[1]).unwrap()), Err(VerifierError::Pending));
+        assert_eq!(latest(&verifier), root);
+        submit(&store, &verifier, Signed::sign(update(), &keys[2]).unwrap()).unwrap();
+        assert!(latest(&verifier) != root);
+    }
+
+    #[test]
+    fn threshold_makes_one_state_that_history_accepts(){
+        let store = spawn_memory_thread();
+        let keys: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate()).collect();
+        let verifier = threshold_verifier(&store, &keys.iter().collect::<Vec<_>>(), NamedHash::default(), 3);
+        let root = latest(&verifier);
+        let command = NamedHashCommand::Set("tiles/a".into(), BlockHash::of(b"tiles/a"));
+        let update = proposal(command.clone(), root.clone());
+        let submit = |signed: Signed| verifier.verify::<NamedHash, NamedHashCommand>(&store, signed).into_future().wait();
+
+        // the last signs through a delegate, so can't be merged into the others' signatures
+        let delegate = KeyPair::generate();
+        let expires = SerializableTime::from_system(SystemTime::now().add(Duration::from_secs(60))).unwrap();
+        let cert = Delegation{ key: delegate.public.clone(), scope: Scope(vec!["tiles/*".into()]), expires }
+            .issue(&keys[2])
+            .unwrap();
+        assert_eq!(submit(Signed::sign(update(), &keys[0]).unwrap()), Err(VerifierError::Pending));
+        assert_eq!(submit(Signed::sign(update(), &keys[1]).unwrap()), Err(VerifierError::Pending));
+        let hash = submit(Signed::sign_delegated(update(), &delegate, vec![cert]).unwrap()).unwrap();
+
+        let (_, state) = peek_state::<NamedHash>(&store, &hash).unwrap();
+        let signed = state.update.as_ref().unwrap();
+        assert_eq!(signed.signers().cloned().collect::<Vec<_>>(), vec![keys[0].public.clone(), keys[1].public.clone()]);
+        assert_eq!(state.cosigners.len(), 1);
+        assert_eq!(state.cosigners[0].root(), &keys[2].public);
+        assert_eq!(state.value, command.process(NamedHash::default()).unwrap());
+        assert_eq!(state.previous_state::<NamedHashCommand>().unwrap(), Some(root));
+        assert_eq!(verify_history::<NamedHash, NamedHashCommand>(&store, hash, &verifier.keypair.public, None), Ok(2));
+    }
+
+    fn set(store: &BlockStore, verifier: &Verifier, name: &str, last: &BlockHash, keypair: &KeyPair)
+        -> Result<BlockHash, VerifierError>
+    {
+        let signed = sign(NamedHashCommand::Set(name.into(), BlockHash::of(name.as_bytes())), last, keypair);
+        verifier.verify::<NamedHash, NamedHashCommand>(store, signed).into_future().wait()
+    }
+
+    fn rebasing_verifier(store: &BlockStore, admin: &KeyPair) -> Verifier{
+        let mut verifier = verifier(store, &[admin], NamedHash::default());
+        verifier.rebase = true;
+        verifier
+    }
+
+    #[test]
+    fn commuting_update_is_rebased(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let verifier = rebasing_verifier(&store, &admin);
+        let root = latest(&verifier);
+        let first = set(&store, &verifier, "a", &root, &admin).unwrap();
+        let second = set(&store, &verifier, "b", &root, &admin).unwrap();
+
+        let (_, state) = peek_state::<NamedHash>(&store, &second).unwrap();
+        let rebase = state.rebase.as_ref().unwrap();
+        assert_eq!(rebase.original, root);
+        assert_eq!(rebase.parent, first);
+        assert_eq!(state.previous_state::<NamedHashCommand>().unwrap(), Some(first));
+        assert!(state.value.0.contains_key("a") && state.value.0.contains_key("b"));
+        assert_eq!(verify_history::<NamedHash, NamedHashCommand>(&store, second, &verifier.keypair.public, None), Ok(3));
+    }
+
+    #[test]
+    fn update_to_the_same_name_is_not_rebased(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let verifier = rebasing_verifier(&store, &admin);
+        let root = latest(&verifier);
+        let first = set(&store, &verifier, "a", &root, &admin).unwrap();
+        assert_eq!(set(&store, &verifier, "a", &root, &admin), Err(VerifierError::NotLatest));
+        assert_eq!(latest(&verifier), first);
+    }
+
+    #[test]
+    fn update_is_not_rebased_over_acl_change_or_rotation(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let verifier = rebasing_verifier(&store, &admin);
+        let root = latest(&verifier);
+        let grant = sign(AclCommand::Grant(KeyPair::generate().public), &root, &admin);
+        let changed = verifier.change_acl::<NamedHash>(&store, grant).unwrap();
+        assert_eq!(set(&store, &verifier, "a", &root, &admin), Err(VerifierError::NotLatest));
+        assert_eq!(latest(&verifier), changed);
+
+        let verifier = rebasing_verifier(&store, &admin);
+        let root = latest(&verifier);
+        set(&store, &verifier, "a", &root, &admin).unwrap();
+        let rotated = verifier.rotate::<NamedHash>(&store, KeyPair::generate()).unwrap();
+        let rotation = latest(&rotated);
+        assert_eq!(set(&store, &rotated, "b", &root, &admin), Err(VerifierError::NotLatest));
+        assert_eq!(latest(&rotated), rotation);
+    }
+
+    #[test]
+    fn identical_update_is_replayed(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let mut verifier = verifier(&store, &[&admin], TestObject::default());
+        verifier.rebase = true; // it would otherwise be turned away for not being made against latest
+        let update = proposal(TestCommand::Add(1), latest(&verifier));
+        let applied = submit(&store, &verifier, Signed::sign(update(), &admin).unwrap()).unwrap();
+        assert_eq!(submit(&store, &verifier, Signed::sign(update(), &admin).unwrap()), Err(VerifierError::Replayed));
+        assert_eq!(latest(&verifier), applied);
+
+        // nor may an update go without a nonce
+        let unnonced = Update{ nonce: None, ..TestCommand::Add(1).into_update(applied.clone()) };
+        assert_eq!(submit(&store, &verifier, Signed::sign(unnonced, &admin).unwrap()), Err(VerifierError::Replayed));
+    }
+
+    #[test]
+    fn update_from_too_far_ahead_is_stale(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let verifier = verifier(&store, &[&admin], TestObject::default());
+        let root = latest(&verifier);
+        let skew = Duration::from_secs(verifier.freshness.future + 2);
+        let update = Update{
+            timestamp: SerializableTime::from_system(SystemTime::now().add(skew)).unwrap(),
+            ..TestCommand::Add(1).into_update(root.clone())
+        };
+        assert_eq!(submit(&store, &verifier, Signed::sign(update, &admin).unwrap()), Err(VerifierError::Stale));
+        assert_eq!(latest(&verifier), root);
+        add(&store, &verifier, 1, &admin).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_are_only_made_of_accepted_updates(){
+        let store = spawn_memory_thread();
+        let (admin, stranger) = (KeyPair::generate(), KeyPair::generate());
+        let mut verifier = verifier(&store, &[&admin], TestObject::default());
+        verifier.checkpoint_every = Some(2);
+        add(&store, &verifier, 1, &admin).unwrap();
+        verifier.since_checkpoint.set(2);
+
+        let blocks = store.hashes().wait().unwrap().unwrap().len();
+        for n in 0..4{
+            assert_eq!(add(&store, &verifier, n, &stranger), Err(VerifierError::DisallowedKey));
+        }
+        assert_eq!(store.hashes().wait().unwrap().unwrap().len(), blocks);
+        assert!(verifier.new_checkpoint.borrow().is_none());
+
+        // the checkpoint is of the state just made, and the next state refers to it
+        let checkpointed = add(&store, &verifier, 1, &admin).unwrap();
+        assert!(peek_state::<TestObject>(&store, &checkpointed).unwrap().1.checkpoint.is_none());
+        let next = add(&store, &verifier, 1, &admin).unwrap();
+        let hash = peek_state::<TestObject>(&store, &next).unwrap().1.checkpoint.unwrap();
+        let (signer, checkpoint) = checkpoint::load(&store, &hash).unwrap();
+        assert_eq!(signer, verifier.keypair.public);
+        assert_eq!(checkpoint.state, checkpointed);
+        assert_eq!(checkpoint.height, 2);
+        assert_eq!(checkpoint.stats.updates, 2);
+        assert_eq!(verify_history::<TestObject, TestCommand>(&store, next, &verifier.keypair.public, None), Ok(4));
+    }
+
+    #[test]
+    fn history_from_a_height_stops_at_a_trusted_checkpoint(){
+        let store = spawn_memory_thread();
+        let admin = KeyPair::generate();
+        let mut verifier = verifier(&store, &[&admin], TestObject::default());
+        verifier.checkpoint_every = Some(2);
+        for n in 0..8{
+            add(&store, &verifier, n, &admin).unwrap();
+        }
+        let latest = latest(&verifier);
+        let key = verifier.keypair.public.clone();
+        assert_eq!(verify_history::<TestObject, TestCommand>(&store, latest.clone(), &key, None), Ok(9));
+
+        // the oldest checkpoint at least that high, and the states from it up to latest (at 8)
+        let (signer, checkpoint) = checkpoint::trusted_from::<TestObject>(&store, &latest, 3).unwrap().unwrap();
+        assert_eq!(signer, key);
+        assert_eq!(checkpoint.height, 4);
+        assert_eq!(verify_history::<TestObject, TestCommand>(&store, latest.clone(), &key, Some(3)), Ok(5));
+        // nothing is that high, so the newest it refers to
+        assert_eq!(verify_history::<TestObject, TestCommand>(&store, latest.clone(), &key, Some(100)), Ok(3));
+
+        // the chain below is trusted, so it needn't even be there
+        let (_, below) = peek_state::<TestObject>(&store, &checkpoint.state).unwrap();
+        let below = below.previous_state::<TestCommand>().unwrap().unwrap();
+        assert!(store.remove(below).wait().unwrap().unwrap());
+        assert_eq!(verify_history::<TestObject, TestCommand>(&store, latest.clone(), &key, Some(3)), Ok(5));
+        assert_eq!(verify_history::<TestObject, TestCommand>(&store, latest, &key, None), Err(VerifierError::LastErr));
+    }
+}
@@ -5,14 +5,14 @@ use chrono::{self, TimeZone};
 use futures::Future;
 use base64;
 
-use std::path::PathBuf;
 use std::sync::Arc;
 use std::fmt::Debug;
 
 use update::{Update, Command, TestCommand, TestObject, NamedHash, NamedHashCommand};
 use signed::{Signed};
-use block::{BlockHash, BlockStore, spawn_thread as spawn_block_thread};
+use block::{BlockHash, BlockStore, BlockStoreConfig, spawn_thread as spawn_block_thread};
 use verify::*;
+use checkpoint;
 
 
 type NavigationString = String;
@@ -42,9 +42,9 @@ fn decode_vd<T, C>(block_store: BlockStore, block_hash: BlockHash) -> Navigation
                     return NErr(format!("{:?} failed to decode to Signed: {:?}", block_hash, e));
                 }
             };
-            let signed_user_b64 = base64::encode_config(&signed.user, base64::URL_SAFE_NO_PAD);
+            let signed_user_b64 = base64::encode_config(signed.user(), base64::URL_SAFE_NO_PAD);
 
-            let allow_any = HashTrieSet::new().insert(signed.user.clone());
+            let allow_any = HashTrieSet::new().insert(signed.user().clone());
             let verified = match signed.verify::<VerifiedData<T>>(&allow_any){
                 Ok(u) => u,
                 Err(e) => {
@@ -53,16 +53,86 @@ fn decode_vd<T, C>(block_store: BlockStore, block_hash: BlockHash) -> Navigation
             };
             
             println!("{:?} verified by {}:\n\tvalue: {:?}", block_hash, signed_user_b64, verified.value);
-            if let Some(update) = verified.update{
-                let update_user = update.user.clone();
+            match verified.rotated_from(signed.user()){
+                Ok(Some((last, old_key))) => {
+                    let old_key_b64 = base64::encode_config(&old_key, base64::URL_SAFE_NO_PAD);
+                    println!("\tkey rotation:\n\t\tfrom key {}\n\t\tto last {:?}", old_key_b64, last);
+
+                    let next_fn = Box::new(move |bs: BlockStore| -> NavigationResult {decode_vd::<T, C>(bs, last.clone())});
+                    next.push(("last".into(), next_fn));
+                },
+                Ok(None) => (),
+                Err(err) => {
+                    return NErr(format!("\tinvalid key rotation {:?}", err));
+                }
+            }
+            if let Some(ref acl) = verified.acl{
+                println!("\tacl: {:?}", acl);
+            }
+            if let Some(ref hash) = verified.checkpoint{
+                let (_, checkpoint) = match checkpoint::load(&block_store, hash){
+                    Ok(c) => c,
+                    Err(err) => {
+                        return NErr(format!("\tinvalid checkpoint {:?}: {:?}", hash, err));
+                    }
+                };
+                println!("\tcheckpoint {:?}:\n\t\tnumber {}\n\t\tof {:?}\n\t\tat height {}\n\t\t{:?}",
+                         hash, checkpoint.number, checkpoint.state, checkpoint.height, checkpoint.stats);
+
+                // jump straight to the states of earlier checkpoints
+                let mut jumps = vec![("checkpoint".to_string(), checkpoint.state, checkpoint.height)];
+                for (k, further) in checkpoint.skip.iter().enumerate(){
+                    match checkpoint::load(&block_store, further){
+                        Ok((_, further)) =>
+                            jumps.push((format!("checkpoint {} back", 1u64 << k), further.state, further.height)),
+                        Err(err) => {
+                            return NErr(format!("\tinvalid checkpoint {:?}: {:?}", further, err));
+                        }
+                    }
+                }
+                for (name, state, height) in jumps{
+                    let next_fn = Box::new(move |bs: BlockStore| -> NavigationResult {decode_vd::<T, C>(bs, state.clone())});
+                    next.push((format!("{} (height {})", name, height), next_fn));
+                }
+            }
+            match verified.acl_changed(){
+                Ok(Some((user, update))) => {
+                    let user_b64 = base64::encode_config(&user, base64::URL_SAFE_NO_PAD);
+                    let time = chrono::Local.timestamp(update.timestamp.to_u64() as i64, 0).to_rfc3339();
+                    let update_last = update.last.clone();
+
+                    println!("\tacl change {:?}:\n\t\tby key {}\n\t\tat {}\n\t\tto last {:?}", update.command, user_b64, time, update_last);
+
+                    let next_fn = Box::new(move |bs: BlockStore| -> NavigationResult {decode_vd::<T, C>(bs, update_last.clone())});
+                    next.push(("last".into(), next_fn));
+                },
+                Ok(None) => (),
+                Err(err) => {
+                    return NErr(format!("\tinvalid acl change {:?}", err));
+                }
+            }
+            if let Some(ref update) = verified.update{
+                let update_user = update.user().clone();
                 let update_user_b64 = base64::encode_config(&update_user, base64::URL_SAFE_NO_PAD);
+                let also_signed: Vec<String> = update.signers().skip(1)
+                    .map(|signer| base64::encode_config(signer, base64::URL_SAFE_NO_PAD))
+                    .collect();
 
                 match update.verify::<Update<C>>(&allow_any.insert(update_user)){
                     Ok(update) => {
                         let time = chrono::Local.timestamp(update.timestamp.to_u64() as i64, 0).to_rfc3339();
-                        let update_last = update.last.clone();
+                        let update_last = verified.update_parent(update.last.clone());
                         
-                        println!("\tupdate:\n\t\tby key {}\n\t\tat {}\n\t\tto last {:?}", update_user_b64, time, update_last);
+                        println!("\tupdate:\n\t\tby key {}\n\t\tat {}\n\t\tto last {:?}", update_user_b64, time, update.last);
+                        if let Some(ref rebase) = verified.rebase{
+                            println!("\t\trebased onto {:?}", rebase.parent);
+                        }
+                        for signer in also_signed.iter(){
+                            println!("\t\tcosigned by key {}", signer);
+                        }
+                        for cosigner in verified.cosigners.iter(){
+                            println!("\t\tcosigned by key {} (delegated)", base64::encode_config(cosigner.user(), base64::URL_SAFE_NO_PAD));
+                        }
                         
                         let next_fn = Box::new(move |bs: BlockStore| -> NavigationResult {decode_vd::<T, C>(bs, update_last.clone())});
                         next.push(("last".into(), next_fn));
@@ -72,7 +142,7 @@ fn decode_vd<T, C>(block_store: BlockStore, block_hash: BlockHash) -> Navigation
                     }
                 }
             }
-            else{
+            else if verified.rotation.is_none() && verified.acl_update.is_none(){
                 println!("\tthis is a root block");
             }
             NOk(next)
@@ -120,10 +190,51 @@ fn navigate<F>(block_store: &BlockStore, depth: usize, f: Box<F>)
     }
 }
 
-pub fn main(type_string: String, block_string: String)
+pub fn main(config: BlockStoreConfig, type_string: String, block_string: String,
+            height_string: Option<String>)
 {
-    let block_store = spawn_block_thread(PathBuf::from("public/blocks/"));
-    let block_hash = BlockHash::from(block_string.as_str());
+    let block_hash: BlockHash = match block_string.parse(){
+        Ok(hash) => hash,
+        Err(e) => {
+            println!("Can't view {:?}: {}", block_string, e);
+            return;
+        }
+    };
+    let height: Option<u64> = match height_string.map(|h| h.parse()){
+        Some(Ok(height)) => Some(height),
+        Some(Err(e)) => {
+            println!("Can't view that height: {}", e);
+            return;
+        }
+        None => None
+    };
+    let block_store = spawn_block_thread(config);
+    let block_hash = match height{
+        Some(height) => {
+            let found = match type_string.as_str(){
+                "test"  =>
+                    checkpoint::state_at::<TestObject, TestCommand>(&block_store, block_hash, height)
+                        .map(|state| state.map(|(hash, _)| hash)),
+                "named" =>
+                    checkpoint::state_at::<NamedHash, NamedHashCommand>(&block_store, block_hash, height)
+                        .map(|state| state.map(|(hash, _)| hash)),
+                _ =>
+                    panic!("Invalid block type {}", type_string)
+            };
+            match found{
+                Ok(Some(hash)) => hash,
+                Ok(None) => {
+                    println!("The chain has no state at height {}", height);
+                    return;
+                },
+                Err(e) => {
+                    println!("Failed to find the state at height {}: {:?}", height, e);
+                    return;
+                }
+            }
+        },
+        None => block_hash
+    };
     let next = Box::new(move |bs: BlockStore| -> NavigationResult {
         match type_string.as_str(){
             "test"  =>
@@ -136,4 +247,3 @@ pub fn main(type_string: String, block_string: String)
     });
     navigate(&block_store, 0, next);
 }
-
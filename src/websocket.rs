@@ -18,7 +18,7 @@ use std::path::{Path, PathBuf};
 use std::io;
 
 use block::{BlockStore, BlockHash};
-use signed::{Signed, KeyPair, PublicKey};
+use signed::{Signed, KeyPair, PublicKey, Context, Passphrase};
 use ltime::SerializableTime;
 use map::{self, MapThreadHandle};
 
@@ -58,6 +58,12 @@ struct ServerAuthChallenge{ // must be Signed to send to the client, still doesn
     timestamp: SerializableTime,
     challenge: [u8; CHALLENGE_BYTES]
 }
+impl Context for ServerAuthChallenge{
+    fn context() -> String{
+        "ServerAuthChallenge".into()
+    }
+}
+
 impl ServerAuthChallenge{
     fn new() -> ServerAuthChallenge{
         use rand::{thread_rng, Rng};
@@ -76,6 +82,12 @@ struct ClientAuthResponse{
     challenge: [u8; CHALLENGE_BYTES]
 }
 
+impl Context for ClientAuthResponse{
+    fn context() -> String{
+        "ClientAuthResponse".into()
+    }
+}
+
 impl ClientAuthResponse{
     fn check(&self, server: &ServerAuthChallenge) -> bool{
         use sodiumoxide::crypto::verify::verify_32;
@@ -145,9 +157,9 @@ impl Handler for ServerHandler{
         match self.state{
             AwaitingAuth(ref challenge) => {
                 let signed: Signed = decode(msg)?;
-                let user_key = signed.user.clone();
+                let user_key = signed.user().clone();
                 let allowed = HashTrieSet::new().insert(user_key.clone());
-                let response = signed.verify::<ClientAuthResponse>(&allowed)
+                let response = signed.verify_submitted::<ClientAuthResponse>(&allowed)
                     .map_err(|_e| WsError::new(WsErrorKind::Protocol, "Failed to decode Signed ClientAuthResponse"))?;
                 if response.check(&challenge){
                     trace!("{:?} authenticated!", user_key);
@@ -224,7 +236,7 @@ struct ServerShared(Rc<ServerSharedInternal>);
 
 impl ServerShared{
     fn new(store: BlockStore, map: MapThreadHandle, defer: DeferSender) -> ServerShared{
-        let auth = KeyPair::from_file_or_new(WEBSOCKET_KEYFILE);
+        let auth = KeyPair::from_file_or_new(WEBSOCKET_KEYFILE, Passphrase::Ask);
         ServerShared(Rc::new(ServerSharedInternal{
             store,
             auth,
@@ -324,6 +336,7 @@ fn write_example_messages(){
     use update::{Update, NamedHashCommand};
     use block::BlockHash;
     use ltime::SerializableTime;
+    use replay::new_nonce;
 
     fs::create_dir_all(EXAMPLE_MSG_DIR).expect("Failed to create example message dir");
     let dir = Path::new(EXAMPLE_MSG_DIR);
@@ -339,8 +352,9 @@ fn write_example_messages(){
                 timestamp: SerializableTime::from_system_now().unwrap(),
                 command:
             NamedHashCommand::Set("smile".into(),
-                                  BlockHash::from("l6RV2N6qQRjHCvKZ47adEXMf51YwEiIj2qiKcs-7L9Y")),
-                last: BlockHash::from("ABC123")
+                                  "l6RV2N6qQRjHCvKZ47adEXMf51YwEiIj2qiKcs-7L9Y".parse()?),
+                last: BlockHash::of(b"ABC123"),
+                nonce: Some(new_nonce())
             };
         example(dir, "UpdateSmilePresign", update)?;
 
@@ -349,8 +363,9 @@ fn write_example_messages(){
                 timestamp: SerializableTime::from_system_now().unwrap(),
                 command:
             NamedHashCommand::Set("smile".into(),
-                                  BlockHash::from("l6RV2N6qQRjHCvKZ47adEXMf51YwEiIj2qiKcs-7L9Y")),
-                last: BlockHash::from("ABC123")
+                                  "l6RV2N6qQRjHCvKZ47adEXMf51YwEiIj2qiKcs-7L9Y".parse()?),
+                last: BlockHash::of(b"ABC123"),
+                nonce: Some(new_nonce())
             };
 
         let kp = KeyPair::generate();